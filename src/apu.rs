@@ -1,918 +1,655 @@
-use rodio::{OutputStream, Sink, Source};
-use std::sync::{Arc, Condvar, Mutex};
-use std::thread;
-use std::time::Duration;
-
-const PERIOD_TABLE: [u16; 16] = [
-    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
-];
-
-const SAMPLE_RATE: u32 = 44100;
-// const SAMPLE_RATE: u32 = 36750;
-const FRAME_SEQUENCER_RATE: f32 = 240.0; // Hz - typical NES frame sequencer rate
-const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE as f32 / FRAME_SEQUENCER_RATE) as usize;
-
-struct SoundChannel {
-    frequency: Arc<Mutex<f32>>,
-    volume: Arc<Mutex<f32>>,
-    enabled: Arc<Mutex<bool>>,
-    length_counter: Arc<Mutex<u8>>,
-    length_counter_enabled: Arc<Mutex<bool>>,
-    length_counter_halt: Arc<Mutex<bool>>,
-    sweep_mute: Arc<Mutex<bool>>,
+//! # APU
+//! The 2A03's audio unit. [`Apu::clock`] runs once per CPU cycle and steps the same
+//! timers, sequencers and frame counter as the console, so the sound only depends on
+//! the CPU cycles emulated and not on when the audio thread happens to run. Every channel
+//! is plain state, which makes the APU deterministic and lets it be saved and restored.
+//!
+//! The channel outputs are mixed every cycle, resampled to the output rate with
+//! band-limited synthesis, filtered and pushed into a lock-free ring buffer that the
+//! audio thread drains. While a device is playing, dynamic rate control keeps that buffer
+//! near its target fill by nudging the output rate up or down by a fraction of a percent,
+//! which is inaudible but absorbs the drift between the emulation and the audio clock.
+
+pub mod backend;
+mod blip;
+mod dmc;
+mod envelope;
+mod filter;
+mod frame_counter;
+mod length_counter;
+mod mixer;
+mod noise;
+mod pulse;
+mod recorder;
+pub mod ring;
+mod triangle;
+mod vgm;
+mod wav;
+
+use std::collections::VecDeque;
+use std::io;
+use std::path::Path;
+
+use log::warn;
+
+use crate::states::{Savestate, StateReader, StateWriter};
+use backend::{AudioBackend, AudioConfig};
+use blip::BlipBuffer;
+use dmc::Dmc;
+use filter::FilterChain;
+pub use filter::FilterPreset;
+use frame_counter::{FrameCounter, FrameStep};
+pub use mixer::Channel;
+use mixer::{ChannelLevels, Mixer};
+use noise::Noise;
+use pulse::Pulse;
+use recorder::Recorder;
+use ring::{ring_buffer, SampleConsumer, SampleProducer};
+use triangle::Triangle;
+use vgm::VgmLogger;
+
+pub(crate) const CPU_CLOCK: u32 = 1_789_773;
+/// Largest change of the output rate the rate control makes, as a fraction.
+const MAX_RATE_ADJUST: f32 = 0.005;
+
+pub struct Apu {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    frame_counter: FrameCounter,
+    mixer: Mixer,
+    /// Pulse timers run at half the CPU clock, on every other cycle.
+    odd_cycle: bool,
+
+    /// Resamples the mixed level from the CPU clock to the output rate. In stereo this
+    /// and `filter` carry the left side.
+    blip: BlipBuffer,
+    /// Analog output path applied to each sample.
+    filter: FilterChain,
+    /// The right side, only clocked in stereo. Not part of save states.
+    right_blip: BlipBuffer,
+    right_filter: FilterChain,
+
+    mute: bool,
+    config: AudioConfig,
+    producer: SampleProducer,
+    /// The ring buffer's other end, until a backend takes it.
+    consumer: Option<SampleConsumer>,
+    /// Copy of the most recent samples for `take_samples`.
+    captured: VecDeque<f32>,
+    backend: Option<Box<dyn AudioBackend>>,
+    /// Whether a real-time backend is playing, which turns on rate control.
+    realtime: bool,
+    recorder: Option<Recorder>,
+    /// Last value written to each register $4000-$4017, replayed at the start of a VGM log.
+    registers: [u8; 0x18],
+    vgm: Option<VgmLogger>,
 }
 
-impl SoundChannel {
-    fn new() -> Self {
-        SoundChannel {
-            frequency: Arc::new(Mutex::new(0.0)),
-            volume: Arc::new(Mutex::new(0.0)),
-            enabled: Arc::new(Mutex::new(false)),
-            length_counter: Arc::new(Mutex::new(0)),
-            length_counter_enabled: Arc::new(Mutex::new(true)),
-            length_counter_halt: Arc::new(Mutex::new(false)),
-            sweep_mute: Arc::new(Mutex::new(false)),
+impl Apu {
+    /// Creates an APU generating samples in the given format. Nothing plays them until a
+    /// backend is started with [`Apu::start_backend`].
+    pub fn new(config: AudioConfig) -> Self {
+        // Room for the target latency and plenty of slack, at least a quarter second
+        let quarter_second = config.sample_rate as usize * config.channels() as usize / 4;
+        let capacity = (config.target_fill() * 4).max(quarter_second);
+        let (producer, consumer) = ring_buffer(capacity);
+        Apu {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            frame_counter: FrameCounter::new(),
+            mixer: Mixer::new(),
+            odd_cycle: false,
+
+            blip: BlipBuffer::new(CPU_CLOCK, config.sample_rate),
+            filter: FilterChain::new(FilterPreset::default(), config.sample_rate),
+            right_blip: BlipBuffer::new(CPU_CLOCK, config.sample_rate),
+            right_filter: FilterChain::new(FilterPreset::default(), config.sample_rate),
+
+            mute: false,
+            config,
+            producer,
+            consumer: Some(consumer),
+            captured: VecDeque::new(),
+            backend: None,
+            realtime: false,
+            recorder: None,
+            registers: [0; 0x18],
+            vgm: None,
         }
     }
 
-    fn set_frequency(&self, freq: f32) {
-        *self.frequency.lock().unwrap() = freq;
+    /// Creates an APU with the default format and no backend. Used for headless runs.
+    pub fn new_silent() -> Self {
+        Self::new(AudioConfig::default())
     }
 
-    fn set_volume(&self, vol: f32) {
-        *self.volume.lock().unwrap() = vol;
+    /// Hands the sample queue to `backend` and starts it. The queue can only be handed
+    /// out once; a second call is an error.
+    pub fn start_backend(&mut self, mut backend: Box<dyn AudioBackend>) -> io::Result<()> {
+        let Some(consumer) = self.consumer.take() else {
+            return Err(io::Error::other("an audio backend is already running"));
+        };
+        backend.start(consumer, self.config)?;
+        self.realtime = backend.is_realtime();
+        self.backend = Some(backend);
+        Ok(())
     }
 
-    fn set_enabled(&self, enabled: bool) {
-        *self.enabled.lock().unwrap() = enabled;
+    /// Selects the PAL noise periods. The rest of the console is emulated with NTSC
+    /// timing.
+    pub fn set_pal(&mut self, pal: bool) {
+        self.noise.set_pal(pal);
+    }
 
-        if !enabled {
-            *self.length_counter.lock().unwrap() = 0;
+    /// Selects the output filter. Changing it restarts the filters from silence.
+    pub fn set_filter(&mut self, preset: FilterPreset) {
+        if preset != self.filter.preset() {
+            self.filter = FilterChain::new(preset, self.config.sample_rate);
+            self.right_filter = FilterChain::new(preset, self.config.sample_rate);
         }
     }
 
-    fn set_length_counter(&self, value: u8) {
-        *self.length_counter.lock().unwrap() = value;
+    pub fn toggle_sound(&mut self) {
+        self.mute = !self.mute;
     }
 
-    fn set_length_counter_halt(&self, halt: bool) {
-        *self.length_counter_halt.lock().unwrap() = halt;
+    /// Sets a channel's volume, 1.0 being its level on the console, up to 2.0.
+    pub fn set_channel_volume(&mut self, channel: Channel, volume: f32) {
+        self.mixer.set_volume(channel, volume);
     }
 
-    fn decrement_length_counter(&self) -> bool {
-        let mut counter = self.length_counter.lock().unwrap();
-        let halt = *self.length_counter_halt.lock().unwrap();
-        let enabled = *self.length_counter_enabled.lock().unwrap();
-
-        if halt || !enabled {
-            return *counter > 0;
-        }
-
-        if *counter > 0 {
-            *counter -= 1;
-            return *counter > 0;
-        }
-
-        false
+    pub fn channel_volume(&self, channel: Channel) -> f32 {
+        self.mixer.volume(channel)
     }
-}
 
-struct SweepUnit {
-    divider_period: u8,
-    negate_flag: bool,
-    shift_amount: u8,
-    enabled: bool,
-    divider_counter: u8,
-    mute: Arc<Mutex<bool>>,
-}
-
-impl SweepUnit {
-    fn new(channel_sweep_mute: Arc<Mutex<bool>>) -> Self {
-        SweepUnit {
-            divider_period: 0,
-            negate_flag: false,
-            shift_amount: 0,
-            enabled: false,
-            divider_counter: 0,
-            mute: channel_sweep_mute,
-        }
-    }
-
-    fn write(&mut self, data: u8) {
-        self.enabled = (data & 0x80) != 0;
-        self.divider_period = ((data >> 4) & 0x07) + 1;
-        self.negate_flag = (data & 0x08) != 0;
-        self.shift_amount = data & 0x07;
-        self.reset_divider();
-    }
-
-    fn reset_divider(&mut self) {
-        self.divider_counter = self.divider_period;
-        *self.mute.lock().unwrap() = false;
-    }
-
-    fn clock(&mut self, current_timer: u16, channel_id: u8) -> Option<u16> {
-        let mut _sweep_mute_local = false;
-        let mut new_timer_value: Option<u16> = None;
-
-        if self.divider_counter == 0 {
-            self.divider_counter = self.divider_period;
-            if self.enabled && self.shift_amount > 0 {
-                let change = current_timer >> self.shift_amount;
-                let calculated_new_timer = if self.negate_flag {
-                    if channel_id == 1 {
-                        current_timer.wrapping_sub(change).wrapping_sub(1)
-                    } else {
-                        current_timer.wrapping_sub(change)
-                    }
-                } else {
-                    current_timer.wrapping_add(change)
-                };
-
-                if calculated_new_timer > 0x7FF || current_timer < 8 {
-                    _sweep_mute_local = true;
-                } else {
-                    new_timer_value = Some(calculated_new_timer);
-                    _sweep_mute_local = false;
-                }
-            } else {
-                _sweep_mute_local = false;
-            }
-        } else {
-            self.divider_counter -= 1;
-            _sweep_mute_local = *self.mute.lock().unwrap();
-        }
-        *self.mute.lock().unwrap() = _sweep_mute_local;
-        new_timer_value
+    pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+        self.mixer.set_muted(channel, muted);
     }
-}
 
-struct PulseWaveSource {
-    channel: Arc<SoundChannel>,
-    duty: Arc<Mutex<u8>>,
-    sample_rate: u32,
-    position: f32,
-    samples_since_last_frame: usize,
-    pulse1_sweep_unit: Option<Arc<Mutex<SweepUnit>>>,
-    pulse2_sweep_unit: Option<Arc<Mutex<SweepUnit>>>,
-    pulse1_timer: Option<Arc<Mutex<u16>>>,
-    pulse2_timer: Option<Arc<Mutex<u16>>>,
-    length_counters: Option<(
-        Arc<SoundChannel>,
-        Arc<SoundChannel>,
-        Arc<SoundChannel>,
-        Arc<SoundChannel>,
-    )>,
-}
-
-impl PulseWaveSource {
-    fn new_pulse1(
-        channel: Arc<SoundChannel>,
-        duty: Arc<Mutex<u8>>,
-        pulse1_sweep_unit: Arc<Mutex<SweepUnit>>,
-        pulse2_sweep_unit: Arc<Mutex<SweepUnit>>,
-        pulse1_timer: Arc<Mutex<u16>>,
-        pulse2_timer: Arc<Mutex<u16>>,
-        length_counters: (
-            Arc<SoundChannel>,
-            Arc<SoundChannel>,
-            Arc<SoundChannel>,
-            Arc<SoundChannel>,
-        ),
-    ) -> Self {
-        PulseWaveSource {
-            channel,
-            duty,
-            sample_rate: SAMPLE_RATE,
-            position: 0.0,
-            samples_since_last_frame: 0,
-            pulse1_sweep_unit: Some(pulse1_sweep_unit),
-            pulse2_sweep_unit: Some(pulse2_sweep_unit),
-            pulse1_timer: Some(pulse1_timer),
-            pulse2_timer: Some(pulse2_timer),
-            length_counters: Some(length_counters),
-        }
-    }
-
-    fn new_pulse2(channel: Arc<SoundChannel>, duty: Arc<Mutex<u8>>) -> Self {
-        PulseWaveSource {
-            channel,
-            duty,
-            sample_rate: SAMPLE_RATE,
-            position: 0.0,
-            samples_since_last_frame: 0,
-            pulse1_sweep_unit: None,
-            pulse2_sweep_unit: None,
-            pulse1_timer: None,
-            pulse2_timer: None,
-            length_counters: None,
-        }
-    }
-
-    fn get_duty_output(&self, position: f32) -> f32 {
-        let duty_cycle_val = *self.duty.lock().unwrap() & 0xC0;
-        let duty_percent = match duty_cycle_val {
-            0x00 => 0.125,
-            0x40 => 0.25,
-            0x80 => 0.5,
-            0xC0 => 0.75,
-            _ => 0.5,
-        };
-
-        if position < duty_percent {
-            1.0
-        } else {
-            -1.0
-        }
-    }
-
-    fn clock_frame_sequencer(&mut self) {
-        if let (
-            Some(pulse1_sweep),
-            Some(pulse2_sweep),
-            Some(pulse1_timer),
-            Some(pulse2_timer),
-            Some((pulse1, pulse2, triangle, noise)),
-        ) = (
-            &self.pulse1_sweep_unit,
-            &self.pulse2_sweep_unit,
-            &self.pulse1_timer,
-            &self.pulse2_timer,
-            &self.length_counters,
-        ) {
-            let current_pulse1_timer = *pulse1_timer.lock().unwrap();
-            let current_pulse2_timer = *pulse2_timer.lock().unwrap();
-
-            // Clock sweep units
-            if let Some(new_timer) = pulse1_sweep.lock().unwrap().clock(current_pulse1_timer, 1) {
-                *pulse1_timer.lock().unwrap() = new_timer;
-                pulse1.set_frequency(Apu::get_frequency_from_timer_value(new_timer));
-            }
-
-            if let Some(new_timer) = pulse2_sweep.lock().unwrap().clock(current_pulse2_timer, 2) {
-                *pulse2_timer.lock().unwrap() = new_timer;
-                pulse2.set_frequency(Apu::get_frequency_from_timer_value(new_timer));
-            }
-
-            // Decrement length counters
-            let pulse1_active_due_to_length = pulse1.decrement_length_counter();
-            let pulse2_active_due_to_length = pulse2.decrement_length_counter();
-            let triangle_active_due_to_length = triangle.decrement_length_counter();
-            let noise_active_due_to_length = noise.decrement_length_counter();
-
-            // Determine final channel enabled state (considering sweep mute for pulse channels)
-            let pulse1_final_enabled =
-                pulse1_active_due_to_length && !*pulse1.sweep_mute.lock().unwrap();
-            let pulse2_final_enabled =
-                pulse2_active_due_to_length && !*pulse2.sweep_mute.lock().unwrap();
-
-            pulse1.set_enabled(pulse1_final_enabled);
-            pulse2.set_enabled(pulse2_final_enabled);
-            triangle.set_enabled(triangle_active_due_to_length);
-            noise.set_enabled(noise_active_due_to_length);
-        }
+    pub fn is_channel_muted(&self, channel: Channel) -> bool {
+        self.mixer.is_muted(channel)
     }
-}
 
-impl Source for PulseWaveSource {
-    fn current_frame_len(&self) -> Option<usize> {
-        None
+    /// While any channel is soloed only the soloed channels are heard.
+    pub fn set_channel_solo(&mut self, channel: Channel, solo: bool) {
+        self.mixer.set_solo(channel, solo);
     }
 
-    fn channels(&self) -> u16 {
-        1
+    pub fn is_channel_solo(&self, channel: Channel) -> bool {
+        self.mixer.is_solo(channel)
     }
 
-    fn sample_rate(&self) -> u32 {
-        SAMPLE_RATE
+    /// Puts every channel back at full volume, unmuted and not soloed. Pans are kept.
+    pub fn reset_channel_controls(&mut self) {
+        self.mixer.reset_controls();
     }
 
-    fn total_duration(&self) -> Option<Duration> {
-        None
+    /// Sets a channel's position in stereo output, from -1.0 (left) to 1.0 (right).
+    pub fn set_channel_pan(&mut self, channel: Channel, pan: f32) {
+        self.mixer.set_pan(channel, pan);
     }
-}
 
-impl Iterator for PulseWaveSource {
-    type Item = f32;
+    pub fn channel_pan(&self, channel: Channel) -> f32 {
+        self.mixer.pan(channel)
+    }
 
-    fn next(&mut self) -> Option<f32> {
-        // Clock frame sequencer based on sample count
-        if self.samples_since_last_frame >= SAMPLES_PER_FRAME {
-            self.clock_frame_sequencer();
-            self.samples_since_last_frame = 0;
+    /// Advances the APU by one CPU cycle. Called by the bus on every CPU cycle.
+    pub fn clock(&mut self) {
+        match self.frame_counter.clock() {
+            FrameStep::Quarter => self.clock_quarter_frame(),
+            FrameStep::Half => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            FrameStep::None => {}
         }
-        self.samples_since_last_frame += 1;
-
-        let freq = *self.channel.frequency.lock().unwrap();
-        let vol = *self.channel.volume.lock().unwrap();
-        let enabled = *self.channel.enabled.lock().unwrap();
-        let length_counter = *self.channel.length_counter.lock().unwrap();
-        let sweep_muted = *self.channel.sweep_mute.lock().unwrap();
 
-        if !enabled || freq <= 0.0 || length_counter == 0 || sweep_muted {
-            self.position = 0.0;
-            return Some(0.0);
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
         }
+        self.odd_cycle = !self.odd_cycle;
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock();
 
-        let sample = self.get_duty_output(self.position) * vol;
-
-        self.position += freq / self.sample_rate as f32;
-        while self.position >= 1.0 {
-            self.position -= 1.0;
+        if let Some(vgm) = &mut self.vgm {
+            vgm.tick();
         }
-
-        Some(sample)
-    }
-}
-
-struct TriangleWaveSource {
-    channel: Arc<SoundChannel>,
-    sample_rate: u32,
-    position: f32,
-    index: usize,
-    activate: Arc<(Mutex<bool>, Condvar)>,
-}
-
-impl TriangleWaveSource {
-    fn new(channel: Arc<SoundChannel>, activate: Arc<(Mutex<bool>, Condvar)>) -> Self {
-        TriangleWaveSource {
-            channel,
-            sample_rate: SAMPLE_RATE,
-            position: 0.0,
-            index: 0,
-            activate,
+        let levels = self.levels();
+        let (left, right) = if self.config.stereo {
+            self.mixer.mix_stereo(levels)
+        } else {
+            let level = self.mixer.mix(levels);
+            (level, level)
+        };
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(err) = recorder.clock(&self.mixer, levels, [left, right]) {
+                warn!("audio recording stopped: {}", err);
+                self.recorder = None;
+            }
+        }
+        if !self.config.stereo {
+            if let Some(sample) = self.blip.clock(left) {
+                self.output_sample(sample, sample);
+            }
+            return;
+        }
+        // Both buffers run at the same rate, so they produce their samples together
+        let right = self.right_blip.clock(right);
+        if let (Some(left), Some(right)) = (self.blip.clock(left), right) {
+            self.output_sample(left, right);
         }
     }
-}
 
-impl Source for TriangleWaveSource {
-    fn current_frame_len(&self) -> Option<usize> {
-        None
+    /// Clocks the envelopes and the triangle's linear counter, four times per frame.
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.clock_quarter_frame();
+        self.pulse2.clock_quarter_frame();
+        self.triangle.clock_quarter_frame();
+        self.noise.clock_quarter_frame();
     }
 
-    fn channels(&self) -> u16 {
-        1
+    /// Clocks the sweep units and the length counters, twice per frame.
+    fn clock_half_frame(&mut self) {
+        self.pulse1.clock_half_frame();
+        self.pulse2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
     }
 
-    fn sample_rate(&self) -> u32 {
-        SAMPLE_RATE
+    fn levels(&self) -> ChannelLevels {
+        ChannelLevels {
+            pulse1: self.pulse1.output(),
+            pulse2: self.pulse2.output(),
+            triangle: self.triangle.output(),
+            noise: self.noise.output(),
+            dmc: self.dmc.output(),
+        }
     }
 
-    fn total_duration(&self) -> Option<Duration> {
-        None
+    /// Feeds the cartridge's sound chip output into the mixer, once per CPU cycle.
+    pub fn set_expansion_audio(&mut self, level: f32) {
+        self.mixer.set_expansion(level);
     }
-}
-
-impl Iterator for TriangleWaveSource {
-    type Item = f32;
-
-    fn next(&mut self) -> Option<f32> {
-        let freq = *self.channel.frequency.lock().unwrap();
-        let vol = *self.channel.volume.lock().unwrap();
-        let enabled = *self.channel.enabled.lock().unwrap();
-        let length_counter = *self.channel.length_counter.lock().unwrap();
-        if self.index % 735 == 0 {
-            let (lock, cvar) = &*self.activate;
-            // Use try_lock to avoid blocking
-            if let Ok(mut go) = lock.lock() {
-                *go = true;
-                cvar.notify_all();
-            }
-        }
-        self.index += 1;
-
-        if !enabled || freq <= 0.0 || length_counter == 0 {
-            self.position = 0.0;
-            return Some(0.0);
-        }
 
-        let sample = if self.position < 0.5 {
-            (self.position * 4.0 - 1.0) * vol
+    /// Queued samples relative to the target fill, or `None` without a real-time backend.
+    pub fn audio_fill(&self) -> Option<f32> {
+        if self.realtime {
+            Some(self.producer.len() as f32 / self.config.target_fill() as f32)
         } else {
-            (3.0 - self.position * 4.0) * vol
-        };
-
-        self.position += freq / self.sample_rate as f32;
-
-        while self.position >= 1.0 {
-            self.position -= 1.0;
+            None
         }
-
-        Some(sample)
     }
-}
-
-struct NoiseSource {
-    channel: Arc<SoundChannel>,
-    mode_flag: Arc<Mutex<bool>>,
-    sample_rate: u32,
-    shift_register: u16,
-    sample_period: f32,
-    sample_timer: f32,
-    current_output: f32,
-}
 
-impl NoiseSource {
-    fn new(channel: Arc<SoundChannel>, mode_flag: Arc<Mutex<bool>>) -> Self {
-        NoiseSource {
-            channel,
-            mode_flag,
-            sample_rate: SAMPLE_RATE,
-            shift_register: 1,
-            sample_period: 0.0,
-            sample_timer: 0.0,
-            current_output: 0.0,
+    /// Queues one output frame. `right` is ignored in mono.
+    fn output_sample(&mut self, left: f32, right: f32) {
+        // Produce a little more audio when the queue runs low and a little less when it
+        // fills up, proportionally to the distance from the target
+        if let Some(fill) = self.audio_fill() {
+            let adjust = (1.0 - fill).clamp(-1.0, 1.0) * MAX_RATE_ADJUST;
+            let rate = self.config.sample_rate as f32 * (1.0 + adjust);
+            self.blip.set_sample_rate(rate as u32);
+            self.right_blip.set_sample_rate(rate as u32);
+        }
+        let mut frame = [self.filter.process(left), 0.0];
+        if self.config.stereo {
+            frame[1] = self.right_filter.process(right);
         }
+        let frame = &mut frame[..self.config.channels() as usize];
+        if self.mute {
+            frame.fill(0.0);
+        }
+        // A full buffer means nobody is listening fast enough; the frame is dropped
+        self.producer.push_frame(frame);
+        // Keep at most one second of audio around if nobody is draining it
+        let limit = self.config.sample_rate as usize * frame.len();
+        while self.captured.len() + frame.len() > limit {
+            self.captured.pop_front();
+        }
+        self.captured.extend(frame.iter());
     }
 
-    fn update_shift_register(&mut self) {
-        let bit0 = self.shift_register & 1;
-
-        let feedback_bit = if *self.mode_flag.lock().unwrap() {
-            ((self.shift_register >> 6) & 1) ^ bit0
-        } else {
-            ((self.shift_register >> 1) & 1) ^ bit0
-        };
+    /// Whether the frame counter is asserting /IRQ.
+    pub fn frame_irq(&self) -> bool {
+        self.frame_counter.irq()
+    }
 
-        self.shift_register >>= 1;
-        self.shift_register |= feedback_bit << 14;
-        self.current_output = if bit0 == 0 { 1.0 } else { -1.0 };
+    /// Whether the DMC is asserting /IRQ.
+    pub fn dmc_irq(&self) -> bool {
+        self.dmc.irq()
     }
-}
 
-impl Source for NoiseSource {
-    fn current_frame_len(&self) -> Option<usize> {
-        None
+    /// Address of the next DMC sample byte, once the channel needs it fetched.
+    pub fn dmc_fetch_request(&mut self) -> Option<u16> {
+        self.dmc.fetch_request()
     }
 
-    fn channels(&self) -> u16 {
-        1
+    /// Hands the DMC the sample byte the DMA unit fetched for it.
+    pub fn dmc_sample_fetched(&mut self, data: u8) {
+        if let Some(vgm) = &mut self.vgm {
+            vgm.dmc_fetch(self.dmc.fetch_address(), data);
+        }
+        self.dmc.sample_fetched(data);
     }
 
-    fn sample_rate(&self) -> u32 {
-        SAMPLE_RATE
+    /// Starts writing the output to a WAV file at `path`, and with `stems` every channel to
+    /// its own file next to it. A recording already running is finished first.
+    pub fn start_recording(&mut self, path: &Path, stems: bool) -> io::Result<()> {
+        self.stop_recording()?;
+        let recorder = Recorder::create(path, stems, self.config, self.filter.preset())?;
+        self.recorder = Some(recorder);
+        Ok(())
     }
 
-    fn total_duration(&self) -> Option<Duration> {
-        None
+    /// Finishes the recording, if one is running.
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
     }
-}
 
-impl Iterator for NoiseSource {
-    type Item = f32;
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
 
-    fn next(&mut self) -> Option<f32> {
-        let freq = *self.channel.frequency.lock().unwrap();
-        let vol = *self.channel.volume.lock().unwrap();
-        let enabled = *self.channel.enabled.lock().unwrap();
-        let length_counter = *self.channel.length_counter.lock().unwrap();
+    /// Starts logging register writes to a VGM file at `path`, written when the log is
+    /// stopped. The log opens with the current register values so playback starts from
+    /// the state the APU is in. A log already running is finished first.
+    pub fn start_vgm_log(&mut self, path: &Path) -> io::Result<()> {
+        self.stop_vgm_log()?;
+        let mut vgm = VgmLogger::new(path, CPU_CLOCK);
+        vgm.write_snapshot(&self.registers);
+        self.vgm = Some(vgm);
+        Ok(())
+    }
 
-        if !enabled || freq <= 0.0 || length_counter == 0 {
-            return Some(0.0);
+    /// Sets the VGM loop point to now. Does nothing unless logging.
+    pub fn mark_vgm_loop(&mut self) {
+        if let Some(vgm) = &mut self.vgm {
+            vgm.mark_loop();
         }
+    }
 
-        self.sample_timer += 1.0 / self.sample_rate as f32;
-        self.sample_period = if freq > 0.0 { 1.0 / freq } else { 0.0 };
-
-        if self.sample_period > 0.0 && self.sample_timer >= self.sample_period {
-            self.sample_timer = 0.0;
-            self.update_shift_register();
+    /// Finishes the VGM log and writes the file, if logging.
+    pub fn stop_vgm_log(&mut self) -> io::Result<()> {
+        match self.vgm.take() {
+            Some(vgm) => vgm.finish(),
+            None => Ok(()),
         }
-
-        Some(self.current_output * vol)
     }
-}
-
-pub struct Apu {
-    mute: Arc<Mutex<bool>>,
-
-    pulse1_duty: Arc<Mutex<u8>>,
-    pulse1_sweep: Mutex<u8>,
-    pulse1_timer_low: Mutex<u8>,
-    pulse1_timer_high: Mutex<u8>,
-    pulse1: Arc<SoundChannel>,
-    pulse1_sweep_unit: Arc<Mutex<SweepUnit>>,
-    pulse1_timer: Arc<Mutex<u16>>,
-
-    pulse2_duty: Arc<Mutex<u8>>,
-    pulse2_sweep: Mutex<u8>,
-    pulse2_timer_low: Mutex<u8>,
-    pulse2_timer_high: Mutex<u8>,
-    pulse2: Arc<SoundChannel>,
-    pulse2_sweep_unit: Arc<Mutex<SweepUnit>>,
-    pulse2_timer: Arc<Mutex<u16>>,
-
-    triangle_linear: Mutex<u8>,
-    triangle_timer_low: Mutex<u8>,
-    triangle_timer_high: Mutex<u8>,
-    triangle: Arc<SoundChannel>,
-    triangle_timer: Arc<Mutex<u16>>,
-
-    noise_volume: Mutex<u8>,
-    noise_period: Mutex<u8>,
-    noise_length: Mutex<u8>,
-    noise: Arc<SoundChannel>,
-    noise_mode: Arc<Mutex<bool>>,
-
-    status: Mutex<u8>,
-    frame_counter: Mutex<u8>,
-
-    audio_thread: Mutex<Option<thread::JoinHandle<()>>>,
-    length_counter_table: [u8; 32],
 
-}
-
-impl Apu {
-    pub fn new(activate: Arc<(Mutex<bool>, Condvar)>) -> Self {
-        let pulse1 = Arc::new(SoundChannel::new());
-        let pulse2 = Arc::new(SoundChannel::new());
-        let triangle = Arc::new(SoundChannel::new());
-        let noise = Arc::new(SoundChannel::new());
-
-        let pulse1_duty = Arc::new(Mutex::new(0));
-        let pulse2_duty = Arc::new(Mutex::new(0));
-        let noise_mode = Arc::new(Mutex::new(false));
-        let mute = Arc::new(Mutex::new(false));
-
-        let pulse1_sweep_unit =
-            Arc::new(Mutex::new(SweepUnit::new(Arc::clone(&pulse1.sweep_mute))));
-        let pulse2_sweep_unit =
-            Arc::new(Mutex::new(SweepUnit::new(Arc::clone(&pulse2.sweep_mute))));
-
-        let pulse1_timer = Arc::new(Mutex::new(0));
-        let pulse2_timer = Arc::new(Mutex::new(0));
-        let triangle_timer = Arc::new(Mutex::new(0));
-
-        let length_counter_table: [u8; 32] = [
-            10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20,
-            96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
-        ];
-
-        let apu = Apu {
-            mute,
-            pulse1_duty,
-            pulse1_sweep: Mutex::new(0),
-            pulse1_timer_low: Mutex::new(0),
-            pulse1_timer_high: Mutex::new(0),
-            pulse1,
-            pulse1_sweep_unit,
-            pulse1_timer,
-
-            pulse2_duty,
-            pulse2_sweep: Mutex::new(0),
-            pulse2_timer_low: Mutex::new(0),
-            pulse2_timer_high: Mutex::new(0),
-            pulse2,
-            pulse2_sweep_unit,
-            pulse2_timer,
-
-            triangle_linear: Mutex::new(0),
-            triangle_timer_low: Mutex::new(0),
-            triangle_timer_high: Mutex::new(0),
-            triangle,
-            triangle_timer,
-
-            noise_volume: Mutex::new(0),
-            noise_period: Mutex::new(0),
-            noise_length: Mutex::new(0),
-            noise,
-            noise_mode,
-
-            status: Mutex::new(0x0F),
-            frame_counter: Mutex::new(0),
-
-            audio_thread: Mutex::new(None),
-            length_counter_table,
-        };
-
-        apu.start_audio_thread(activate.clone());
-        apu
+    pub fn is_logging_vgm(&self) -> bool {
+        self.vgm.is_some()
     }
 
-    pub fn toggle_sound(&mut self) {
-        let mute = Arc::clone(&self.mute);
-        let b = *mute.lock().unwrap();
-        *mute.lock().unwrap() = !b;
-    }
-
-    fn start_audio_thread(&self, activate: Arc<(Mutex<bool>, Condvar)>) {
-        let pulse1 = Arc::clone(&self.pulse1);
-        let pulse2 = Arc::clone(&self.pulse2);
-        let triangle = Arc::clone(&self.triangle);
-        let noise = Arc::clone(&self.noise);
-
-        let pulse1_duty = Arc::clone(&self.pulse1_duty);
-        let pulse2_duty = Arc::clone(&self.pulse2_duty);
-        let noise_mode = Arc::clone(&self.noise_mode);
-
-        let pulse1_sweep_unit = Arc::clone(&self.pulse1_sweep_unit);
-        let pulse2_sweep_unit = Arc::clone(&self.pulse2_sweep_unit);
-        let pulse1_timer = Arc::clone(&self.pulse1_timer);
-        let pulse2_timer = Arc::clone(&self.pulse2_timer);
-
-        let handle = thread::spawn(move || {
-            let (_stream, stream_handle) = match OutputStream::try_default() {
-                Ok(result) => result,
-                Err(_) => {
-                    eprintln!("Error initializing audio stream: Could not open output stream.");
-                    return;
-                }
-            };
-
-            let sink = match Sink::try_new(&stream_handle) {
-                Ok(sink) => sink,
-                Err(_) => {
-                    eprintln!("Error initializing audio sink: Could not create sink.");
-                    return;
-                }
-            };
-
-            let length_counters = (
-                Arc::clone(&pulse1),
-                Arc::clone(&pulse2),
-                Arc::clone(&triangle),
-                Arc::clone(&noise),
-            );
-
-            let source1 = PulseWaveSource::new_pulse1(
-                Arc::clone(&pulse1),
-                Arc::clone(&pulse1_duty),
-                Arc::clone(&pulse1_sweep_unit),
-                Arc::clone(&pulse2_sweep_unit),
-                Arc::clone(&pulse1_timer),
-                Arc::clone(&pulse2_timer),
-                length_counters,
-            )
-            .convert_samples::<f32>();
-
-            let source2 =
-                PulseWaveSource::new_pulse2(Arc::clone(&pulse2), Arc::clone(&pulse2_duty))
-                    .convert_samples::<f32>();
-
-            let source3 =
-                TriangleWaveSource::new(Arc::clone(&triangle), activate).convert_samples::<f32>();
-
-            let source4 = NoiseSource::new(Arc::clone(&noise), Arc::clone(&noise_mode))
-                .convert_samples::<f32>();
-
-            let mixed_pulse = source1.mix(source2);
-            let mixed_pulse_triangle = mixed_pulse.mix(source3);
-            let mixed_all = mixed_pulse_triangle.mix(source4);
-
-            let final_source = mixed_all.amplify(0.5);
-
-            sink.append(final_source);
-            sink.play();
-
-            loop {
-                thread::sleep(Duration::from_millis(10000));
-            }
-        });
-
-        *self.audio_thread.lock().unwrap() = Some(handle);
+    /// Drains the samples generated since the last call, at most the last second of them.
+    /// In stereo they come as interleaved left and right pairs.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.captured.drain(..).collect()
     }
 
     pub fn cpu_write(&mut self, address: u16, data: u8) {
-        match address {
-            0x4000 => {
-                *self.pulse1_duty.lock().unwrap() = data;
-                let volume = (data & 0x0F) as f32 / 15.0;
-                self.pulse1.set_volume(volume);
-                self.pulse1.set_length_counter_halt((data & 0x20) != 0);
-                let status = *self.status.lock().unwrap();
-                self.pulse1.set_enabled((status & 0x01) != 0);
-            }
-            0x4001 => {
-                *self.pulse1_sweep.lock().unwrap() = data;
-                self.pulse1_sweep_unit.lock().unwrap().write(data);
-            }
-            0x4002 => {
-                *self.pulse1_timer_low.lock().unwrap() = data;
-                self.update_pulse1_frequency();
-            }
-            0x4003 => {
-                *self.pulse1_timer_high.lock().unwrap() = data & 0b0000_0111;
-                self.update_pulse1_frequency();
-                let length_index = (data >> 3) & 0x1F;
-                let length_value = self.length_counter_table[length_index as usize];
-                self.pulse1.set_length_counter(length_value);
-                self.pulse1_sweep_unit.lock().unwrap().reset_divider();
-                let status = *self.status.lock().unwrap();
-                if (status & 0x01) != 0 {
-                    self.pulse1.set_enabled(true);
-                }
-            }
-
-            0x4004 => {
-                *self.pulse2_duty.lock().unwrap() = data;
-                let volume = (data & 0x0F) as f32 / 15.0;
-                self.pulse2.set_volume(volume);
-                self.pulse2.set_length_counter_halt((data & 0x20) != 0);
-                let status = *self.status.lock().unwrap();
-                self.pulse2.set_enabled((status & 0x02) != 0);
-            }
-            0x4005 => {
-                *self.pulse2_sweep.lock().unwrap() = data;
-                self.pulse2_sweep_unit.lock().unwrap().write(data);
-            }
-            0x4006 => {
-                *self.pulse2_timer_low.lock().unwrap() = data;
-                self.update_pulse2_frequency();
-            }
-            0x4007 => {
-                *self.pulse2_timer_high.lock().unwrap() = data & 0b0000_0111;
-                self.update_pulse2_frequency();
-                let length_index = (data >> 3) & 0x1F;
-                let length_value = self.length_counter_table[length_index as usize];
-                self.pulse2.set_length_counter(length_value);
-                self.pulse2_sweep_unit.lock().unwrap().reset_divider();
-                let status = *self.status.lock().unwrap();
-                if (status & 0x02) != 0 {
-                    self.pulse2.set_enabled(true);
-                }
-            }
-
-            0x4008 => {
-                *self.triangle_linear.lock().unwrap() = data;
-                self.triangle.set_length_counter_halt((data & 0x80) != 0);
-                let status = *self.status.lock().unwrap();
-                self.triangle.set_enabled((status & 0x04) != 0);
-                self.triangle.set_volume(0.9);
-            }
-            0x4009 => {}
-            0x400A => {
-                *self.triangle_timer_low.lock().unwrap() = data;
-                self.update_triangle_frequency();
-            }
-            0x400B => {
-                *self.triangle_timer_high.lock().unwrap() = data & 0b0000_0111;
-                self.update_triangle_frequency();
-                let length_index = (data >> 3) & 0x1F;
-                let length_value = self.length_counter_table[length_index as usize];
-                self.triangle.set_length_counter(length_value);
-                let status = *self.status.lock().unwrap();
-                if (status & 0x04) != 0 {
-                    self.triangle.set_enabled(true);
-                }
-            }
-
-            0x400C => {
-                *self.noise_volume.lock().unwrap() = data;
-                let volume = (data & 0x0F) as f32 / 15.0;
-                self.noise.set_volume(volume);
-                self.noise.set_length_counter_halt((data & 0x20) != 0);
-                let status = *self.status.lock().unwrap();
-                self.noise.set_enabled((status & 0x08) != 0);
-            }
-            0x400D => {}
-            0x400E => {
-                *self.noise_period.lock().unwrap() = data;
-                *self.noise_mode.lock().unwrap() = (data & 0x80) != 0;
-                self.update_noise_frequency();
-            }
-            0x400F => {
-                *self.noise_length.lock().unwrap() = data;
-                let length_index = (data >> 3) & 0x1F;
-                let length_value = self.length_counter_table[length_index as usize];
-                self.noise.set_length_counter(length_value);
-                let status = *self.status.lock().unwrap();
-                if (status & 0x08) != 0 {
-                    self.noise.set_enabled(true);
-                }
+        if let 0x4000..=0x4013 | 0x4015 | 0x4017 = address {
+            self.registers[(address - 0x4000) as usize] = data;
+            if let Some(vgm) = &mut self.vgm {
+                vgm.write_register(address, data);
             }
+        }
+        match address {
+            0x4000..=0x4003 => self.pulse1.write(address & 0x03, data),
+            0x4004..=0x4007 => self.pulse2.write(address & 0x03, data),
+            0x4008..=0x400B => self.triangle.write(address & 0x03, data),
+            0x400C..=0x400F => self.noise.write(address & 0x03, data),
+            0x4010..=0x4013 => self.dmc.write(address, data),
 
             0x4015 => {
-                *self.status.lock().unwrap() = data & 0x0F;
-
-                if (data & 0x01) == 0 {
-                    self.pulse1.set_length_counter(0);
-                }
-                self.pulse1.set_enabled((data & 0x01) != 0);
-
-                if (data & 0x02) == 0 {
-                    self.pulse2.set_length_counter(0);
-                }
-                self.pulse2.set_enabled((data & 0x02) != 0);
-
-                if (data & 0x04) == 0 {
-                    self.triangle.set_length_counter(0);
-                }
-                self.triangle.set_enabled((data & 0x04) != 0);
-
-                if (data & 0x08) == 0 {
-                    self.noise.set_length_counter(0);
-                }
-                self.noise.set_enabled((data & 0x08) != 0);
+                self.pulse1.length.set_enabled(data & 0x01 != 0);
+                self.pulse2.length.set_enabled(data & 0x02 != 0);
+                self.triangle.length.set_enabled(data & 0x04 != 0);
+                self.noise.length.set_enabled(data & 0x08 != 0);
+                self.dmc.set_enabled(data & 0x10 != 0);
             }
 
-            0x4017 => {
-                *self.frame_counter.lock().unwrap() = data;
-            }
+            0x4017 => self.frame_counter.write(data),
 
             _ => {}
         }
     }
 
-    pub fn cpu_read(&self, address: u16) -> u8 {
-        match address {
-            0x4000 => *self.pulse1_duty.lock().unwrap(),
-            0x4001 => *self.pulse1_sweep.lock().unwrap(),
-            0x4002 => *self.pulse1_timer_low.lock().unwrap(),
-            0x4003 => *self.pulse1_timer_high.lock().unwrap(),
-            0x4004 => *self.pulse2_duty.lock().unwrap(),
-            0x4005 => *self.pulse2_sweep.lock().unwrap(),
-            0x4006 => *self.pulse2_timer_low.lock().unwrap(),
-            0x4007 => *self.pulse2_timer_high.lock().unwrap(),
-            0x4008 => *self.triangle_linear.lock().unwrap(),
-            0x4009 => 0,
-            0x400A => *self.triangle_timer_low.lock().unwrap(),
-            0x400B => *self.triangle_timer_high.lock().unwrap(),
-            0x400C => *self.noise_volume.lock().unwrap(),
-            0x400D => 0,
-            0x400E => *self.noise_period.lock().unwrap(),
-            0x400F => *self.noise_length.lock().unwrap(),
-
-            0x4015 => {
-                let mut status = 0x00;
-
-                if *self.pulse1.length_counter.lock().unwrap() > 0 {
-                    status |= 0x01;
-                }
-
-                if *self.pulse2.length_counter.lock().unwrap() > 0 {
-                    status |= 0x02;
-                }
-
-                if *self.triangle.length_counter.lock().unwrap() > 0 {
-                    status |= 0x04;
-                }
-
-                if *self.noise.length_counter.lock().unwrap() > 0 {
-                    status |= 0x08;
-                }
-
-                status
-            }
-
-            0x4017 => *self.frame_counter.lock().unwrap(),
-
-            _ => 0,
+    /// Reads a register. Reading $4015 acknowledges the frame IRQ.
+    pub fn cpu_read(&mut self, address: u16) -> u8 {
+        let data = self.cpu_peek(address);
+        if address == 0x4015 {
+            self.frame_counter.acknowledge();
         }
+        data
     }
 
-    fn get_frequency_from_timer_value(timer: u16) -> f32 {
-        if timer == 0 {
-            0.0
-        } else {
-            1_789_773.0 / (16.0 * (timer as f32 + 1.0))
+    /// Reads a register without acknowledging anything. Only $4015 can be read; the other
+    /// registers are write-only and the bus returns open bus for them.
+    pub fn cpu_peek(&self, address: u16) -> u8 {
+        if address != 0x4015 {
+            return 0;
+        }
+        let mut status = 0x00;
+        if self.pulse1.length.active() {
+            status |= 0x01;
+        }
+        if self.pulse2.length.active() {
+            status |= 0x02;
+        }
+        if self.triangle.length.active() {
+            status |= 0x04;
+        }
+        if self.noise.length.active() {
+            status |= 0x08;
+        }
+        if self.dmc.active() {
+            status |= 0x10;
         }
+        if self.frame_counter.irq() {
+            status |= 0x40;
+        }
+        if self.dmc.irq() {
+            status |= 0x80;
+        }
+        status
     }
+}
 
-    fn update_pulse1_frequency(&self) {
-        let high = *self.pulse1_timer_high.lock().unwrap();
-        let low = *self.pulse1_timer_low.lock().unwrap();
-        let timer = ((high as u16 & 0x07) << 8) | low as u16;
-        *self.pulse1_timer.lock().unwrap() = timer;
-        let freq = Self::get_frequency_from_timer_value(timer);
-        self.pulse1.set_frequency(freq);
+impl Savestate for Apu {
+    /// Saves every channel, the frame counter, the band-limited synthesis buffer and the
+    /// filters, so a restored APU produces exactly the same samples. Samples already queued
+    /// for the audio thread are not machine state and are not saved.
+    fn save_state(&self, w: &mut StateWriter) {
+        self.pulse1.save_state(w);
+        self.pulse2.save_state(w);
+        self.triangle.save_state(w);
+        self.noise.save_state(w);
+        self.dmc.save_state(w);
+        self.frame_counter.save_state(w);
+        w.bool(self.odd_cycle);
+        w.bytes(&self.registers);
+        self.blip.save_state(w);
+        self.filter.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.pulse1.load_state(r)?;
+        self.pulse2.load_state(r)?;
+        self.triangle.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.dmc.load_state(r)?;
+        self.frame_counter.load_state(r)?;
+        self.odd_cycle = r.bool()?;
+        r.bytes_into(&mut self.registers)?;
+        // A running log jumps to the loaded state too
+        if let Some(vgm) = &mut self.vgm {
+            vgm.write_snapshot(&self.registers);
+        }
+        self.blip.load_state(r)?;
+        self.filter.load_state(r)?;
+        // The right side is not saved; starting it from the left keeps both producing
+        // their samples on the same cycles, also when the state was saved in mono
+        self.right_blip = self.blip.clone();
+        self.right_filter = self.filter.clone();
+        Ok(())
     }
+}
 
-    fn update_pulse2_frequency(&self) {
-        let high = *self.pulse2_timer_high.lock().unwrap();
-        let low = *self.pulse2_timer_low.lock().unwrap();
-        let timer = ((high as u16 & 0x07) << 8) | low as u16;
-        *self.pulse2_timer.lock().unwrap() = timer;
-        let freq = Self::get_frequency_from_timer_value(timer);
-        self.pulse2.set_frequency(freq);
+#[cfg(test)]
+///# Unit tests module
+mod apu_tests {
+    use super::*;
+
+    /// Starts a 50% pulse on channel 1 and a triangle
+    fn play_tones(apu: &mut Apu) {
+        for (address, data) in [
+            (0x4015, 0x0F),
+            (0x4000, 0xBF),
+            (0x4002, 0xFD),
+            (0x4003, 0x08),
+            (0x4008, 0x81),
+            (0x400A, 0x40),
+            (0x400B, 0x08),
+        ] {
+            apu.cpu_write(address, data);
+        }
     }
 
-    fn update_triangle_frequency(&self) {
-        let high = *self.triangle_timer_high.lock().unwrap();
-        let low = *self.triangle_timer_low.lock().unwrap();
-        let timer = ((high as u16 & 0x07) << 8) | low as u16;
-        *self.triangle_timer.lock().unwrap() = timer;
-
-        let freq = if timer == 0 {
-            0.0
-        } else {
-            1_789_773.0 / (32.0 * (timer as f32 + 1.0))
-        };
-        self.triangle.set_frequency(freq);
+    #[test]
+    pub fn samples_come_at_the_output_rate() {
+        let mut apu = Apu::new_silent();
+        play_tones(&mut apu);
+        for _ in 0..CPU_CLOCK.div_ceil(10) {
+            apu.clock();
+        }
+        let samples = apu.take_samples();
+        assert_eq!(
+            samples.len(),
+            AudioConfig::default().sample_rate as usize / 10,
+            "sample count, FAILED!"
+        );
+        assert!(
+            samples.iter().any(|sample| *sample > 0.0),
+            "tones are audible, FAILED!"
+        );
+        assert_eq!(
+            apu.consumer.as_mut().unwrap().len(),
+            samples.len(),
+            "samples queued for playback, FAILED!"
+        );
+    }
+
+    #[test]
+    pub fn restored_state_produces_the_same_samples() {
+        let mut apu = Apu::new_silent();
+        play_tones(&mut apu);
+        for _ in 0..12345 {
+            apu.clock();
+        }
+        let mut w = StateWriter::new();
+        apu.save_state(&mut w);
+        let state = w.finish();
+
+        let mut copy = Apu::new_silent();
+        copy.load_state(&mut StateReader::new(&state)).unwrap();
+        apu.take_samples();
+        for _ in 0..50000 {
+            apu.clock();
+            copy.clock();
+        }
+        assert_eq!(
+            apu.take_samples(),
+            copy.take_samples(),
+            "same output, FAILED!"
+        );
+    }
+
+    #[test]
+    pub fn loading_a_state_logs_its_registers() {
+        let path = std::env::temp_dir().join("apu_tests_load.vgm");
+        let mut apu = Apu::new_silent();
+        play_tones(&mut apu);
+        let mut w = StateWriter::new();
+        apu.save_state(&mut w);
+        let state = w.finish();
+
+        apu.cpu_write(0x4015, 0x00);
+        apu.start_vgm_log(&path).unwrap();
+        apu.load_state(&mut StateReader::new(&state)).unwrap();
+        apu.stop_vgm_log().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let enables: Vec<u8> = data
+            .windows(3)
+            .filter(|command| command[..2] == [0xB4, 0x15])
+            .map(|command| command[2])
+            .collect();
+        assert_eq!(enables, vec![0x00, 0x0F], "snapshot after load, FAILED!");
+    }
+
+    #[test]
+    pub fn recording_writes_the_mix_and_stems() {
+        let path = std::env::temp_dir().join("apu_tests_recording.wav");
+        let mut apu = Apu::new_silent();
+        play_tones(&mut apu);
+        apu.start_recording(&path, true).unwrap();
+        for _ in 0..CPU_CLOCK.div_ceil(10) {
+            apu.clock();
+        }
+        apu.stop_recording().unwrap();
+        assert!(!apu.is_recording(), "stopped, FAILED!");
+
+        let mix = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mix.len(), 44 + 4410 * 2, "a tenth of a second, FAILED!");
+        for channel in Channel::APU {
+            let stem_path = recorder::stem_path(&path, channel);
+            let stem = std::fs::read(&stem_path).unwrap();
+            std::fs::remove_file(&stem_path).unwrap();
+            assert_eq!(stem.len(), mix.len(), "stem as long as the mix, FAILED!");
+            let silent = stem[44..].iter().all(|byte| *byte == 0);
+            let playing = matches!(channel, Channel::Pulse1 | Channel::Triangle);
+            assert_eq!(silent, !playing, "only playing channels, FAILED!");
+        }
     }
 
-    fn update_noise_frequency(&self) {
-        let period_idx = *self.noise_period.lock().unwrap() & 0x0F;
-        let period = PERIOD_TABLE[period_idx as usize];
-        let freq = 1_789_773.0 / (period as f32 * 2.0);
-        self.noise.set_frequency(freq);
-    }
-}
+    #[test]
+    pub fn stereo_output_is_interleaved_and_panned() {
+        let config = AudioConfig {
+            stereo: true,
+            ..AudioConfig::default()
+        };
+        let mut apu = Apu::new(config);
+        play_tones(&mut apu);
+        apu.set_channel_pan(Channel::Pulse1, -1.0);
+        apu.set_channel_pan(Channel::Triangle, 1.0);
+        apu.set_channel_muted(Channel::Triangle, true);
+        for _ in 0..CPU_CLOCK.div_ceil(10) {
+            apu.clock();
+        }
 
-impl Drop for Apu {
-    fn drop(&mut self) {
-        self.pulse1.set_enabled(false);
-        self.pulse2.set_enabled(false);
-        self.triangle.set_enabled(false);
-        self.noise.set_enabled(false);
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), 4410 * 2, "a frame per sample, FAILED!");
+        let mut left = samples.iter().step_by(2);
+        let mut right = samples.iter().skip(1).step_by(2);
+        assert!(
+            left.any(|sample| *sample != 0.0),
+            "pulse on the left, FAILED!"
+        );
+        assert!(
+            right.all(|sample| *sample == 0.0),
+            "right only muted, FAILED!"
+        );
     }
 }
@@ -0,0 +1,274 @@
+//! # Audio backends
+//! Where the APU's samples go. A backend takes the consumer end of the sample ring buffer
+//! and drains it on its own thread: [`RodioBackend`] plays it on the default output
+//! device, [`FileBackend`] writes it to a WAV file and [`NullBackend`] discards it.
+//!
+//! Only a backend that plays in real time sets the pace of emulation; with the others
+//! frames follow the wall clock.
+//!
+//! [`RodioBackend`] needs the `audio-device` feature, on by default. Without it nothing
+//! links against the system's audio libraries.
+
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::warn;
+#[cfg(feature = "audio-device")]
+use rodio::{OutputStream, Sink, Source};
+
+use super::ring::SampleConsumer;
+use super::wav::WavWriter;
+
+/// Output format, chosen on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioConfig {
+    pub sample_rate: u32,
+    /// Audio the rate control keeps queued ahead of the device, in milliseconds.
+    pub latency_ms: u32,
+    /// Whether samples come as interleaved left and right pairs instead of mono.
+    pub stereo: bool,
+}
+
+impl AudioConfig {
+    pub fn channels(&self) -> u16 {
+        if self.stereo {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Samples queued at the target latency, counting each channel's.
+    pub fn target_fill(&self) -> usize {
+        let frames = (self.sample_rate as u64 * self.latency_ms as u64 / 1000).max(1);
+        frames as usize * self.channels() as usize
+    }
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44100,
+            latency_ms: 50,
+            stereo: false,
+        }
+    }
+}
+
+pub trait AudioBackend {
+    /// Starts draining `samples`, which arrive at `config.sample_rate` and interleaved in
+    /// stereo. Fails if the output cannot be opened.
+    fn start(&mut self, samples: SampleConsumer, config: AudioConfig) -> io::Result<()>;
+
+    /// Whether samples are consumed at the pace they are played, so the amount queued
+    /// can drive frame pacing and rate control.
+    fn is_realtime(&self) -> bool;
+}
+
+/// Discards all samples. For headless runs and CI.
+pub struct NullBackend;
+
+impl AudioBackend for NullBackend {
+    fn start(&mut self, _samples: SampleConsumer, _config: AudioConfig) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn is_realtime(&self) -> bool {
+        false
+    }
+}
+
+/// Plays the samples on the default output device.
+#[cfg(feature = "audio-device")]
+#[derive(Default)]
+pub struct RodioBackend {
+    /// Dropping this ends the thread that owns the output stream.
+    stop: Option<Sender<()>>,
+}
+
+#[cfg(feature = "audio-device")]
+impl AudioBackend for RodioBackend {
+    fn start(&mut self, samples: SampleConsumer, config: AudioConfig) -> io::Result<()> {
+        let (result_tx, result_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        // The output stream cannot move between threads, so it lives and dies on this one
+        thread::spawn(move || {
+            let (_stream, handle) = match OutputStream::try_default() {
+                Ok(stream) => stream,
+                Err(err) => {
+                    let _ = result_tx.send(Err(err.to_string()));
+                    return;
+                }
+            };
+            let sink = match Sink::try_new(&handle) {
+                Ok(sink) => sink,
+                Err(err) => {
+                    let _ = result_tx.send(Err(err.to_string()));
+                    return;
+                }
+            };
+            sink.append(RingSource {
+                consumer: samples,
+                sample_rate: config.sample_rate,
+                last: vec![0.0; config.channels() as usize],
+                position: 0,
+            });
+            sink.play();
+            let _ = result_tx.send(Ok(()));
+            // Blocks until the backend is dropped
+            let _ = stop_rx.recv();
+        });
+
+        match result_rx.recv() {
+            Ok(Ok(())) => {
+                self.stop = Some(stop_tx);
+                Ok(())
+            }
+            Ok(Err(message)) => Err(io::Error::other(message)),
+            Err(_) => Err(io::Error::other("audio thread exited")),
+        }
+    }
+
+    fn is_realtime(&self) -> bool {
+        true
+    }
+}
+
+/// Plays the samples in the ring buffer. When emulation falls behind the last frame is
+/// held, which is silent, instead of stopping the stream.
+#[cfg(feature = "audio-device")]
+struct RingSource {
+    consumer: SampleConsumer,
+    sample_rate: u32,
+    /// The frame being played, one sample per channel.
+    last: Vec<f32>,
+    /// Index into `last` of the next sample.
+    position: usize,
+}
+
+#[cfg(feature = "audio-device")]
+impl Source for RingSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.last.len() as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(feature = "audio-device")]
+impl Iterator for RingSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        // Frames are queued whole, so a new one is taken only when all of it is there
+        if self.position == 0 && self.consumer.len() >= self.last.len() {
+            for sample in &mut self.last {
+                *sample = self.consumer.pop().unwrap_or_default();
+            }
+        }
+        let sample = self.last[self.position];
+        self.position = (self.position + 1) % self.last.len();
+        Some(sample)
+    }
+}
+
+/// Writes the samples to a WAV file. The file is complete once the backend is dropped.
+pub struct FileBackend {
+    path: PathBuf,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FileBackend {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            stop: None,
+            thread: None,
+        }
+    }
+}
+
+impl AudioBackend for FileBackend {
+    fn start(&mut self, samples: SampleConsumer, config: AudioConfig) -> io::Result<()> {
+        let wav = WavWriter::create(&self.path, config.sample_rate, config.channels())?;
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        self.stop = Some(stop_tx);
+        self.thread = Some(thread::spawn(move || {
+            if let Err(err) = write_samples(samples, wav, stop_rx) {
+                warn!("could not write audio file: {}", err);
+            }
+        }));
+        Ok(())
+    }
+
+    fn is_realtime(&self) -> bool {
+        false
+    }
+}
+
+/// Moves samples from the queue to the file every few milliseconds until told to stop.
+fn write_samples(
+    mut samples: SampleConsumer,
+    mut wav: WavWriter,
+    stop: Receiver<()>,
+) -> io::Result<()> {
+    loop {
+        while let Some(sample) = samples.pop() {
+            wav.write_sample(sample)?;
+        }
+        if stop.recv_timeout(Duration::from_millis(10)) != Err(RecvTimeoutError::Timeout) {
+            break;
+        }
+    }
+    while let Some(sample) = samples.pop() {
+        wav.write_sample(sample)?;
+    }
+    wav.finish()
+}
+
+impl Drop for FileBackend {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod backend_tests {
+    use super::super::ring::ring_buffer;
+    use super::*;
+
+    #[test]
+    pub fn file_backend_writes_everything_queued() {
+        let path = std::env::temp_dir().join("backend_tests_file.wav");
+        let (mut producer, consumer) = ring_buffer(4096);
+        let mut backend = FileBackend::new(&path);
+        backend.start(consumer, AudioConfig::default()).unwrap();
+        assert!(!backend.is_realtime(), "not a clock, FAILED!");
+        for _ in 0..1000 {
+            producer.push(0.25);
+        }
+        drop(backend);
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), 44 + 2000, "every sample written, FAILED!");
+    }
+}
@@ -0,0 +1,172 @@
+//! # Band-limited synthesis
+//! Turns the mixer level, known at every CPU cycle, into output samples without aliasing.
+//! Sampling the level directly, or averaging it, lets the harmonics of the square edges
+//! above half the sample rate fold back as audible tones on high notes.
+//!
+//! Like a blip buffer, this only looks at changes of the level. Each change is added to a
+//! delta buffer as a band-limited impulse (a windowed sinc placed at the sub-sample time of
+//! the change) and the output is the running sum of the buffer. The kernel is precomputed
+//! for a number of sub-sample phases. The output lags the input by half the kernel width.
+
+use std::f32::consts::PI;
+use std::io;
+
+use crate::states::{StateReader, StateWriter};
+
+/// Kernel taps, in output samples.
+const WIDTH: usize = 16;
+/// Sub-sample positions the kernel is computed for.
+const PHASES: usize = 64;
+/// Delta buffer length, a power of two at least the kernel width.
+const BUFFER: usize = 32;
+/// Cutoff of the kernel as a fraction of the sample rate, just below Nyquist.
+const CUTOFF: f32 = 0.45;
+
+#[derive(Clone)]
+pub struct BlipBuffer {
+    clock_rate: u32,
+    sample_rate: u32,
+    /// Kernel taps for each phase; each row sums to 1.
+    kernel: Vec<[f32; WIDTH]>,
+    deltas: [f32; BUFFER],
+    /// Index of the next output sample in `deltas`.
+    position: usize,
+    /// Accumulates `sample_rate` every clock; a sample is due each `clock_rate`.
+    phase: u32,
+    /// Running sum of the deltas read so far, the current output.
+    sum: f32,
+    /// Last input level.
+    level: f32,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: u32, sample_rate: u32) -> Self {
+        let kernel = (0..PHASES)
+            .map(|phase| {
+                let center = (WIDTH / 2) as f32 + phase as f32 / PHASES as f32;
+                let mut taps = [0.0; WIDTH];
+                for (k, tap) in taps.iter_mut().enumerate() {
+                    let x = k as f32 - center;
+                    let sinc = if x == 0.0 {
+                        1.0
+                    } else {
+                        (2.0 * PI * CUTOFF * x).sin() / (2.0 * PI * CUTOFF * x)
+                    };
+                    // Blackman window over the kernel width
+                    let w = (x + (WIDTH / 2) as f32) / WIDTH as f32;
+                    let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+                    *tap = sinc * window.max(0.0);
+                }
+                let total: f32 = taps.iter().sum();
+                taps.iter_mut().for_each(|tap| *tap /= total);
+                taps
+            })
+            .collect();
+        Self {
+            clock_rate,
+            sample_rate,
+            kernel,
+            deltas: [0.0; BUFFER],
+            position: 0,
+            phase: 0,
+            sum: 0.0,
+            level: 0.0,
+        }
+    }
+
+    /// Changes the output rate, which changes the resampling ratio from the next sample on.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// Takes the input level for one clock and returns an output sample when one is due.
+    pub fn clock(&mut self, level: f32) -> Option<f32> {
+        if level != self.level {
+            let delta = level - self.level;
+            self.level = level;
+            let phase = (self.phase as u64 * PHASES as u64 / self.clock_rate as u64) as usize;
+            for (k, tap) in self.kernel[phase].iter().enumerate() {
+                self.deltas[(self.position + k) % BUFFER] += delta * tap;
+            }
+        }
+
+        self.phase += self.sample_rate;
+        if self.phase < self.clock_rate {
+            return None;
+        }
+        self.phase -= self.clock_rate;
+        self.sum += self.deltas[self.position];
+        self.deltas[self.position] = 0.0;
+        self.position = (self.position + 1) % BUFFER;
+        Some(self.sum)
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for delta in &self.deltas {
+            w.f32(*delta);
+        }
+        w.u8(self.position as u8);
+        w.u32(self.phase);
+        w.f32(self.sum);
+        w.f32(self.level);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        for delta in self.deltas.iter_mut() {
+            *delta = r.f32()?;
+        }
+        self.position = r.u8()? as usize % BUFFER;
+        self.phase = r.u32()?;
+        self.sum = r.f32()?;
+        self.level = r.f32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod blip_tests {
+    use super::*;
+
+    /// Runs a square wave with the given period in clocks through a buffer clocked at 10x
+    /// the sample rate and returns the output.
+    fn square(period: u32) -> Vec<f32> {
+        let mut blip = BlipBuffer::new(441_000, 44_100);
+        (0..441_000)
+            .filter_map(|clock| {
+                let high = clock / (period / 2) % 2 == 0;
+                blip.clock(high as u8 as f32)
+            })
+            .collect()
+    }
+
+    #[test]
+    pub fn steps_settle_at_the_input_level() {
+        let mut blip = BlipBuffer::new(441_000, 44_100);
+        let mut last = 0.0;
+        for _ in 0..1000 {
+            if let Some(sample) = blip.clock(0.5) {
+                last = sample;
+            }
+        }
+        assert!((last - 0.5).abs() < 0.0001, "follows the level, FAILED!");
+    }
+
+    #[test]
+    pub fn tones_above_nyquist_are_suppressed() {
+        // 31.5 kHz folds back to 12.6 kHz when sampled directly
+        let high = square(14);
+        let peak = high[100..]
+            .iter()
+            .map(|sample| (sample - 0.5).abs())
+            .fold(0.0, f32::max);
+        assert!(peak < 0.1, "no aliased tone, FAILED!");
+
+        let low = square(1000);
+        let peak = low[100..]
+            .iter()
+            .map(|sample| (sample - 0.5).abs())
+            .fold(0.0, f32::max);
+        assert!(peak > 0.4, "audible tones pass, FAILED!");
+    }
+}
@@ -0,0 +1,307 @@
+//! # DMC
+//! The delta modulation channel. It plays 1-bit delta encoded samples stored in PRG
+//! space: every output clock moves a 7-bit level up or down by 2 depending on the next
+//! bit. Sample bytes are fetched by the DMA unit, which halts the CPU for the read, so
+//! the channel only asks for the next byte and gets it back a few cycles later.
+//!
+//! $4010 sets the rate, loop flag and IRQ enable, $4011 loads the output level directly,
+//! $4012 and $4013 set the sample address ($C000 + A * 64) and length (L * 16 + 1).
+
+use std::io;
+
+use crate::states::{Savestate, StateReader, StateWriter};
+
+/// Output clock periods in CPU cycles, NTSC.
+const RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    /// Output clock period in CPU cycles.
+    rate: u16,
+    /// CPU cycles left until the next output clock.
+    timer: u16,
+    /// 7-bit output level.
+    output_level: u8,
+    /// Start address and length of the sample, from $4012 and $4013.
+    sample_address: u16,
+    sample_length: u16,
+    /// Address of the next byte to fetch and bytes left in the sample.
+    current_address: u16,
+    bytes_remaining: u16,
+    /// Byte fetched ahead of the shift register.
+    sample_buffer: Option<u8>,
+    /// Whether a fetch was handed to the DMA unit and has not arrived yet.
+    fetch_requested: bool,
+    shift_register: u8,
+    bits_remaining: u8,
+    /// Set when the buffer was empty as the shift register ran out; the level holds.
+    silence: bool,
+    irq_flag: bool,
+}
+
+impl Dmc {
+    pub fn new() -> Self {
+        Self {
+            irq_enabled: false,
+            looping: false,
+            rate: RATE_TABLE[0],
+            timer: RATE_TABLE[0],
+            output_level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            fetch_requested: false,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq_flag: false,
+        }
+    }
+
+    /// Handles a write to $4010-$4013.
+    pub fn write(&mut self, address: u16, data: u8) {
+        match address {
+            0x4010 => {
+                self.irq_enabled = data & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq_flag = false;
+                }
+                self.looping = data & 0x40 != 0;
+                self.rate = RATE_TABLE[(data & 0x0F) as usize];
+            }
+            0x4011 => self.output_level = data & 0x7F,
+            0x4012 => self.sample_address = 0xC000 | ((data as u16) << 6),
+            0x4013 => self.sample_length = ((data as u16) << 4) + 1,
+            _ => {}
+        }
+    }
+
+    /// Handles bit 4 of a $4015 write, which also acknowledges the DMC IRQ. Enabling the
+    /// channel restarts the sample only if the previous one has finished.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    /// Whether sample bytes are left, reported in bit 4 of $4015.
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    /// Whether the DMC IRQ flag is set, which also means /IRQ is asserted.
+    pub fn irq(&self) -> bool {
+        self.irq_flag
+    }
+
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+
+    /// Address of the next sample byte once the buffer needs refilling. Returns it only
+    /// once per byte; the byte is handed back through `sample_fetched`.
+    pub fn fetch_request(&mut self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 && !self.fetch_requested {
+            self.fetch_requested = true;
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    /// Address of the byte being fetched, until it is handed over.
+    pub fn fetch_address(&self) -> u16 {
+        self.current_address
+    }
+
+    /// Stores a fetched sample byte and moves on to the next one, looping or raising the
+    /// IRQ at the end of the sample.
+    pub fn sample_fetched(&mut self, data: u8) {
+        self.fetch_requested = false;
+        self.sample_buffer = Some(data);
+        // The channel was disabled while the fetch was under way; the sample is already over
+        if self.bytes_remaining == 0 {
+            return;
+        }
+        // The address wraps from $FFFF around to $8000
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    /// Advances the channel by one CPU cycle.
+    pub fn clock(&mut self) {
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.rate;
+
+        if !self.silence {
+            if self.shift_register & 1 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.shift_register = data;
+                    self.silence = false;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+}
+
+impl Default for Dmc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Dmc {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.irq_enabled);
+        w.bool(self.looping);
+        w.u16(self.rate);
+        w.u16(self.timer);
+        w.u8(self.output_level);
+        w.u16(self.sample_address);
+        w.u16(self.sample_length);
+        w.u16(self.current_address);
+        w.u16(self.bytes_remaining);
+        w.bool(self.sample_buffer.is_some());
+        w.u8(self.sample_buffer.unwrap_or(0));
+        w.bool(self.fetch_requested);
+        w.u8(self.shift_register);
+        w.u8(self.bits_remaining);
+        w.bool(self.silence);
+        w.bool(self.irq_flag);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.irq_enabled = r.bool()?;
+        self.looping = r.bool()?;
+        self.rate = r.u16()?;
+        self.timer = r.u16()?;
+        self.output_level = r.u8()?;
+        self.sample_address = r.u16()?;
+        self.sample_length = r.u16()?;
+        self.current_address = r.u16()?;
+        self.bytes_remaining = r.u16()?;
+        let has_buffer = r.bool()?;
+        let buffer = r.u8()?;
+        self.sample_buffer = has_buffer.then_some(buffer);
+        self.fetch_requested = r.bool()?;
+        self.shift_register = r.u8()?;
+        self.bits_remaining = r.u8()?;
+        self.silence = r.bool()?;
+        self.irq_flag = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod dmc_tests {
+    use super::*;
+
+    /// Clocks the channel, serving its fetches from `memory` at $C000, and returns the
+    /// addresses fetched
+    fn run(dmc: &mut Dmc, memory: &[u8], cycles: u32) -> Vec<u16> {
+        let mut fetched = Vec::new();
+        for _ in 0..cycles {
+            if let Some(address) = dmc.fetch_request() {
+                fetched.push(address);
+                dmc.sample_fetched(memory[(address - 0xC000) as usize]);
+            }
+            dmc.clock();
+        }
+        fetched
+    }
+
+    #[test]
+    pub fn plays_sample_and_raises_irq() {
+        let mut dmc = Dmc::new();
+        dmc.write(0x4010, 0x8F); // IRQ on, fastest rate
+        dmc.write(0x4011, 0x40);
+        dmc.write(0x4012, 0x00);
+        dmc.write(0x4013, 0x00); // 1 byte
+        dmc.set_enabled(true);
+        assert!(dmc.active(), "sample started, FAILED!");
+        // The first output clock comes after the power-on period of 428 cycles
+        let fetched = run(&mut dmc, &[0xFF], 428 + 54 * 15);
+        assert_eq!(fetched, vec![0xC000], "one fetch, FAILED!");
+        assert!(!dmc.active(), "sample finished, FAILED!");
+        assert!(dmc.irq(), "irq at the end of the sample, FAILED!");
+        // The byte of ones was played: the first 8 clocks are silent, the next 8 go up
+        assert_eq!(dmc.output(), 0x40 + 16, "output level, FAILED!");
+        dmc.set_enabled(false);
+        assert!(!dmc.irq(), "$4015 write acknowledges, FAILED!");
+    }
+
+    #[test]
+    pub fn looping_sample_restarts() {
+        let mut dmc = Dmc::new();
+        dmc.write(0x4010, 0x4F);
+        dmc.write(0x4013, 0x00);
+        dmc.set_enabled(true);
+        let fetched = run(&mut dmc, &[0x00], 54 * 8 * 3);
+        assert!(fetched.len() >= 3, "refetched, FAILED!");
+        assert!(
+            fetched.iter().all(|address| *address == 0xC000),
+            "same address, FAILED!"
+        );
+        assert!(dmc.active() && !dmc.irq(), "looping, no irq, FAILED!");
+    }
+
+    #[test]
+    pub fn disabling_during_a_fetch_ends_the_sample() {
+        for control in [0x4F, 0x8F] {
+            let mut dmc = Dmc::new();
+            dmc.write(0x4010, control); // looping or IRQ on
+            dmc.write(0x4013, 0x00);
+            dmc.set_enabled(true);
+            assert_eq!(dmc.fetch_request(), Some(0xC000), "fetch, FAILED!");
+            dmc.set_enabled(false);
+            dmc.sample_fetched(0x55);
+            assert!(!dmc.active(), "stays disabled, FAILED!");
+            assert!(!dmc.irq(), "no irq, FAILED!");
+            assert_eq!(dmc.fetch_request(), None, "no further fetch, FAILED!");
+        }
+    }
+
+    #[test]
+    pub fn direct_load_sets_level() {
+        let mut dmc = Dmc::new();
+        dmc.write(0x4011, 0xFF);
+        assert_eq!(dmc.output(), 0x7F, "7-bit level, FAILED!");
+    }
+}
@@ -0,0 +1,117 @@
+//! # Envelope
+//! Volume control of the pulse and noise channels. Unless the constant volume bit is set,
+//! the volume decays from 15 to 0, one step every V + 1 quarter frames, and starts over
+//! at 15 when the loop flag (the length counter halt bit) is set. A write to the
+//! channel's fourth register restarts the decay on the next quarter frame.
+
+use std::io;
+
+use crate::states::{Savestate, StateReader, StateWriter};
+
+#[derive(Default)]
+pub struct Envelope {
+    /// Set by a write to the fourth register; the next clock restarts the decay.
+    start: bool,
+    divider: u8,
+    decay: u8,
+    looping: bool,
+    constant: bool,
+    /// Constant volume, or the divider period, from the low 4 bits of the first register.
+    volume: u8,
+}
+
+impl Envelope {
+    /// Takes the loop, constant volume and volume bits of the channel's first register.
+    pub fn write(&mut self, data: u8) {
+        self.looping = data & 0x20 != 0;
+        self.constant = data & 0x10 != 0;
+        self.volume = data & 0x0F;
+    }
+
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    /// Clocks the envelope, on quarter frames.
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    /// Current volume, 0-15.
+    pub fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+impl Savestate for Envelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.start);
+        w.u8(self.divider);
+        w.u8(self.decay);
+        w.bool(self.looping);
+        w.bool(self.constant);
+        w.u8(self.volume);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.start = r.bool()?;
+        self.divider = r.u8()?;
+        self.decay = r.u8()?;
+        self.looping = r.bool()?;
+        self.constant = r.bool()?;
+        self.volume = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod envelope_tests {
+    use super::*;
+
+    #[test]
+    pub fn decays_every_period_and_loops() {
+        let mut envelope = Envelope::default();
+        envelope.write(0x01); // decaying, divider period 2
+        envelope.restart();
+        envelope.clock();
+        assert_eq!(envelope.output(), 15, "restarts at 15, FAILED!");
+        envelope.clock();
+        assert_eq!(envelope.output(), 15, "divider counts first, FAILED!");
+        envelope.clock();
+        assert_eq!(envelope.output(), 14, "one step every 2 clocks, FAILED!");
+        for _ in 0..28 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 0, "decayed to 0, FAILED!");
+        for _ in 0..4 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 0, "stays at 0 without loop, FAILED!");
+
+        envelope.write(0x21);
+        envelope.clock();
+        envelope.clock();
+        assert_eq!(envelope.output(), 15, "loops back to 15, FAILED!");
+
+        envelope.write(0x17);
+        assert_eq!(envelope.output(), 7, "constant volume, FAILED!");
+    }
+}
@@ -0,0 +1,159 @@
+//! # Output filter
+//! Models the analog path between the APU and the audio jack with first-order filters run
+//! on the sample stream. The high-pass filters remove the DC offset of the mixer output,
+//! which otherwise pops whenever a channel starts or stops, and the low-pass filter takes
+//! the edge off the aliasing of the square waves.
+//!
+//! | preset  | stages                                             |
+//! |---------|----------------------------------------------------|
+//! | nes     | high-pass 90 Hz, high-pass 440 Hz, low-pass 14 kHz |
+//! | famicom | high-pass 37 Hz, low-pass 14 kHz                   |
+//! | none    | -                                                  |
+
+use std::f32::consts::PI;
+use std::io;
+
+use clap::ValueEnum;
+
+use crate::states::{StateReader, StateWriter};
+
+/// Which console's output path to model.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FilterPreset {
+    /// NES front-loader.
+    #[default]
+    Nes,
+    Famicom,
+    /// Raw mixer output.
+    None,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    HighPass,
+    LowPass,
+}
+
+/// One first-order RC filter stage.
+#[derive(Debug, Clone)]
+struct Stage {
+    kind: Kind,
+    alpha: f32,
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl Stage {
+    fn new(kind: Kind, cutoff: f32, sample_rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        let alpha = match kind {
+            Kind::HighPass => rc / (rc + dt),
+            Kind::LowPass => dt / (rc + dt),
+        };
+        Self {
+            kind,
+            alpha,
+            prev_input: 0.0,
+            prev_output: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = match self.kind {
+            Kind::HighPass => self.alpha * (self.prev_output + input - self.prev_input),
+            Kind::LowPass => self.prev_output + self.alpha * (input - self.prev_output),
+        };
+        self.prev_input = input;
+        self.prev_output = output;
+        output
+    }
+}
+
+#[derive(Clone)]
+pub struct FilterChain {
+    preset: FilterPreset,
+    stages: Vec<Stage>,
+}
+
+impl FilterChain {
+    pub fn new(preset: FilterPreset, sample_rate: u32) -> Self {
+        let stages = match preset {
+            FilterPreset::Nes => vec![
+                Stage::new(Kind::HighPass, 90.0, sample_rate),
+                Stage::new(Kind::HighPass, 440.0, sample_rate),
+                Stage::new(Kind::LowPass, 14_000.0, sample_rate),
+            ],
+            FilterPreset::Famicom => vec![
+                Stage::new(Kind::HighPass, 37.0, sample_rate),
+                Stage::new(Kind::LowPass, 14_000.0, sample_rate),
+            ],
+            FilterPreset::None => Vec::new(),
+        };
+        Self { preset, stages }
+    }
+
+    pub fn preset(&self) -> FilterPreset {
+        self.preset
+    }
+
+    /// Runs one sample through every stage.
+    pub fn process(&mut self, sample: f32) -> f32 {
+        self.stages
+            .iter_mut()
+            .fold(sample, |sample, stage| stage.process(sample))
+    }
+
+    /// Saves the stages' history. The preset is a setting of the frontend and is not saved.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.stages.len() as u8);
+        for stage in &self.stages {
+            w.f32(stage.prev_input);
+            w.f32(stage.prev_output);
+        }
+    }
+
+    /// Restores what `save_state` wrote. History saved with a different preset does not
+    /// fit these stages and is dropped, which only costs a short transient.
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        let count = r.u8()? as usize;
+        let mut history = Vec::with_capacity(count);
+        for _ in 0..count {
+            history.push((r.f32()?, r.f32()?));
+        }
+        if history.len() != self.stages.len() {
+            history = vec![(0.0, 0.0); self.stages.len()];
+        }
+        for (stage, (input, output)) in self.stages.iter_mut().zip(history) {
+            stage.prev_input = input;
+            stage.prev_output = output;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod filter_tests {
+    use super::*;
+
+    #[test]
+    pub fn high_pass_removes_dc_offset() {
+        let mut chain = FilterChain::new(FilterPreset::Nes, 44100);
+        let mut output = 0.0;
+        for _ in 0..44100 {
+            output = chain.process(0.5);
+        }
+        assert!(output.abs() < 0.0001, "settles at 0, FAILED!");
+
+        let mut raw = FilterChain::new(FilterPreset::None, 44100);
+        assert_eq!(raw.process(0.5), 0.5, "passes through, FAILED!");
+    }
+
+    #[test]
+    pub fn low_pass_softens_edges() {
+        let mut chain = FilterChain::new(FilterPreset::Famicom, 44100);
+        let step = chain.process(1.0);
+        assert!(step > 0.0 && step < 1.0, "edge is rounded, FAILED!");
+    }
+}
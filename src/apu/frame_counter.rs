@@ -0,0 +1,234 @@
+//! # Frame counter
+//! The APU's frame sequencer. It counts CPU cycles and, at fixed points of a 4-step or
+//! 5-step sequence, clocks the envelopes and triangle linear counter (quarter frames) and
+//! the length counters and sweep units (half frames). In 4-step mode it also raises the
+//! frame IRQ at the end of every sequence unless $4017 inhibits it.
+//!
+//! | step | 4-step (CPU cycle) | 5-step (CPU cycle) |
+//! |------|--------------------|--------------------|
+//! | 1    | 7457 quarter       | 7457 quarter       |
+//! | 2    | 14913 half         | 14913 half         |
+//! | 3    | 22371 quarter      | 22371 quarter      |
+//! | 4    | 29828 IRQ          | 29829 nothing      |
+//! |      | 29829 half, IRQ    | 37281 half         |
+//! |      | 29830 IRQ, restart | 37282 restart      |
+
+use std::io;
+
+use crate::states::{Savestate, StateReader, StateWriter};
+
+/// What the sequencer clocks on a cycle. A half frame clocks the quarter frame units too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameStep {
+    None,
+    Quarter,
+    Half,
+}
+
+pub struct FrameCounter {
+    /// 5-step mode, bit 7 of $4017.
+    five_step: bool,
+    /// IRQ inhibit, bit 6 of $4017.
+    irq_inhibit: bool,
+    /// Frame IRQ flag, read through bit 6 of $4015.
+    irq_flag: bool,
+    /// CPU cycles since the sequence started.
+    cycle: u32,
+    /// Whether the last cycle was an odd CPU cycle, i.e. between two APU cycles.
+    odd: bool,
+    /// Mode written to $4017 and the cycles left before the sequencer restarts with it.
+    pending_write: Option<(u8, bool)>,
+}
+
+impl FrameCounter {
+    pub fn new() -> Self {
+        Self {
+            five_step: false,
+            irq_inhibit: false,
+            irq_flag: false,
+            cycle: 0,
+            odd: false,
+            pending_write: None,
+        }
+    }
+
+    /// Handles a write to $4017. The inhibit bit acts at once and clears the IRQ flag;
+    /// the sequencer restarts 3 or 4 CPU cycles later, depending on whether the write
+    /// landed on an APU cycle.
+    pub fn write(&mut self, data: u8) {
+        self.irq_inhibit = data & 0x40 != 0;
+        if self.irq_inhibit {
+            self.irq_flag = false;
+        }
+        let delay = if self.odd { 4 } else { 3 };
+        self.pending_write = Some((delay, data & 0x80 != 0));
+    }
+
+    /// Whether the frame IRQ flag is set, which also means /IRQ is asserted.
+    pub fn irq(&self) -> bool {
+        self.irq_flag
+    }
+
+    /// Clears the frame IRQ flag, as reading $4015 does.
+    pub fn acknowledge(&mut self) {
+        self.irq_flag = false;
+    }
+
+    /// Advances the sequencer by one CPU cycle and reports what it clocks on it.
+    pub fn clock(&mut self) -> FrameStep {
+        self.odd = !self.odd;
+        if let Some((delay, five_step)) = self.pending_write {
+            if delay > 1 {
+                self.pending_write = Some((delay - 1, five_step));
+            } else {
+                self.pending_write = None;
+                self.five_step = five_step;
+                self.cycle = 0;
+                // Switching to 5-step mode clocks every unit right away
+                return if five_step {
+                    FrameStep::Half
+                } else {
+                    FrameStep::None
+                };
+            }
+        }
+
+        self.cycle += 1;
+        match (self.five_step, self.cycle) {
+            (_, 7457) | (_, 22371) => FrameStep::Quarter,
+            (_, 14913) => FrameStep::Half,
+            (false, 29828) => {
+                self.set_irq();
+                FrameStep::None
+            }
+            (false, 29829) => {
+                self.set_irq();
+                FrameStep::Half
+            }
+            (false, 29830) => {
+                self.set_irq();
+                self.cycle = 0;
+                FrameStep::None
+            }
+            (true, 37281) => FrameStep::Half,
+            (true, 37282) => {
+                self.cycle = 0;
+                FrameStep::None
+            }
+            _ => FrameStep::None,
+        }
+    }
+
+    fn set_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.irq_flag = true;
+        }
+    }
+}
+
+impl Default for FrameCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for FrameCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.five_step);
+        w.bool(self.irq_inhibit);
+        w.bool(self.irq_flag);
+        w.u32(self.cycle);
+        w.bool(self.odd);
+        let (delay, five_step) = self.pending_write.unwrap_or((0, false));
+        w.u8(delay);
+        w.bool(five_step);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.five_step = r.bool()?;
+        self.irq_inhibit = r.bool()?;
+        self.irq_flag = r.bool()?;
+        self.cycle = r.u32()?;
+        self.odd = r.bool()?;
+        let delay = r.u8()?;
+        let five_step = r.bool()?;
+        self.pending_write = (delay > 0).then_some((delay, five_step));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod frame_counter_tests {
+    use super::*;
+
+    /// Clocks `counter` for `cycles` cycles and returns the cycles with a step on them
+    fn steps(counter: &mut FrameCounter, cycles: u32) -> Vec<(u32, FrameStep)> {
+        (1..=cycles)
+            .map(|cycle| (cycle, counter.clock()))
+            .filter(|(_, step)| *step != FrameStep::None)
+            .collect()
+    }
+
+    #[test]
+    pub fn four_step_sequence_raises_irq() {
+        let mut counter = FrameCounter::new();
+        assert_eq!(
+            steps(&mut counter, 29827),
+            vec![
+                (7457, FrameStep::Quarter),
+                (14913, FrameStep::Half),
+                (22371, FrameStep::Quarter),
+            ],
+            "4-step sequence, FAILED!"
+        );
+        assert!(!counter.irq(), "no irq before the last step, FAILED!");
+        assert_eq!(counter.clock(), FrameStep::None, "cycle 29828, FAILED!");
+        assert!(counter.irq(), "irq at 29828, FAILED!");
+        assert_eq!(counter.clock(), FrameStep::Half, "cycle 29829, FAILED!");
+        counter.clock();
+        counter.acknowledge();
+        assert_eq!(
+            steps(&mut counter, 7457),
+            vec![(7457, FrameStep::Quarter)],
+            "sequence restarts after 29830 cycles, FAILED!"
+        );
+        assert!(!counter.irq(), "acknowledged, FAILED!");
+    }
+
+    #[test]
+    pub fn five_step_sequence_has_no_irq() {
+        let mut counter = FrameCounter::new();
+        counter.write(0x80);
+        // The write lands on an even cycle, so the sequencer restarts 3 cycles later
+        assert_eq!(
+            steps(&mut counter, 3),
+            vec![(3, FrameStep::Half)],
+            "immediate clock, FAILED!"
+        );
+        assert_eq!(
+            steps(&mut counter, 37282),
+            vec![
+                (7457, FrameStep::Quarter),
+                (14913, FrameStep::Half),
+                (22371, FrameStep::Quarter),
+                (37281, FrameStep::Half),
+            ],
+            "5-step sequence, FAILED!"
+        );
+        assert!(!counter.irq(), "5-step never raises irq, FAILED!");
+    }
+
+    #[test]
+    pub fn inhibit_clears_and_blocks_irq() {
+        let mut counter = FrameCounter::new();
+        steps(&mut counter, 29830);
+        assert!(counter.irq(), "irq raised, FAILED!");
+        counter.clock();
+        counter.write(0x40);
+        assert!(!counter.irq(), "inhibit clears the flag, FAILED!");
+        // Written on an odd cycle: 4 cycles of delay, then a full sequence
+        steps(&mut counter, 4 + 29830);
+        assert!(!counter.irq(), "inhibited, FAILED!");
+    }
+}
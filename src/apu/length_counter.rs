@@ -0,0 +1,70 @@
+//! # Length counter
+//! Silences a channel after a number of half frames loaded from a table on the write to
+//! its fourth register. Shared by the pulse, triangle and noise channels.
+
+use std::io;
+
+use crate::states::{Savestate, StateReader, StateWriter};
+
+/// Half frames for each 5-bit length index.
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+#[derive(Default)]
+pub struct LengthCounter {
+    counter: u8,
+    /// Halt flag, which doubles as the envelope loop flag (the triangle's control flag).
+    halt: bool,
+    /// Channel enable bit of $4015.
+    enabled: bool,
+}
+
+impl LengthCounter {
+    /// Handles the channel's bit of a $4015 write; disabling clears the counter.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn set_halt(&mut self, halt: bool) {
+        self.halt = halt;
+    }
+
+    /// Loads the counter from the top 5 bits of a register write, if the channel is enabled.
+    pub fn load(&mut self, data: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(data >> 3) as usize];
+        }
+    }
+
+    /// Counts down one half frame unless halted.
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    /// Whether the channel may play, reported in $4015.
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+impl Savestate for LengthCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.counter);
+        w.bool(self.halt);
+        w.bool(self.enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.counter = r.u8()?;
+        self.halt = r.bool()?;
+        self.enabled = r.bool()?;
+        Ok(())
+    }
+}
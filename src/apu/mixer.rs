@@ -0,0 +1,394 @@
+//! # Mixer
+//! Combines the channel outputs the way the console's resistor network does. The two
+//! pulses share one DAC and the triangle, noise and DMC another, and neither responds
+//! linearly: a channel gets quieter the more the others on its DAC output. The nesdev
+//! formulas are precomputed into two lookup tables:
+//!
+//! ```text
+//! pulse_out = 95.52 / (8128 / (pulse1 + pulse2) + 100)
+//! tnd_out = 163.67 / (24329 / (3 * triangle + 2 * noise + dmc) + 100)
+//! ```
+//!
+//! Cartridges with their own sound chip add their output on top, through the expansion
+//! input.
+//!
+//! Each channel, the expansion input included, can be muted, soloed or given a volume.
+//! While all of them play at full volume the tables are used as they are. Otherwise each
+//! channel's DAC input is scaled by its gain and the formulas are evaluated directly, so
+//! the remaining channels keep the nonlinear interaction they have on the console.
+//!
+//! For stereo output each channel also has a pan position, and each side is mixed on its
+//! own with the channels scaled by how much of them goes to that side. A centered channel
+//! plays at full level on both sides, so with every channel centered both sides equal the
+//! mono mix.
+
+/// Channel outputs for one cycle, in the units of each channel's DAC.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ChannelLevels {
+    pub pulse1: u8,
+    pub pulse2: u8,
+    pub triangle: u8,
+    pub noise: u8,
+    pub dmc: u8,
+}
+
+/// The mixer's inputs: the APU's channels and the cartridge's sound chip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    Expansion,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 6] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::Dmc,
+        Channel::Expansion,
+    ];
+
+    /// The channels of the APU itself, without the expansion input.
+    pub const APU: [Channel; 5] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::Dmc,
+    ];
+
+    /// Lowercase name, used in file names.
+    pub fn name(self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+            Channel::Expansion => "expansion",
+        }
+    }
+
+    /// The channel called `name`, as returned by [`Channel::name`].
+    pub fn from_name(name: &str) -> Option<Channel> {
+        Channel::ALL
+            .into_iter()
+            .find(|channel| channel.name() == name)
+    }
+}
+
+/// Mute, solo and volume of one channel. Frontend settings, not part of save states.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ChannelControl {
+    volume: f32,
+    muted: bool,
+    solo: bool,
+}
+
+impl Default for ChannelControl {
+    fn default() -> Self {
+        Self {
+            volume: 1.0,
+            muted: false,
+            solo: false,
+        }
+    }
+}
+
+pub struct Mixer {
+    /// pulse_out indexed by pulse1 + pulse2, 0-30.
+    pulse_table: [f32; 31],
+    /// tnd_out indexed by 3 * triangle + 2 * noise + dmc, 0-202.
+    tnd_table: [f32; 203],
+    /// Level of the cartridge's sound chip, on the same scale as the output.
+    expansion: f32,
+    /// Indexed by `Channel as usize`.
+    controls: [ChannelControl; 6],
+    /// What each channel is multiplied by, from `controls`.
+    gains: [f32; 6],
+    /// Whether every gain is 1.0, so the tables can be used.
+    unity: bool,
+    /// Pan positions from -1.0 (left) to 1.0 (right), indexed by `Channel as usize`.
+    /// Kept apart from `controls` so resetting those keeps the stereo layout.
+    pans: [f32; 6],
+    /// `gains` scaled by how much of each channel goes left and right.
+    left_gains: [f32; 6],
+    right_gains: [f32; 6],
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        let mut pulse_table = [0.0; 31];
+        for (n, level) in pulse_table.iter_mut().enumerate().skip(1) {
+            *level = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+        let mut tnd_table = [0.0; 203];
+        for (n, level) in tnd_table.iter_mut().enumerate().skip(1) {
+            *level = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+        Self {
+            pulse_table,
+            tnd_table,
+            expansion: 0.0,
+            controls: [ChannelControl::default(); 6],
+            gains: [1.0; 6],
+            unity: true,
+            pans: [0.0; 6],
+            left_gains: [1.0; 6],
+            right_gains: [1.0; 6],
+        }
+    }
+
+    /// Sets the expansion audio input, which is added to the APU's output until changed.
+    pub fn set_expansion(&mut self, level: f32) {
+        self.expansion = level;
+    }
+
+    /// Sets a channel's volume, 1.0 being its level on the console. Clamped to 0.0-2.0.
+    pub fn set_volume(&mut self, channel: Channel, volume: f32) {
+        self.controls[channel as usize].volume = volume.clamp(0.0, 2.0);
+        self.update_gains();
+    }
+
+    pub fn volume(&self, channel: Channel) -> f32 {
+        self.controls[channel as usize].volume
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.controls[channel as usize].muted = muted;
+        self.update_gains();
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.controls[channel as usize].muted
+    }
+
+    /// While any channel is soloed only the soloed channels are heard, muted or not.
+    pub fn set_solo(&mut self, channel: Channel, solo: bool) {
+        self.controls[channel as usize].solo = solo;
+        self.update_gains();
+    }
+
+    pub fn is_solo(&self, channel: Channel) -> bool {
+        self.controls[channel as usize].solo
+    }
+
+    /// Sets where a channel sits in stereo output, from -1.0 (left) through 0.0 (both
+    /// sides, the default) to 1.0 (right). Has no effect on the mono mix.
+    pub fn set_pan(&mut self, channel: Channel, pan: f32) {
+        self.pans[channel as usize] = pan.clamp(-1.0, 1.0);
+        self.update_gains();
+    }
+
+    pub fn pan(&self, channel: Channel) -> f32 {
+        self.pans[channel as usize]
+    }
+
+    /// Unmutes and unsolos every channel and puts them back at full volume.
+    pub fn reset_controls(&mut self) {
+        self.controls = [ChannelControl::default(); 6];
+        self.update_gains();
+    }
+
+    fn update_gains(&mut self) {
+        let soloing = self.controls.iter().any(|control| control.solo);
+        for (gain, control) in self.gains.iter_mut().zip(&self.controls) {
+            let audible = if soloing {
+                control.solo
+            } else {
+                !control.muted
+            };
+            *gain = if audible { control.volume } else { 0.0 };
+        }
+        self.unity = self.gains.iter().all(|gain| *gain == 1.0);
+        for (i, pan) in self.pans.iter().enumerate() {
+            // Panning turns the far side down and leaves the near one at full level
+            self.left_gains[i] = self.gains[i] * (1.0 - pan).min(1.0);
+            self.right_gains[i] = self.gains[i] * (1.0 + pan).min(1.0);
+        }
+    }
+
+    /// Output level for the given channel outputs, 0.0 to about 1.0 without expansion audio.
+    pub fn mix(&self, levels: ChannelLevels) -> f32 {
+        if !self.unity {
+            return self.mix_scaled(levels, &self.gains);
+        }
+        let pulse = levels.pulse1 as usize + levels.pulse2 as usize;
+        let tnd = 3 * levels.triangle as usize + 2 * levels.noise as usize + levels.dmc as usize;
+        self.pulse_table[pulse] + self.tnd_table[tnd] + self.expansion
+    }
+
+    /// Left and right output levels for stereo.
+    pub fn mix_stereo(&self, levels: ChannelLevels) -> (f32, f32) {
+        if self.pans.iter().all(|pan| *pan == 0.0) {
+            let level = self.mix(levels);
+            return (level, level);
+        }
+        (
+            self.mix_scaled(levels, &self.left_gains),
+            self.mix_scaled(levels, &self.right_gains),
+        )
+    }
+
+    /// `mix` with each input scaled by the given gains.
+    fn mix_scaled(&self, levels: ChannelLevels, gains: &[f32; 6]) -> f32 {
+        let [pulse1, pulse2, triangle, noise, dmc, expansion] = *gains;
+        let pulse = levels.pulse1 as f32 * pulse1 + levels.pulse2 as f32 * pulse2;
+        let tnd = 3.0 * levels.triangle as f32 * triangle
+            + 2.0 * levels.noise as f32 * noise
+            + levels.dmc as f32 * dmc;
+        let mut level = self.expansion * expansion;
+        if pulse > 0.0 {
+            level += 95.52 / (8128.0 / pulse + 100.0);
+        }
+        if tnd > 0.0 {
+            level += 163.67 / (24329.0 / tnd + 100.0);
+        }
+        level
+    }
+
+    /// What each channel would output if it played alone, in the order of [`Channel::APU`].
+    /// Ignores the channel controls.
+    /// Because the mixer is nonlinear these add up to a little more than `mix`.
+    pub fn isolated(&self, levels: ChannelLevels) -> [f32; 5] {
+        [
+            self.pulse_table[levels.pulse1 as usize],
+            self.pulse_table[levels.pulse2 as usize],
+            self.tnd_table[3 * levels.triangle as usize],
+            self.tnd_table[2 * levels.noise as usize],
+            self.tnd_table[levels.dmc as usize],
+        ]
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod mixer_tests {
+    use super::*;
+
+    #[test]
+    pub fn channels_mix_nonlinearly() {
+        let mut mixer = Mixer::new();
+        let silent = ChannelLevels::default();
+        assert_eq!(mixer.mix(silent), 0.0, "silence, FAILED!");
+
+        let one = mixer.mix(ChannelLevels {
+            pulse1: 15,
+            ..silent
+        });
+        let both = mixer.mix(ChannelLevels {
+            pulse1: 15,
+            pulse2: 15,
+            ..silent
+        });
+        assert!(both < 2.0 * one, "second pulse adds less, FAILED!");
+
+        let full = mixer.mix(ChannelLevels {
+            pulse1: 15,
+            pulse2: 15,
+            triangle: 15,
+            noise: 15,
+            dmc: 127,
+        });
+        assert!(
+            (full - 1.0).abs() < 0.01,
+            "full scale is about 1.0, FAILED!"
+        );
+
+        mixer.set_expansion(0.25);
+        assert_eq!(mixer.mix(silent), 0.25, "expansion audio adds up, FAILED!");
+    }
+
+    #[test]
+    pub fn controls_scale_mute_and_solo_channels() {
+        let mut mixer = Mixer::new();
+        let levels = ChannelLevels {
+            pulse1: 15,
+            triangle: 15,
+            ..Default::default()
+        };
+        let pulse_only = mixer.mix(ChannelLevels {
+            triangle: 0,
+            ..levels
+        });
+        let full = mixer.mix(levels);
+
+        mixer.set_muted(Channel::Triangle, true);
+        assert!(
+            (mixer.mix(levels) - pulse_only).abs() < 1e-6,
+            "muted, FAILED!"
+        );
+
+        mixer.set_solo(Channel::Triangle, true);
+        let triangle_only = mixer.mix(levels);
+        assert!(triangle_only > 0.0, "solo beats mute, FAILED!");
+        assert!(
+            (triangle_only + pulse_only - full).abs() < 1e-6,
+            "separate DACs add up, FAILED!"
+        );
+        mixer.set_solo(Channel::Triangle, false);
+
+        mixer.set_muted(Channel::Triangle, false);
+        mixer.set_volume(Channel::Pulse1, 0.5);
+        let half = mixer.mix(levels);
+        assert!(half < full && half > triangle_only, "half volume, FAILED!");
+
+        mixer.set_expansion(0.25);
+        mixer.set_muted(Channel::Expansion, true);
+        assert_eq!(mixer.mix(levels), half, "expansion muted, FAILED!");
+
+        mixer.reset_controls();
+        assert_eq!(mixer.volume(Channel::Pulse1), 1.0, "reset volume, FAILED!");
+        assert_eq!(
+            mixer.mix(levels),
+            full + 0.25,
+            "back to the tables, FAILED!"
+        );
+    }
+
+    #[test]
+    pub fn panned_channels_move_between_sides() {
+        let mut mixer = Mixer::new();
+        let levels = ChannelLevels {
+            pulse1: 15,
+            pulse2: 8,
+            ..Default::default()
+        };
+        let mono = mixer.mix(levels);
+        assert_eq!(mixer.mix_stereo(levels), (mono, mono), "centered, FAILED!");
+
+        mixer.set_pan(Channel::Pulse1, -1.0);
+        mixer.set_pan(Channel::Pulse2, 1.0);
+        let (left, right) = mixer.mix_stereo(levels);
+        let pulse1 = mixer.isolated(levels)[0];
+        let pulse2 = mixer.isolated(levels)[1];
+        assert!(
+            (left - pulse1).abs() < 1e-6,
+            "pulse 1 alone on the left, FAILED!"
+        );
+        assert!(
+            (right - pulse2).abs() < 1e-6,
+            "pulse 2 alone on the right, FAILED!"
+        );
+        assert_eq!(mixer.mix(levels), mono, "mono mix unchanged, FAILED!");
+
+        mixer.reset_controls();
+        assert_eq!(mixer.pan(Channel::Pulse1), -1.0, "pans kept, FAILED!");
+        assert_eq!(
+            Channel::from_name("dmc"),
+            Some(Channel::Dmc),
+            "by name, FAILED!"
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! # Noise
+//! The noise channel ($400C-$400F). A timer with a period from a 16 entry table clocks a
+//! 15-bit linear feedback shift register; bit 0 of the register gates the output. Mode 1
+//! takes the feedback from bit 6 instead of bit 1, giving a short, metallic sequence.
+//! The periods differ between NTSC and PAL consoles, which have different CPU clocks.
+
+use std::io;
+
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::states::{Savestate, StateReader, StateWriter};
+
+/// Timer periods in CPU cycles, NTSC.
+const NTSC_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+/// Timer periods in CPU cycles, PAL.
+const PAL_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+pub struct Noise {
+    short_mode: bool,
+    /// Uses the PAL period table.
+    pal: bool,
+    /// Period index, the low 4 bits of $400E.
+    period: u8,
+    timer: u16,
+    shift_register: u16,
+    pub length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Self {
+            short_mode: false,
+            pal: false,
+            period: 0,
+            timer: 0,
+            // The register is loaded with 1 at power on
+            shift_register: 1,
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+        }
+    }
+
+    /// Handles a write to one of the channel's four registers, `register` being 0-3.
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.length.set_halt(data & 0x20 != 0);
+                self.envelope.write(data);
+            }
+            2 => {
+                self.short_mode = data & 0x80 != 0;
+                self.period = data & 0x0F;
+            }
+            3 => {
+                self.length.load(data);
+                self.envelope.restart();
+            }
+            _ => {}
+        }
+    }
+
+    /// Selects the PAL period table instead of the NTSC one.
+    pub fn set_pal(&mut self, pal: bool) {
+        self.pal = pal;
+    }
+
+    fn timer_period(&self) -> u16 {
+        let table = if self.pal {
+            &PAL_PERIOD_TABLE
+        } else {
+            &NTSC_PERIOD_TABLE
+        };
+        table[self.period as usize]
+    }
+
+    /// Clocks the timer, once per CPU cycle.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period() - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Clocks the envelope, on quarter frames.
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// Clocks the length counter, on half frames.
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    /// Current output, 0-15.
+    pub fn output(&self) -> u8 {
+        if self.shift_register & 1 != 0 || !self.length.active() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Noise {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.pal);
+        w.bool(self.short_mode);
+        w.u8(self.period);
+        w.u16(self.timer);
+        w.u16(self.shift_register);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.pal = r.bool()?;
+        self.short_mode = r.bool()?;
+        self.period = r.u8()?;
+        self.timer = r.u16()?;
+        self.shift_register = r.u16()?;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod noise_tests {
+    use super::*;
+
+    #[test]
+    pub fn period_table_follows_the_region() {
+        let mut noise = Noise::new();
+        noise.write(2, 0x0F);
+        noise.clock_timer();
+        assert_eq!(noise.timer + 1, 4068, "NTSC period, FAILED!");
+
+        let mut w = StateWriter::new();
+        noise.set_pal(true);
+        noise.save_state(&mut w);
+        let mut noise = Noise::new();
+        noise
+            .load_state(&mut StateReader::new(&w.finish()))
+            .unwrap();
+        noise.timer = 0;
+        noise.clock_timer();
+        assert_eq!(noise.timer + 1, 3778, "PAL period after load, FAILED!");
+    }
+}
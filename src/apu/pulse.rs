@@ -0,0 +1,216 @@
+//! # Pulse
+//! The two square wave channels ($4000-$4003 and $4004-$4007). An 11-bit timer clocked
+//! every APU cycle steps an 8-step duty sequencer, and the sweep unit can bend the timer
+//! period every half frame. The two channels differ only in how the sweep negates.
+
+use std::io;
+
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::states::{Savestate, StateReader, StateWriter};
+
+/// Waveforms of the four duty settings: 12.5%, 25%, 50% and 25% negated.
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+pub struct Pulse {
+    /// Pulse 1 negates the sweep change with one's complement, pulse 2 with two's.
+    ones_complement: bool,
+    duty: u8,
+    duty_step: u8,
+    timer_period: u16,
+    timer: u16,
+    pub length: LengthCounter,
+    envelope: Envelope,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    /// Pulse 1 when `first` is true, pulse 2 otherwise.
+    pub fn new(first: bool) -> Self {
+        Self {
+            ones_complement: first,
+            duty: 0,
+            duty_step: 0,
+            timer_period: 0,
+            timer: 0,
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+
+    /// Handles a write to one of the channel's four registers, `register` being 0-3.
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.length.set_halt(data & 0x20 != 0);
+                self.envelope.write(data);
+            }
+            1 => {
+                self.sweep_enabled = data & 0x80 != 0;
+                self.sweep_period = (data >> 4) & 0x07;
+                self.sweep_negate = data & 0x08 != 0;
+                self.sweep_shift = data & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.length.load(data);
+                self.envelope.restart();
+                self.duty_step = 0;
+            }
+            _ => {}
+        }
+    }
+
+    /// Clocks the timer, once per APU cycle (every other CPU cycle).
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_step = (self.duty_step + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Clocks the envelope, on quarter frames.
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// Clocks the sweep unit and the length counter, on half frames.
+    pub fn clock_half_frame(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.timer_period = self.target_period();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+        self.length.clock();
+    }
+
+    /// Period the sweep unit is aiming for. It is computed all the time, even with the
+    /// sweep disabled, and mutes the channel when it overflows.
+    fn target_period(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            self.timer_period
+                .saturating_sub(change)
+                .saturating_sub(self.ones_complement as u16)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    fn muted(&self) -> bool {
+        self.timer_period < 8 || self.target_period() > 0x7FF
+    }
+
+    /// Current output, 0-15.
+    pub fn output(&self) -> u8 {
+        if !self.length.active()
+            || self.muted()
+            || DUTY_TABLE[self.duty as usize][self.duty_step as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl Savestate for Pulse {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.duty);
+        w.u8(self.duty_step);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+        w.bool(self.sweep_enabled);
+        w.u8(self.sweep_period);
+        w.bool(self.sweep_negate);
+        w.u8(self.sweep_shift);
+        w.u8(self.sweep_divider);
+        w.bool(self.sweep_reload);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.duty = r.u8()?;
+        self.duty_step = r.u8()?;
+        self.timer_period = r.u16()?;
+        self.timer = r.u16()?;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+        self.sweep_enabled = r.bool()?;
+        self.sweep_period = r.u8()?;
+        self.sweep_negate = r.bool()?;
+        self.sweep_shift = r.u8()?;
+        self.sweep_divider = r.u8()?;
+        self.sweep_reload = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod pulse_tests {
+    use super::*;
+
+    #[test]
+    pub fn sweep_bends_period_and_mutes_on_overflow() {
+        let mut pulse = Pulse::new(true);
+        pulse.length.set_enabled(true);
+        pulse.write(0, 0xBF); // 50% duty, constant volume 15
+        pulse.write(2, 0x00);
+        pulse.write(3, 0x0A); // period $200
+        pulse.write(1, 0x81); // sweep on, period 0, shift 1, adding
+        pulse.clock_half_frame();
+        assert_eq!(pulse.timer_period, 0x300, "period grows by half, FAILED!");
+        pulse.clock_half_frame();
+        assert_eq!(pulse.timer_period, 0x480, "period grows again, FAILED!");
+        pulse.clock_half_frame();
+        assert_eq!(pulse.timer_period, 0x6C0, "period grows to $6C0, FAILED!");
+        // The next target is above $7FF, which mutes the channel and stops the sweep
+        assert!(pulse.muted(), "muted, FAILED!");
+        pulse.clock_half_frame();
+        assert_eq!(pulse.timer_period, 0x6C0, "no update past $7FF, FAILED!");
+
+        // Pulse 1 subtracts one more than pulse 2 when negating
+        let mut pulse2 = Pulse::new(false);
+        pulse.write(1, 0x89);
+        pulse.timer_period = 0x480;
+        pulse2.write(1, 0x89);
+        pulse2.timer_period = 0x480;
+        assert_eq!(
+            pulse.target_period(),
+            0x480 - 0x240 - 1,
+            "one's complement, FAILED!"
+        );
+        assert_eq!(
+            pulse2.target_period(),
+            0x480 - 0x240,
+            "two's complement, FAILED!"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! # Audio recorder
+//! Writes the APU output to WAV files, optionally with one stem per channel next to the
+//! mix. The recorder runs its own band-limited synthesis and filters at the nominal
+//! sample rate instead of tapping the playback stream, so recordings do not pick up the
+//! rate control's adjustments and two runs with the same input give identical files.
+//!
+//! In stereo mode the mix is recorded in stereo too. Stems are mono: each channel as it
+//! would sound alone, unpanned. The mixer is nonlinear, so the stems add up to slightly
+//! more than the mix.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::backend::AudioConfig;
+use super::blip::BlipBuffer;
+use super::filter::{FilterChain, FilterPreset};
+use super::mixer::{Channel, ChannelLevels, Mixer};
+use super::wav::WavWriter;
+use super::CPU_CLOCK;
+
+/// One output file and the synthesis feeding it, one per channel of the file.
+struct Track {
+    synths: Vec<(BlipBuffer, FilterChain)>,
+    wav: WavWriter,
+}
+
+impl Track {
+    fn create(
+        path: &Path,
+        sample_rate: u32,
+        channels: u16,
+        preset: FilterPreset,
+    ) -> io::Result<Self> {
+        let synths = (0..channels)
+            .map(|_| {
+                let blip = BlipBuffer::new(CPU_CLOCK, sample_rate);
+                (blip, FilterChain::new(preset, sample_rate))
+            })
+            .collect();
+        Ok(Self {
+            synths,
+            wav: WavWriter::create(path, sample_rate, channels)?,
+        })
+    }
+
+    /// Takes a level per channel of the file; extra levels are ignored.
+    fn clock(&mut self, levels: &[f32]) -> io::Result<()> {
+        // The buffers run at the same rate, so they all produce a sample on the same cycle
+        // and the channels come out interleaved
+        for ((blip, filter), level) in self.synths.iter_mut().zip(levels) {
+            if let Some(sample) = blip.clock(*level) {
+                self.wav.write_sample(filter.process(sample))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Recorder {
+    mix: Track,
+    /// One track per channel in the order of `Channel::APU`, if stems were asked for.
+    stems: Vec<Track>,
+}
+
+impl Recorder {
+    /// Starts recording the mix to `path`, in the format of `config`. With `stems`, each
+    /// channel also goes to a file named after `path` with the channel name appended, like
+    /// `song.pulse1.wav`.
+    pub fn create(
+        path: &Path,
+        stems: bool,
+        config: AudioConfig,
+        preset: FilterPreset,
+    ) -> io::Result<Self> {
+        let rate = config.sample_rate;
+        let mix = Track::create(path, rate, config.channels(), preset)?;
+        let stems = if stems {
+            Channel::APU
+                .iter()
+                .map(|channel| Track::create(&stem_path(path, *channel), rate, 1, preset))
+                .collect::<io::Result<_>>()?
+        } else {
+            Vec::new()
+        };
+        Ok(Self { mix, stems })
+    }
+
+    /// Takes the channel outputs and the left and right mixed levels of one CPU cycle. In
+    /// mono only the left level is used.
+    pub fn clock(
+        &mut self,
+        mixer: &Mixer,
+        levels: ChannelLevels,
+        mixed: [f32; 2],
+    ) -> io::Result<()> {
+        self.mix.clock(&mixed)?;
+        if !self.stems.is_empty() {
+            for (track, level) in self.stems.iter_mut().zip(mixer.isolated(levels)) {
+                track.clock(&[level])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Completes the files.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.mix.wav.finish()?;
+        for track in &mut self.stems {
+            track.wav.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// `song.wav` becomes `song.pulse1.wav` for pulse 1.
+pub fn stem_path(path: &Path, channel: Channel) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.{}.wav", stem, channel.name()))
+}
@@ -0,0 +1,187 @@
+//! # Ring buffer
+//! A lock-free single-producer single-consumer queue of samples. The emulation thread
+//! pushes samples as the APU generates them and the audio thread pops them when the
+//! device asks for more; neither side ever blocks or takes a lock, so a slow audio
+//! callback cannot stall emulation and the other way round.
+//!
+//! Samples are stored as the bits of an `f32` in atomics, which keeps the queue in safe
+//! code. The head and tail only ever grow (wrapping), and a slot is reused only after
+//! the consumer published that it moved past it.
+
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+struct Shared {
+    slots: Box<[AtomicU32]>,
+    /// Index of the next sample to pop.
+    head: AtomicUsize,
+    /// Index of the next sample to push.
+    tail: AtomicUsize,
+}
+
+impl Shared {
+    fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+}
+
+/// Creates a queue holding up to `capacity` samples and returns its two ends.
+pub fn ring_buffer(capacity: usize) -> (SampleProducer, SampleConsumer) {
+    let shared = Arc::new(Shared {
+        slots: (0..capacity.max(1)).map(|_| AtomicU32::new(0)).collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (
+        SampleProducer {
+            shared: Arc::clone(&shared),
+        },
+        SampleConsumer { shared },
+    )
+}
+
+/// The pushing end, owned by the APU.
+pub struct SampleProducer {
+    shared: Arc<Shared>,
+}
+
+impl SampleProducer {
+    /// Queues a sample. Returns false and drops it if the queue is full.
+    pub fn push(&mut self, sample: f32) -> bool {
+        let slots = &self.shared.slots;
+        let tail = self.shared.tail.load(Ordering::Relaxed);
+        let head = self.shared.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == slots.len() {
+            return false;
+        }
+        slots[tail % slots.len()].store(sample.to_bits(), Ordering::Relaxed);
+        self.shared
+            .tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Queues the samples of one frame, like a left and right pair, together: either all
+    /// of them become visible to the consumer at once or, if they do not fit, none.
+    pub fn push_frame(&mut self, frame: &[f32]) -> bool {
+        let slots = &self.shared.slots;
+        let tail = self.shared.tail.load(Ordering::Relaxed);
+        let head = self.shared.head.load(Ordering::Acquire);
+        if slots.len() - tail.wrapping_sub(head) < frame.len() {
+            return false;
+        }
+        for (i, sample) in frame.iter().enumerate() {
+            slots[tail.wrapping_add(i) % slots.len()].store(sample.to_bits(), Ordering::Relaxed);
+        }
+        self.shared
+            .tail
+            .store(tail.wrapping_add(frame.len()), Ordering::Release);
+        true
+    }
+
+    /// Samples queued and not popped yet.
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.slots.len()
+    }
+}
+
+/// The popping end, owned by the audio backend.
+pub struct SampleConsumer {
+    shared: Arc<Shared>,
+}
+
+impl SampleConsumer {
+    /// Takes the oldest queued sample, if any.
+    pub fn pop(&mut self) -> Option<f32> {
+        let slots = &self.shared.slots;
+        let head = self.shared.head.load(Ordering::Relaxed);
+        let tail = self.shared.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let sample = f32::from_bits(slots[head % slots.len()].load(Ordering::Relaxed));
+        self.shared
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(sample)
+    }
+
+    /// Samples queued and not popped yet.
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.slots.len()
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod ring_tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    pub fn push_pop_and_full() {
+        let (mut producer, mut consumer) = ring_buffer(3);
+        assert_eq!(consumer.pop(), None, "empty, FAILED!");
+        for i in 0..3 {
+            assert!(producer.push(i as f32), "push {}, FAILED!", i);
+        }
+        assert!(!producer.push(3.0), "full, FAILED!");
+        assert_eq!(consumer.pop(), Some(0.0), "oldest first, FAILED!");
+        assert!(producer.push(3.0), "slot freed, FAILED!");
+        assert_eq!(consumer.len(), 3, "len, FAILED!");
+        let rest: Vec<f32> = std::iter::from_fn(|| consumer.pop()).collect();
+        assert_eq!(rest, vec![1.0, 2.0, 3.0], "wraps around, FAILED!");
+    }
+
+    #[test]
+    pub fn frames_are_pushed_whole() {
+        let (mut producer, mut consumer) = ring_buffer(5);
+        assert!(producer.push_frame(&[1.0, 2.0]), "first frame, FAILED!");
+        assert!(producer.push_frame(&[3.0, 4.0]), "second frame, FAILED!");
+        assert!(!producer.push_frame(&[5.0, 6.0]), "no room, FAILED!");
+        assert_eq!(consumer.len(), 4, "nothing half pushed, FAILED!");
+        consumer.pop();
+        assert!(producer.push_frame(&[5.0, 6.0]), "wraps around, FAILED!");
+        let rest: Vec<f32> = std::iter::from_fn(|| consumer.pop()).collect();
+        assert_eq!(rest, vec![2.0, 3.0, 4.0, 5.0, 6.0], "in order, FAILED!");
+    }
+
+    #[test]
+    pub fn samples_arrive_in_order_across_threads() {
+        let (mut producer, mut consumer) = ring_buffer(64);
+        let writer = thread::spawn(move || {
+            let mut next = 0;
+            while next < 10_000 {
+                if producer.push(next as f32) {
+                    next += 1;
+                }
+            }
+        });
+        let mut expected = 0;
+        while expected < 10_000 {
+            if let Some(sample) = consumer.pop() {
+                assert_eq!(sample, expected as f32, "sample order, FAILED!");
+                expected += 1;
+            }
+        }
+        writer.join().unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+//! # Triangle
+//! The triangle channel ($4008-$400B). Its timer is clocked every CPU cycle and steps a
+//! 32-step sequence that ramps the 4-bit output down and back up. It has no volume
+//! control; instead a linear counter, reloaded from $4008 and clocked every quarter
+//! frame, cuts the note off with a finer resolution than the length counter.
+
+use std::io;
+
+use super::length_counter::LengthCounter;
+use crate::states::{Savestate, StateReader, StateWriter};
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+#[derive(Default)]
+pub struct Triangle {
+    timer_period: u16,
+    timer: u16,
+    step: u8,
+    pub length: LengthCounter,
+    linear_counter: u8,
+    linear_reload_value: u8,
+    /// Set by a write to $400B; the next quarter frame reloads the linear counter.
+    linear_reload: bool,
+    /// Control flag, bit 7 of $4008. It also halts the length counter.
+    control: bool,
+}
+
+impl Triangle {
+    /// Handles a write to one of the channel's four registers, `register` being 0-3.
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.control = data & 0x80 != 0;
+                self.length.set_halt(self.control);
+                self.linear_reload_value = data & 0x7F;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.length.load(data);
+                self.linear_reload = true;
+            }
+            _ => {}
+        }
+    }
+
+    /// Clocks the timer, once per CPU cycle. The sequencer only moves while both counters
+    /// are running, so a silenced triangle holds its level instead of popping.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) & 0x1F;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Clocks the linear counter, on quarter frames. The reload flag stays set while the
+    /// control flag is, which keeps reloading the counter.
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    /// Clocks the length counter, on half frames.
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    /// Current output, 0-15. Periods below 2 are far above hearing and only produce
+    /// aliasing, so the channel holds the middle of its range for them.
+    pub fn output(&self) -> u8 {
+        if self.timer_period < 2 {
+            7
+        } else {
+            SEQUENCE[self.step as usize]
+        }
+    }
+}
+
+impl Savestate for Triangle {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+        w.u8(self.step);
+        self.length.save_state(w);
+        w.u8(self.linear_counter);
+        w.u8(self.linear_reload_value);
+        w.bool(self.linear_reload);
+        w.bool(self.control);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.timer_period = r.u16()?;
+        self.timer = r.u16()?;
+        self.step = r.u8()?;
+        self.length.load_state(r)?;
+        self.linear_counter = r.u8()?;
+        self.linear_reload_value = r.u8()?;
+        self.linear_reload = r.bool()?;
+        self.control = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod triangle_tests {
+    use super::*;
+
+    #[test]
+    pub fn linear_counter_stops_the_sequencer() {
+        let mut triangle = Triangle::default();
+        triangle.length.set_enabled(true);
+        triangle.write(0, 0x02); // linear counter 2, control clear
+        triangle.write(2, 0x10);
+        triangle.write(3, 0x08);
+        for _ in 0..0x11 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.step, 0, "silent before the reload, FAILED!");
+
+        triangle.clock_quarter_frame();
+        for _ in 0..0x11 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.step, 1, "steps once reloaded, FAILED!");
+
+        triangle.clock_quarter_frame();
+        triangle.clock_quarter_frame();
+        assert_eq!(triangle.linear_counter, 0, "counted down, FAILED!");
+        for _ in 0..0x11 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.step, 1, "holds its level, FAILED!");
+    }
+}
@@ -0,0 +1,209 @@
+//! # VGM logger
+//! Logs APU register writes to a VGM file, the format chiptune players use to replay the
+//! register writes of a sound chip. Each write is stored with the time it happened at,
+//! counted in CPU cycles and converted to the 44.1 kHz sample ticks VGM uses for waits.
+//!
+//! VGM players emulate the DMC with their own memory, so the sample bytes the DMC fetches
+//! are logged too, as one-byte NES APU RAM data blocks at the time of the fetch. A byte is
+//! only logged again if a bank switch changed it.
+//!
+//! A loop point can be marked while logging; players repeat from there to the end.
+//!
+//! The log starts with a snapshot of the last value written to every register, so players
+//! begin in the state the APU was in. The same snapshot is logged again whenever the APU
+//! state is replaced by a save state load or a rewind.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// VGM version 1.61, the first with the NES APU.
+const VERSION: u32 = 0x0161;
+/// Header length for version 1.61.
+const HEADER_SIZE: usize = 0xC0;
+/// VGM timestamps are in samples at 44.1 kHz, whatever the playback rate.
+const VGM_RATE: u64 = 44100;
+
+const CMD_WAIT: u8 = 0x61;
+const CMD_WAIT_NTSC_FRAME: u8 = 0x62;
+const CMD_WAIT_PAL_FRAME: u8 = 0x63;
+const CMD_END: u8 = 0x66;
+const CMD_DATA_BLOCK: u8 = 0x67;
+const CMD_NES_APU: u8 = 0xB4;
+/// Data block type for NES APU RAM writes.
+const BLOCK_NES_RAM: u8 = 0xC2;
+
+pub struct VgmLogger {
+    path: PathBuf,
+    cpu_clock: u32,
+    /// Commands written so far, without the header.
+    data: Vec<u8>,
+    /// CPU cycles since logging started.
+    cycles: u64,
+    /// Samples covered by the waits written so far.
+    samples: u64,
+    /// Offset into `data` and sample count at the loop point.
+    loop_point: Option<(usize, u64)>,
+    /// DMC sample bytes already in the log, for $8000-$FFFF.
+    dmc_memory: Box<[Option<u8>]>,
+}
+
+impl VgmLogger {
+    pub fn new(path: impl Into<PathBuf>, cpu_clock: u32) -> Self {
+        Self {
+            path: path.into(),
+            cpu_clock,
+            data: Vec::new(),
+            cycles: 0,
+            samples: 0,
+            loop_point: None,
+            dmc_memory: vec![None; 0x8000].into_boxed_slice(),
+        }
+    }
+
+    /// Counts one CPU cycle.
+    pub fn tick(&mut self) {
+        self.cycles += 1;
+    }
+
+    /// Logs a write to one of the registers $4000-$401F.
+    pub fn write_register(&mut self, address: u16, data: u8) {
+        self.catch_up();
+        self.data
+            .extend_from_slice(&[CMD_NES_APU, (address & 0x1F) as u8, data]);
+    }
+
+    /// Logs the values in `registers`, which holds $4000-$4017, as writes. $4015 goes first
+    /// so the channels are enabled before their length counters are loaded.
+    pub fn write_snapshot(&mut self, registers: &[u8; 0x18]) {
+        self.write_register(0x4015, registers[0x15]);
+        for address in 0x4000..=0x4013 {
+            self.write_register(address, registers[(address - 0x4000) as usize]);
+        }
+        self.write_register(0x4017, registers[0x17]);
+    }
+
+    /// Logs a byte the DMC fetched, unless the log already has it at that address.
+    pub fn dmc_fetch(&mut self, address: u16, data: u8) {
+        let slot = &mut self.dmc_memory[(address & 0x7FFF) as usize];
+        if *slot == Some(data) {
+            return;
+        }
+        *slot = Some(data);
+        self.catch_up();
+        // 0x66 guards older players; the size covers the 2-byte start address and the data
+        self.data
+            .extend_from_slice(&[CMD_DATA_BLOCK, 0x66, BLOCK_NES_RAM]);
+        self.data.extend_from_slice(&3u32.to_le_bytes());
+        self.data.extend_from_slice(&address.to_le_bytes());
+        self.data.push(data);
+    }
+
+    /// Marks the current time as the loop point, replacing an earlier one.
+    pub fn mark_loop(&mut self) {
+        self.catch_up();
+        self.loop_point = Some((self.data.len(), self.samples));
+    }
+
+    /// Writes a wait up to the current cycle.
+    fn catch_up(&mut self) {
+        let now = self.cycles * VGM_RATE / self.cpu_clock as u64;
+        let mut wait = now - self.samples;
+        self.samples = now;
+        while wait > 0 {
+            let step = wait.min(0xFFFF);
+            match step {
+                735 => self.data.push(CMD_WAIT_NTSC_FRAME),
+                882 => self.data.push(CMD_WAIT_PAL_FRAME),
+                1..=16 => self.data.push(0x70 + (step - 1) as u8),
+                _ => {
+                    self.data.push(CMD_WAIT);
+                    self.data.extend_from_slice(&(step as u16).to_le_bytes());
+                }
+            }
+            wait -= step;
+        }
+    }
+
+    /// Ends the log at the current cycle and writes the file.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.catch_up();
+        self.data.push(CMD_END);
+        let mut file = self.header();
+        file.extend_from_slice(&self.data);
+        fs::write(&self.path, file)
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let mut header = vec![0u8; HEADER_SIZE];
+        let mut put = |offset: usize, value: u32| {
+            header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        let total = HEADER_SIZE + self.data.len();
+        put(0x04, (total - 0x04) as u32);
+        put(0x08, VERSION);
+        put(0x18, self.samples as u32);
+        if let Some((offset, samples)) = self.loop_point {
+            // Offsets are relative to the field holding them
+            put(0x1C, (HEADER_SIZE + offset - 0x1C) as u32);
+            put(0x20, (self.samples - samples) as u32);
+        }
+        put(0x24, 60);
+        put(0x34, (HEADER_SIZE - 0x34) as u32);
+        put(0x84, self.cpu_clock);
+        header[0..4].copy_from_slice(b"Vgm ");
+        header
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod vgm_tests {
+    use super::*;
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    pub fn writes_are_timestamped_and_loop_points_stored() {
+        let path = std::env::temp_dir().join("vgm_tests_log.vgm");
+        let mut vgm = VgmLogger::new(&path, 1_789_773);
+        vgm.write_register(0x4015, 0x01);
+        for _ in 0..29781 {
+            vgm.tick();
+        }
+        vgm.mark_loop();
+        vgm.write_register(0x4000, 0xBF);
+        vgm.dmc_fetch(0xC000, 0x55);
+        vgm.dmc_fetch(0xC000, 0x55);
+        for _ in 0..406 {
+            vgm.tick();
+        }
+        vgm.finish().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&data[0..4], b"Vgm ", "magic, FAILED!");
+        assert_eq!(
+            read_u32(&data, 0x04) as usize,
+            data.len() - 4,
+            "EOF offset, FAILED!"
+        );
+        // One frame is 733.8 samples, 10 more samples follow the loop point
+        assert_eq!(read_u32(&data, 0x18), 743, "total samples, FAILED!");
+        assert_eq!(read_u32(&data, 0x20), 10, "loop samples, FAILED!");
+        assert_eq!(read_u32(&data, 0x84), 1_789_773, "APU clock, FAILED!");
+
+        let commands = &data[HEADER_SIZE..];
+        let mut expected = vec![0xB4, 0x15, 0x01, 0x61, 0xDD, 0x02, 0xB4, 0x00, 0xBF];
+        expected.extend_from_slice(&[0x67, 0x66, 0xC2, 3, 0, 0, 0, 0x00, 0xC0, 0x55]);
+        expected.extend_from_slice(&[0x79, 0x66]);
+        assert_eq!(commands, expected.as_slice(), "commands, FAILED!");
+        assert_eq!(
+            read_u32(&data, 0x1C) as usize + 0x1C,
+            HEADER_SIZE + 6,
+            "loop offset, FAILED!"
+        );
+    }
+}
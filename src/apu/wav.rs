@@ -0,0 +1,109 @@
+//! # WAV writer
+//! Writes 16-bit PCM WAV files, mono or with interleaved channels. The sizes in the
+//! header are only known at the end, so they are written as zero and patched by
+//! [`WavWriter::finish`].
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub struct WavWriter {
+    out: BufWriter<File>,
+    /// Samples written so far, counting every channel.
+    samples: u32,
+}
+
+impl WavWriter {
+    /// Creates the file. With more than one channel, samples are written interleaved.
+    pub fn create(path: &Path, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let block_align = channels * 2;
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?; // PCM
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(Self { out, samples: 0 })
+    }
+
+    /// Appends a sample, clipping it to -1.0..=1.0.
+    pub fn write_sample(&mut self, sample: f32) -> io::Result<()> {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        self.out.write_all(&value.to_le_bytes())?;
+        self.samples += 1;
+        Ok(())
+    }
+
+    /// Fills in the header sizes and flushes the file. Dropping the writer does the same
+    /// but ignores errors.
+    pub fn finish(&mut self) -> io::Result<()> {
+        let data_size = self.samples * 2;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(36 + data_size).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&data_size.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod wav_tests {
+    use super::*;
+
+    #[test]
+    pub fn header_sizes_are_patched() {
+        let path = std::env::temp_dir().join("wav_tests_header.wav");
+        let mut wav = WavWriter::create(&path, 44100, 1).unwrap();
+        for sample in [0.0, 0.5, -2.0] {
+            wav.write_sample(sample).unwrap();
+        }
+        wav.finish().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), 44 + 6, "file length, FAILED!");
+        assert_eq!(&data[4..8], &42u32.to_le_bytes(), "RIFF size, FAILED!");
+        assert_eq!(&data[40..44], &6u32.to_le_bytes(), "data size, FAILED!");
+        assert_eq!(
+            &data[48..50],
+            &(-i16::MAX).to_le_bytes(),
+            "clipped, FAILED!"
+        );
+    }
+
+    #[test]
+    pub fn stereo_header() {
+        let path = std::env::temp_dir().join("wav_tests_stereo.wav");
+        let mut wav = WavWriter::create(&path, 48000, 2).unwrap();
+        for sample in [0.25, -0.25] {
+            wav.write_sample(sample).unwrap();
+        }
+        wav.finish().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&data[22..24], &2u16.to_le_bytes(), "channels, FAILED!");
+        assert_eq!(
+            &data[28..32],
+            &(48000u32 * 4).to_le_bytes(),
+            "byte rate, FAILED!"
+        );
+        assert_eq!(&data[32..34], &4u16.to_le_bytes(), "block align, FAILED!");
+        assert_eq!(&data[40..44], &4u32.to_le_bytes(), "data size, FAILED!");
+    }
+}
@@ -1,4 +1,5 @@
-use clap::{ArgAction, Parser};
+use clap::{ArgAction, Parser, ValueEnum};
+use emulator::apu::{Channel, FilterPreset};
 
 
 #[derive(Parser, Debug)]
@@ -15,5 +16,87 @@ pub struct Args {
     ///Toggle between debug and regular mode
     #[arg(short, long, action=ArgAction::SetFalse)]
     pub debug: bool,
+
+    /// Record an input movie from power-on to this file (.fm2 for FCEUX format)
+    #[arg(long)]
+    pub record: Option<String>,
+
+    /// Play back an input movie (.fm2 for FCEUX format)
+    #[arg(long, conflicts_with = "record")]
+    pub play: Option<String>,
+
+    /// Filters modelling the console's analog audio output
+    #[arg(long, value_enum, default_value_t = FilterPreset::Nes)]
+    pub audio_filter: FilterPreset,
+
+    /// Where audio goes
+    #[arg(long, value_enum, default_value_t = AudioOutput::Device)]
+    pub audio: AudioOutput,
+
+    /// Record the audio to this WAV file from power-on (F9 toggles recording to it)
+    #[arg(long)]
+    pub record_audio: Option<String>,
+
+    /// Also record each APU channel to its own WAV file next to the recording
+    #[arg(long)]
+    pub stems: bool,
+
+    /// Log APU register writes to this VGM file from power-on (F10 marks the loop point)
+    #[arg(long)]
+    pub vgm: Option<String>,
+
+    /// WAV file to write audio to with `--audio file`
+    #[arg(long, default_value_t = String::from("audio.wav"))]
+    pub audio_file: String,
+
+    /// Audio sample rate in Hz
+    #[arg(long, default_value_t = 44100, value_parser = clap::value_parser!(u32).range(8000..=192000))]
+    pub sample_rate: u32,
+
+    /// Audio buffered ahead of the device in milliseconds; lower is snappier, higher
+    /// survives hiccups
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u32).range(5..=1000))]
+    pub audio_latency: u32,
+
+    /// Use the PAL noise periods; the rest of the console keeps NTSC timing
+    #[arg(long)]
+    pub pal_noise: bool,
+
+    /// Play and record audio in stereo, by default with pulse 1 to the left and pulse 2
+    /// to the right
+    #[arg(long)]
+    pub stereo: bool,
+
+    /// Place a channel in the stereo field, from -1 (left) to 1 (right), like
+    /// `--pan triangle=0.3`; replaces the default layout. Channels: pulse1, pulse2,
+    /// triangle, noise, dmc, expansion
+    #[arg(long, requires = "stereo", value_parser = parse_pan)]
+    pub pan: Vec<(Channel, f32)>,
+}
+
+/// Parses `CHANNEL=POSITION` for `--pan`.
+fn parse_pan(value: &str) -> Result<(Channel, f32), String> {
+    let (name, position) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected CHANNEL=POSITION, got `{}`", value))?;
+    let channel = Channel::from_name(name).ok_or_else(|| format!("no channel `{}`", name))?;
+    let position: f32 = position
+        .parse()
+        .map_err(|_| format!("invalid position `{}`", position))?;
+    if !(-1.0..=1.0).contains(&position) {
+        return Err(format!("position {} is not between -1 and 1", position));
+    }
+    Ok((channel, position))
+}
+
+/// Audio backends selectable with `--audio`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AudioOutput {
+    /// The default output device, or nothing if there is none
+    Device,
+    /// Discard audio
+    Null,
+    /// Write audio to the file given by `--audio-file`
+    File,
 }
 
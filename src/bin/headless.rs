@@ -0,0 +1,138 @@
+//! # Headless runner
+//! Runs a ROM for a fixed number of frames without a window, keyboard polling or audio
+//! output, then dumps the last frame and prints hashes of it and of CPU RAM. The audio
+//! can be written to WAV files with `--record-audio` and the APU register writes to a VGM
+//! file with `--vgm`.
+//! Meant for CI machines that have neither a display nor a sound card. Built with
+//! `cargo build --bin headless --no-default-features` it does not link the audio
+//! libraries either.
+//!
+//! The console runs in deterministic mode, so the per-frame hash log written with
+//! `--hash-log` is identical between runs and builds unless emulation changed;
+//! `--compare` checks a run against such a log and fails on the first differing frame.
+
+use clap::Parser;
+use emulator::movie::Movie;
+use emulator::Nes;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::process::ExitCode;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Run a ROM without a window or audio device", long_about = None)]
+struct Args {
+    /// Rom to run
+    #[arg(short, long)]
+    rom: String,
+
+    /// Number of frames to emulate (default: 60, or the length of the movie being played)
+    #[arg(short, long)]
+    frames: Option<u32>,
+
+    /// Play back an input movie (.fm2 for FCEUX format)
+    #[arg(short, long)]
+    play: Option<String>,
+
+    /// Write the final frame to this file as a PPM image
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Seed for the power-on contents of CPU RAM
+    #[arg(short, long, default_value_t = 0)]
+    seed: u64,
+
+    /// Write the hashes of every frame to this file
+    #[arg(long)]
+    hash_log: Option<String>,
+
+    /// Compare the hashes of every frame against a log written by --hash-log
+    #[arg(long)]
+    compare: Option<String>,
+
+    /// Record the audio to this WAV file
+    #[arg(long)]
+    record_audio: Option<String>,
+
+    /// Also record each APU channel to its own WAV file next to the recording
+    #[arg(long, requires = "record_audio")]
+    stems: bool,
+
+    /// Log APU register writes to this VGM file
+    #[arg(long)]
+    vgm: Option<String>,
+
+    /// Frame at which the VGM log loops
+    #[arg(long, requires = "vgm")]
+    vgm_loop: Option<u32>,
+}
+
+fn main() -> Result<ExitCode, Box<dyn Error>> {
+    let args = Args::parse();
+    let mut nes = Nes::new_deterministic(&args.rom, args.seed);
+
+    let mut frames = args.frames.unwrap_or(60);
+    if let Some(path) = &args.play {
+        let movie = Movie::load(path)?;
+        frames = args.frames.unwrap_or(movie.frames.len() as u32);
+        nes.play_movie(movie)?;
+    }
+    if let Some(path) = &args.record_audio {
+        nes.start_audio_recording(path, args.stems)?;
+    }
+    if let Some(path) = &args.vgm {
+        nes.start_vgm_log(path)?;
+    }
+
+    let mut log = match &args.hash_log {
+        Some(path) => Some(BufWriter::new(File::create(path)?)),
+        None => None,
+    };
+    let expected: Option<Vec<String>> = match &args.compare {
+        Some(path) => Some(fs::read_to_string(path)?.lines().map(str::to_string).collect()),
+        None => None,
+    };
+
+    for frame in 0..frames {
+        if args.vgm_loop == Some(frame) {
+            nes.mark_vgm_loop();
+        }
+        nes.step_frame();
+        let line = format!("{} {}", frame, nes.frame_hashes());
+        if let Some(log) = &mut log {
+            writeln!(log, "{}", line)?;
+        }
+        if let Some(expected) = &expected {
+            match expected.get(frame as usize) {
+                Some(want) if *want == line => {}
+                Some(want) => {
+                    eprintln!("mismatch at frame {}", frame);
+                    eprintln!("  expected: {}", want);
+                    eprintln!("  got:      {}", line);
+                    return Ok(ExitCode::FAILURE);
+                }
+                None => {
+                    eprintln!("compare log ends before frame {}", frame);
+                    return Ok(ExitCode::FAILURE);
+                }
+            }
+        }
+    }
+    if let Some(log) = &mut log {
+        log.flush()?;
+    }
+
+    if let Some(path) = &args.output {
+        nes.frame().write_ppm(path)?;
+    }
+    nes.stop_audio_recording()?;
+    nes.stop_vgm_log()?;
+
+    println!("frames: {}", frames);
+    println!("frame hash: {:016x}", nes.frame_hash());
+    println!("ram hash: {:016x}", nes.ram_hash());
+    if expected.is_some() {
+        println!("all {} frames match", frames);
+    }
+    Ok(ExitCode::SUCCESS)
+}
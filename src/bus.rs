@@ -1,6 +1,28 @@
-use std::{cell::RefCell, rc::Rc};
+pub mod dma;
 
-use crate::{apu::Apu, cartridge::Cartridge, controller::Controller, ppu::Ppu};
+use std::{cell::RefCell, io, rc::Rc};
+
+use crate::{apu::Apu, cartridge::Cartridge, controller::Controller, cpu::CpuBus, ppu::Ppu};
+use crate::hash;
+use crate::nes::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::ppu::frame::Frame;
+use crate::states::{Savestate, StateReader, StateWriter};
+use bitflags::bitflags;
+use dma::{Dma, DmaCycle};
+
+bitflags! {
+    /// Devices that can pull the shared /IRQ line low. The line is a wired-OR: it stays
+    /// asserted as long as any one of them holds it, and each is acknowledged separately.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct IrqSource: u8 {
+        /// The cartridge's mapper, e.g. the MMC3 scanline counter.
+        const MAPPER = 0b0000_0001;
+        /// The APU frame counter.
+        const FRAME_COUNTER = 0b0000_0010;
+        /// The APU DMC channel, at the end of a sample.
+        const DMC = 0b0000_0100;
+    }
+}
 
 /// The `Bus` struct acts as the central communication layer connecting the CPU
 /// to the various subsystems in the NES emulator, including RAM, the cartridge,
@@ -24,6 +46,19 @@ pub struct Bus {
 
     /// The APU (Audio Processing Unit), handles sound and related I/O registers.
     apu: Option<Rc<RefCell<Apu>>>,
+
+    /// The picture the PPU draws into as the bus clocks it.
+    frame: Frame,
+
+    /// OAM and DMC DMA, which halt the CPU and use the bus themselves.
+    dma: Dma,
+
+    /// CPU cycles since power on, including the ones the CPU spent halted by DMA.
+    cycles: u64,
+
+    /// Last value driven on the CPU data bus. Unmapped addresses and the bits a register
+    /// does not drive read back as this value.
+    open_bus: u8,
 }
 
 impl Bus {
@@ -37,9 +72,80 @@ impl Bus {
             ppu: ppu,
             apu: None,
             controller1state: false,
+            frame: Frame::new(SCREEN_WIDTH as u16, SCREEN_HEIGHT as u16),
+            dma: Dma::new(),
+            cycles: 0,
+            open_bus: 0,
+        }
+    }
+
+    /// CPU cycles since power on, including the ones the CPU spent halted by DMA.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// The picture drawn by the PPU so far.
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    pub fn frame_mut(&mut self) -> &mut Frame {
+        &mut self.frame
+    }
+
+    /// Runs the PPU for the three dots that make up one CPU cycle, and the APU for the cycle.
+    /// DMC sample fetches the APU asks for are queued on the DMA unit, and fetched bytes are
+    /// handed back to it on the next cycle. The cartridge's expansion audio is mixed in too.
+    fn tick(&mut self) {
+        self.cycles = self.cycles.wrapping_add(1);
+        let mut ppu = self.ppu.borrow_mut();
+        for _ in 0..3 {
+            ppu.clock(&mut self.frame);
+        }
+        if let Some(apu) = &self.apu {
+            let mut apu = apu.borrow_mut();
+            if let Some(sample) = self.dma.take_dmc_sample() {
+                apu.dmc_sample_fetched(sample);
+            }
+            if let Some(cart) = &self.cartridge {
+                apu.set_expansion_audio(cart.borrow_mut().expansion_audio());
+            }
+            apu.clock();
+            if let Some(address) = apu.dmc_fetch_request() {
+                self.dma.request_dmc(address);
+            }
         }
     }
 
+    /// The 2KB of internal CPU RAM ($0000-$07FF).
+    pub fn ram(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Fills CPU RAM with pseudo random bytes derived from `seed`, imitating the undefined
+    /// power-on contents of real RAM while staying reproducible.
+    pub fn fill_ram(&mut self, seed: u64) {
+        let mut state = seed;
+        for chunk in self.memory.chunks_mut(8) {
+            let value = hash::splitmix64(&mut state).to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
+        }
+    }
+
+    /// Sources currently asserting /IRQ.
+    pub fn irq_sources(&self) -> IrqSource {
+        let mut sources = IrqSource::empty();
+        if let Some(cart) = &self.cartridge {
+            sources.set(IrqSource::MAPPER, cart.borrow_mut().irq());
+        }
+        if let Some(apu) = &self.apu {
+            let apu = apu.borrow();
+            sources.set(IrqSource::FRAME_COUNTER, apu.frame_irq());
+            sources.set(IrqSource::DMC, apu.dmc_irq());
+        }
+        sources
+    }
+
     /// Links a cartridge to the bus, allowing CPU access to PRG-ROM and other mapper-controlled behavior.
     pub fn link_cartridge(&mut self, cart: Rc<RefCell<Cartridge>>){
         self.cartridge = Some(cart);
@@ -74,8 +180,11 @@ impl Bus {
     ///
     /// # Returns
     /// * `u8` - The byte read from the given address.
+    ///
+    /// Addresses nothing answers to ($4000-$4014, $4018-$401F and unmapped cartridge space)
+    /// return the open bus value, as do the undriven bits of $4015, $4016 and $4017.
     pub fn cpu_read(&mut self, address: u16, rdonly: bool) -> u8 {
-        let mut data = 0;
+        let mut data = self.open_bus;
 
         if address <= 0x1FFF {
             data = self.memory[(address & 0x7FF) as usize];
@@ -83,16 +192,16 @@ impl Bus {
             data = self.ppu.borrow_mut().cpu_read(address, rdonly);
         } else if address <= 0x4017 {
             match address {
-                0x4000..=0x4013 | 0x4015 => {
+                0x4015 => {
                     if let Some(apu) = &self.apu {
-                        data = apu.borrow_mut().cpu_read(address);
+                        data = (apu.borrow_mut().cpu_read(address) & !0x20) | (data & 0x20);
                     } else {
                         panic!("APU Error");
                     }
                 },
                 0x4016 => {
                     if let Some(controller) = &self.controller1 {
-                        data = controller.borrow_mut().cpu_read();
+                        data = (data & 0xE0) | controller.borrow_mut().cpu_read();
                         // self.get_controller1_state();
                         if controller.borrow_mut().readfullregister(){
                             self.controller1state = true;
@@ -102,17 +211,14 @@ impl Bus {
                     }
                 },
                 0x4017 => {
+                    data &= 0xE0;
                     if let Some(controller) = &self.controller2 {
-                        data = controller.borrow_mut().cpu_read();
-                    } else {
-                        data = 0;
+                        data |= controller.borrow_mut().cpu_read();
                     }
                 },
                 _ => {}
             }
-        } else if address <= 0x401F {
-            data = 0;
-        } else {
+        } else if address >= 0x4020 {
             if let Some(cart) = &self.cartridge {
                 cart.borrow_mut().cpu_read(address, &mut data);
             } else {
@@ -120,6 +226,46 @@ impl Bus {
             }
         }
 
+        self.open_bus = data;
+        data
+    }
+
+    /// Reads a byte from the CPU address space without disturbing any component,
+    /// e.g. without clearing the PPU vblank flag or advancing the controller shift register.
+    pub fn cpu_peek(&self, address: u16) -> u8 {
+        let mut data = self.open_bus;
+
+        if address <= 0x1FFF {
+            data = self.memory[(address & 0x7FF) as usize];
+        } else if address <= 0x3FFF {
+            data = self.ppu.borrow().cpu_peek(address);
+        } else if address <= 0x4017 {
+            match address {
+                0x4015 => {
+                    if let Some(apu) = &self.apu {
+                        data = (apu.borrow().cpu_peek(address) & !0x20) | (data & 0x20);
+                    }
+                }
+                0x4016 => {
+                    data &= 0xE0;
+                    if let Some(controller) = &self.controller1 {
+                        data |= controller.borrow().peek();
+                    }
+                }
+                0x4017 => {
+                    data &= 0xE0;
+                    if let Some(controller) = &self.controller2 {
+                        data |= controller.borrow().peek();
+                    }
+                }
+                _ => {}
+            }
+        } else if address >= 0x4020 {
+            if let Some(cart) = &self.cartridge {
+                cart.borrow().cpu_read(address, &mut data);
+            }
+        }
+
         data
     }
 
@@ -129,6 +275,7 @@ impl Bus {
     /// * `address` - The 16-bit memory address to write to.
     /// * `byte` - The value to write.
     pub fn cpu_write(&mut self, address: u16, byte: u8) {
+        self.open_bus = byte;
         if address <= 0x1FFF {
             self.memory[(address & 0x7FF) as usize] = byte;
         } else if address <= 0x3FFF {
@@ -143,12 +290,8 @@ impl Bus {
                     }
                 },
                 0x4014 => {
-                    // Perform OAM DMA transfer from page in memory to PPU OAM
-                    let base = (byte as usize) << 8;
-                    for i in 0..=0xFF {
-                        let data = self.memory[(base + i) % 2048];
-                        self.ppu.borrow_mut().oam_dma_write(i as u8, data);
-                    }
+                    // OAM DMA from page $XX00, run when the CPU next reads
+                    self.dma.request_oam(byte);
                 },
                 0x4016 => {
                     if let Some(controller) = &self.controller1 {
@@ -172,4 +315,79 @@ impl Bus {
             }
         }
     }
+
+    /// Runs a pending DMA transfer while the CPU is halted on a read of `address`.
+    /// Even cycles are get cycles; the halt and alignment cycles repeat the CPU's read.
+    fn run_dma(&mut self, address: u16) {
+        while self.dma.active() {
+            self.tick();
+            match self.dma.next_cycle(self.cycles.is_multiple_of(2)) {
+                DmaCycle::Dummy => {
+                    self.cpu_read(address, false);
+                }
+                DmaCycle::OamRead(source) => {
+                    let data = self.cpu_read(source, false);
+                    self.dma.oam_read_done(data);
+                }
+                DmaCycle::OamWrite(index, data) => {
+                    self.ppu.borrow_mut().oam_dma_write(index, data);
+                }
+                DmaCycle::DmcRead(source) => {
+                    let data = self.cpu_read(source, false);
+                    self.dma.dmc_read_done(data);
+                }
+            }
+        }
+    }
+}
+
+/// Every CPU access is one CPU cycle, so the PPU is advanced by a cycle before each access
+/// and sees it at the same point of the frame as on the console. DMA only halts the CPU
+/// on reads, so it runs before a read once it is requested.
+impl CpuBus for Bus {
+    fn read(&mut self, address: u16) -> u8 {
+        if self.dma.active() {
+            self.run_dma(address);
+        }
+        self.tick();
+        self.cpu_read(address, false)
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.tick();
+        self.cpu_write(address, data);
+    }
+
+    fn peek(&mut self, address: u16) -> u8 {
+        self.cpu_peek(address)
+    }
+
+    fn nmi_line(&mut self) -> bool {
+        self.ppu.borrow().nmi_line()
+    }
+
+    fn irq_line(&mut self) -> bool {
+        !self.irq_sources().is_empty()
+    }
+}
+
+impl Savestate for Bus {
+    /// Saves CPU RAM, the cycle counter, the open bus value and DMA progress. The linked
+    /// components are saved by their owner.
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.memory);
+        w.bool(self.controller1state);
+        w.u64(self.cycles);
+        w.u8(self.open_bus);
+        self.dma.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.bytes_into(&mut self.memory)?;
+        self.controller1state = r.bool()?;
+        self.cycles = r.u64()?;
+        self.open_bus = r.u8()?;
+        self.dma.load_state(r)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,244 @@
+//! # DMA
+//! The DMA unit of the 2A03. Once a transfer is requested it halts the CPU on the CPU's
+//! next read cycle and takes over the bus until it is done. It alternates between get
+//! cycles, on which it can read, and put cycles, on which it can write, so an OAM DMA
+//! takes one halt cycle, an alignment cycle if it would start on a put cycle, and 256
+//! get/put pairs: 513 or 514 cycles in total.
+//!
+//! Sample fetches of the DMC channel go through the same unit and take priority over
+//! OAM DMA on get cycles, so a fetch in the middle of an OAM transfer delays it. A fetch
+//! on its own takes the halt cycle, a dummy cycle, an alignment cycle if needed and the
+//! get: 3 or 4 cycles.
+
+use std::io;
+
+use crate::states::{Savestate, StateReader, StateWriter};
+
+/// What the bus does on one cycle while the CPU is halted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaCycle {
+    /// Halt or alignment cycle: the CPU's own read is repeated and its result dropped.
+    Dummy,
+    /// Read for an OAM transfer; hand the byte to [`Dma::oam_read_done`].
+    OamRead(u16),
+    /// Write this byte to the next OAM entry.
+    OamWrite(u8, u8),
+    /// Sample fetch for the DMC; hand the byte to [`Dma::dmc_read_done`].
+    DmcRead(u16),
+}
+
+pub struct Dma {
+    /// Source page of a running or requested OAM DMA.
+    oam_page: Option<u8>,
+    /// Next OAM entry to fill.
+    oam_index: u8,
+    /// Byte read on the last get cycle, written on the next put cycle.
+    oam_latch: Option<u8>,
+    /// Address of a requested DMC sample fetch.
+    dmc_address: Option<u16>,
+    /// Byte fetched for the DMC, waiting to be collected.
+    dmc_sample: Option<u8>,
+    /// Whether the CPU has been halted for the current transfer.
+    halted: bool,
+    /// Whether the dummy cycle a DMC fetch takes after the halt is still to come.
+    dmc_dummy: bool,
+}
+
+impl Dma {
+    pub fn new() -> Self {
+        Self {
+            oam_page: None,
+            oam_index: 0,
+            oam_latch: None,
+            dmc_address: None,
+            dmc_sample: None,
+            halted: false,
+            dmc_dummy: false,
+        }
+    }
+
+    /// Starts copying page `page` ($XX00-$XXFF) to OAM, as a write to $4014 does.
+    pub fn request_oam(&mut self, page: u8) {
+        self.oam_page = Some(page);
+        self.oam_index = 0;
+        self.oam_latch = None;
+    }
+
+    /// Requests a DMC sample fetch from `address`.
+    pub fn request_dmc(&mut self, address: u16) {
+        self.dmc_address = Some(address);
+    }
+
+    /// Takes the byte fetched by the last DMC request, once it arrived.
+    pub fn take_dmc_sample(&mut self) -> Option<u8> {
+        self.dmc_sample.take()
+    }
+
+    /// Whether a transfer is waiting to halt the CPU or still running.
+    pub fn active(&self) -> bool {
+        self.oam_page.is_some() || self.dmc_address.is_some()
+    }
+
+    /// Decides what happens on the next bus cycle. `get` tells whether it is a get cycle.
+    pub fn next_cycle(&mut self, get: bool) -> DmaCycle {
+        if !self.halted {
+            self.halted = true;
+            // Only a fetch that halted the CPU itself has the extra cycle
+            self.dmc_dummy = self.oam_page.is_none();
+            return DmaCycle::Dummy;
+        }
+        if self.dmc_dummy {
+            self.dmc_dummy = false;
+            return DmaCycle::Dummy;
+        }
+        let cycle = if get {
+            if let Some(address) = self.dmc_address.take() {
+                DmaCycle::DmcRead(address)
+            } else {
+                match (self.oam_page, self.oam_latch) {
+                    (Some(page), None) => {
+                        DmaCycle::OamRead(((page as u16) << 8) | self.oam_index as u16)
+                    }
+                    _ => DmaCycle::Dummy,
+                }
+            }
+        } else {
+            match self.oam_latch.take() {
+                Some(data) => {
+                    let index = self.oam_index;
+                    self.oam_index = self.oam_index.wrapping_add(1);
+                    if self.oam_index == 0 {
+                        self.oam_page = None;
+                    }
+                    DmaCycle::OamWrite(index, data)
+                }
+                None => DmaCycle::Dummy,
+            }
+        };
+        if !self.active() {
+            self.halted = false;
+        }
+        cycle
+    }
+
+    pub fn oam_read_done(&mut self, data: u8) {
+        self.oam_latch = Some(data);
+    }
+
+    pub fn dmc_read_done(&mut self, data: u8) {
+        self.dmc_sample = Some(data);
+    }
+}
+
+impl Default for Dma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Dma {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.oam_page.is_some());
+        w.u8(self.oam_page.unwrap_or(0));
+        w.u8(self.oam_index);
+        w.bool(self.oam_latch.is_some());
+        w.u8(self.oam_latch.unwrap_or(0));
+        w.bool(self.dmc_address.is_some());
+        w.u16(self.dmc_address.unwrap_or(0));
+        w.bool(self.dmc_sample.is_some());
+        w.u8(self.dmc_sample.unwrap_or(0));
+        w.bool(self.halted);
+        w.bool(self.dmc_dummy);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        let has_page = r.bool()?;
+        let page = r.u8()?;
+        self.oam_page = has_page.then_some(page);
+        self.oam_index = r.u8()?;
+        let has_latch = r.bool()?;
+        let latch = r.u8()?;
+        self.oam_latch = has_latch.then_some(latch);
+        let has_address = r.bool()?;
+        let address = r.u16()?;
+        self.dmc_address = has_address.then_some(address);
+        let has_sample = r.bool()?;
+        let sample = r.u8()?;
+        self.dmc_sample = has_sample.then_some(sample);
+        self.halted = r.bool()?;
+        self.dmc_dummy = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod dma_tests {
+    use super::*;
+
+    /// Runs a transfer to the end starting on a get or put cycle and counts its cycles
+    fn run(dma: &mut Dma, mut get: bool) -> usize {
+        let mut cycles = 0;
+        while dma.active() {
+            if let DmaCycle::OamRead(address) = dma.next_cycle(get) {
+                dma.oam_read_done(address as u8);
+            }
+            cycles += 1;
+            get = !get;
+        }
+        cycles
+    }
+
+    #[test]
+    pub fn oam_dma_takes_513_or_514_cycles() {
+        let mut dma = Dma::new();
+        dma.request_oam(0x02);
+        // The halt cycle is a put cycle, so the first get comes right after it
+        assert_eq!(run(&mut dma, false), 513, "aligned transfer, FAILED!");
+        dma.request_oam(0x02);
+        assert_eq!(run(&mut dma, true), 514, "unaligned transfer, FAILED!");
+    }
+
+    #[test]
+    pub fn dmc_fetch_takes_3_or_4_cycles() {
+        for (halt_on_get, expected) in [(true, 3), (false, 4)] {
+            let mut dma = Dma::new();
+            dma.request_dmc(0xC000);
+            let mut get = halt_on_get;
+            let mut cycles = 0;
+            while dma.active() {
+                if let DmaCycle::DmcRead(_) = dma.next_cycle(get) {
+                    dma.dmc_read_done(0);
+                }
+                cycles += 1;
+                get = !get;
+            }
+            assert_eq!(cycles, expected, "halt on get {}, FAILED!", halt_on_get);
+        }
+    }
+
+    #[test]
+    pub fn dmc_fetch_delays_oam_dma() {
+        let mut dma = Dma::new();
+        dma.request_oam(0x02);
+        assert_eq!(dma.next_cycle(false), DmaCycle::Dummy, "halt, FAILED!");
+        dma.request_dmc(0xC000);
+        assert_eq!(
+            dma.next_cycle(true),
+            DmaCycle::DmcRead(0xC000),
+            "dmc first, FAILED!"
+        );
+        dma.dmc_read_done(0x55);
+        assert_eq!(dma.take_dmc_sample(), Some(0x55), "sample, FAILED!");
+        assert_eq!(
+            dma.next_cycle(false),
+            DmaCycle::Dummy,
+            "nothing to put, FAILED!"
+        );
+        assert_eq!(
+            dma.next_cycle(true),
+            DmaCycle::OamRead(0x0200),
+            "oam resumes, FAILED!"
+        );
+    }
+}
@@ -22,6 +22,13 @@ use mapper004::Mapper004;
 use mapper066::Mapper066;
 
 use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use crate::hash;
+use crate::states::{invalid, Savestate, StateReader, StateWriter};
 
 /// Represents the 16-byte iNES header from a NES ROM file.
 #[derive(Debug)]
@@ -41,6 +48,29 @@ pub enum MirrorMode {
     Vertical,
 }
 
+impl MirrorMode {
+    /// Encodes the mode as a byte for save states.
+    pub(crate) fn to_u8(&self) -> u8 {
+        match self {
+            MirrorMode::Horizontal => 0,
+            MirrorMode::OneScreenHi => 1,
+            MirrorMode::OneScreenLo => 2,
+            MirrorMode::Vertical => 3,
+        }
+    }
+
+    /// Decodes a byte written by `to_u8`.
+    pub(crate) fn from_u8(value: u8) -> io::Result<Self> {
+        match value {
+            0 => Ok(MirrorMode::Horizontal),
+            1 => Ok(MirrorMode::OneScreenHi),
+            2 => Ok(MirrorMode::OneScreenLo),
+            3 => Ok(MirrorMode::Vertical),
+            _ => Err(invalid("unknown mirror mode")),
+        }
+    }
+}
+
 /// Represents an NES cartridge, encapsulating PRG/CHR ROM and a memory mapper.
 /// Handles read/write operations from the CPU and PPU, mirroring, and mapper-specific IRQ behavior.
 pub struct Cartridge {
@@ -48,10 +78,17 @@ pub struct Cartridge {
     prg_rom: Vec<u8>,
     chr_rom: Vec<u8>,
     mapper: Box<dyn Mapper>,
+    rom_hash: u64,
+    /// Where battery backed PRG-RAM is persisted; `None` for carts without a battery
+    /// or when persistence is turned off.
+    sav_path: Option<PathBuf>,
+    /// PRG-RAM changed since it was last written to `sav_path`.
+    sram_dirty: bool,
 }
 
 impl Cartridge {
-    /// Clears the mapper's IRQ flag. This is typically called by the CPU after an IRQ has been serviced.
+    /// Clears the mapper's IRQ flag. Taking the IRQ does not do this: mappers hold /IRQ
+    /// until the game acknowledges it through one of their registers.
     pub fn irq_clear(&mut self) {
         self.mapper.irq_clear();
     }
@@ -63,10 +100,22 @@ impl Cartridge {
 
     /// Constructs a new `Cartridge` from the provided file path.
     /// Loads PRG and CHR ROM data, parses the iNES header, and initializes the appropriate memory mapper.
+    /// If the header has the battery flag set, PRG-RAM is restored from `<rom>.sav` and written back
+    /// by [`Cartridge::flush_sram`] and when the cartridge is dropped.
     ///
     /// # Arguments
     /// * `file_name` - The path to the `.nes` ROM file.
     pub fn new(file_name: &str) -> Self {
+        Self::load(file_name, true)
+    }
+
+    /// Like [`Cartridge::new`], but never reads or writes a `.sav` file, so PRG-RAM always
+    /// starts out blank. Used for headless and deterministic runs.
+    pub fn new_volatile(file_name: &str) -> Self {
+        Self::load(file_name, false)
+    }
+
+    fn load(file_name: &str, persist: bool) -> Self {
         let buf = fs::read(file_name).expect("unable to open file!");
         let header = &buf[0..16];
 
@@ -95,6 +144,7 @@ impl Cartridge {
 
         let mapper = (header[7] & 0xF0) | (header[6] >> 4);
         let four_screen = (header[6] & 0x08) != 0;
+        let battery = (header[6] & 0x02) != 0;
         let nametable_arrangement = match header[6] & 1 {
             0 => MirrorMode::Horizontal,
             1 => MirrorMode::Vertical,
@@ -118,7 +168,6 @@ impl Cartridge {
                 prg_rom_size as u8,
                 chr_rom_size as u8,
                 nametable_arrangement,
-                None,
             )),
             2 => Box::new(Mapper002::new(
                 prg_rom_size as u8,
@@ -142,14 +191,59 @@ impl Cartridge {
             _ => panic!("mapper {} not supported", mapper),
         };
 
-        println!("{:?}", header);
+        debug!("{:?}", header);
 
-        Self {
+        let mut cartridge = Self {
             _header: header,
             prg_rom,
             chr_rom,
             mapper,
+            rom_hash: hash::hash_bytes(&buf),
+            sav_path: None,
+            sram_dirty: false,
+        };
+        if persist && battery && cartridge.mapper.prg_ram().is_some() {
+            let path = Path::new(file_name).with_extension("sav");
+            cartridge.restore_sram(&path);
+            cartridge.sav_path = Some(path);
+        }
+        cartridge
+    }
+
+    /// Copies a `.sav` file into PRG-RAM. A missing file just means there is no save yet.
+    fn restore_sram(&mut self, path: &Path) {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return,
+            Err(err) => {
+                warn!("could not read {}: {}", path.display(), err);
+                return;
+            }
+        };
+        if let Some(ram) = self.mapper.prg_ram_mut() {
+            if data.len() != ram.len() {
+                warn!("{} is {} bytes, expected {}", path.display(), data.len(), ram.len());
+            }
+            let len = data.len().min(ram.len());
+            ram[..len].copy_from_slice(&data[..len]);
+        }
+    }
+
+    /// Returns true if the cartridge has battery backed PRG-RAM that is persisted to disk.
+    pub fn has_battery(&self) -> bool {
+        self.sav_path.is_some()
+    }
+
+    /// Writes battery backed PRG-RAM to the `.sav` file if it changed since the last flush.
+    pub fn flush_sram(&mut self) -> io::Result<()> {
+        if !self.sram_dirty {
+            return Ok(());
         }
+        if let (Some(path), Some(ram)) = (&self.sav_path, self.mapper.prg_ram()) {
+            fs::write(path, ram)?;
+        }
+        self.sram_dirty = false;
+        Ok(())
     }
 
     /// Resets the mapper to its initial state. Called when the console is reset.
@@ -167,6 +261,11 @@ impl Cartridge {
         self.mapper.hasirq()
     }
 
+    /// Clocks the board's sound chip, if it has one, and returns its output.
+    pub fn expansion_audio(&mut self) -> f32 {
+        self.mapper.expansion_audio()
+    }
+
     /// Reads a byte from CPU-visible memory mapped to the cartridge.
     ///
     /// # Arguments
@@ -187,6 +286,9 @@ impl Cartridge {
     /// * `address` - The 16-bit CPU address to write to.
     /// * `byte` - The byte value to be written.
     pub fn cpu_write(&mut self, address: u16, byte: u8) {
+        if (0x6000..=0x7FFF).contains(&address) && self.sav_path.is_some() {
+            self.sram_dirty = true;
+        }
         let mut mapped_address = address as u32;
         let res = self.mapper.cpu_write(address, &mut mapped_address, byte);
         if res && mapped_address != 0xFFFFFFFF {
@@ -221,8 +323,81 @@ impl Cartridge {
         }
     }
 
-    /// Saves the internal state of the mapper (useful for emulator save states).
-    pub fn savestate(&mut self) {
-        self.mapper.savestate();
+    /// Hash of the ROM file as loaded, used to check that a save state belongs to this game.
+    pub fn rom_hash(&self) -> u64 {
+        self.rom_hash
+    }
+}
+
+impl Savestate for Cartridge {
+    /// Saves the mapper, plus CHR-RAM on boards without CHR-ROM. ROM contents are not saved.
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self._header._mapper);
+        if self._header._chr_rom_size == 0 {
+            w.bytes(&self.chr_rom);
+        }
+        self.mapper.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        if r.u8()? != self._header._mapper {
+            return Err(invalid("save state was made with a different mapper"));
+        }
+        if self._header._chr_rom_size == 0 {
+            r.bytes_into(&mut self.chr_rom)?;
+        }
+        self.mapper.load_state(r)?;
+        // PRG-RAM may differ from the .sav file now
+        self.sram_dirty = self.sav_path.is_some();
+        Ok(())
+    }
+}
+
+impl Drop for Cartridge {
+    /// Makes sure battery saves are not lost when the game is closed or swapped.
+    fn drop(&mut self) {
+        if let Err(err) = self.flush_sram() {
+            warn!("could not write save file: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod cartridge_tests {
+    use super::Cartridge;
+
+    /// Writes an MMC1 image with the battery flag set and returns its path.
+    fn battery_rom(name: &str) -> std::path::PathBuf {
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 2, 1, 0x12, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.extend_from_slice(&[0; 2 * 0x4000 + 0x2000]);
+        let path = std::env::temp_dir().join(format!("{}-{}.nes", name, std::process::id()));
+        std::fs::write(&path, rom).unwrap();
+        path
+    }
+
+    #[test]
+    pub fn battery_ram_persists() {
+        let rom = battery_rom("battery_ram_persists");
+        let sav = rom.with_extension("sav");
+        let _ = std::fs::remove_file(&sav);
+
+        let mut cart = Cartridge::new(rom.to_str().unwrap());
+        assert!(cart.has_battery(), "battery flag, FAILED!");
+        cart.cpu_write(0x6000, 0x42);
+        drop(cart);
+        assert_eq!(std::fs::read(&sav).unwrap()[0], 0x42, "sav written on drop, FAILED!");
+
+        let cart = Cartridge::new(rom.to_str().unwrap());
+        let mut byte = 0;
+        cart.cpu_read(0x6000, &mut byte);
+        assert_eq!(byte, 0x42, "sav restored, FAILED!");
+        drop(cart);
+
+        let cart = Cartridge::new_volatile(rom.to_str().unwrap());
+        let mut byte = 0;
+        cart.cpu_read(0x6000, &mut byte);
+        assert_eq!(byte, 0, "volatile cart ignores sav, FAILED!");
+        let _ = std::fs::remove_file(&sav);
     }
 }
@@ -1,4 +1,7 @@
+use std::io;
+
 use super::MirrorMode;
+use crate::states::{StateReader, StateWriter};
 
 pub trait Mapper{
     fn cpu_read(&self, address: u16,mapped_addr: &mut u32, data: &mut u8) -> bool;
@@ -7,9 +10,26 @@ pub trait Mapper{
     fn ppu_write(&mut self, address: u16,mapped_addr: &mut u32, data: u8) -> bool;
     fn get_mirror_mode(&self) -> MirrorMode;
     fn irq_clear(&mut self);
-    fn savestate(&self);
     fn hasirq(&mut self) -> bool;
     fn scanline(&mut self);
     fn reset(&mut self);
+    /// Writes bank registers, IRQ state and on-board RAM for a save state.
+    fn save_state(&self, w: &mut StateWriter);
+    /// Restores what `save_state` wrote.
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()>;
+    /// On-board PRG-RAM at $6000-$7FFF, if the board has any. Battery backed carts
+    /// persist this to a `.sav` file.
+    fn prg_ram(&self) -> Option<&[u8]> {
+        None
+    }
+    /// Mutable access to the PRG-RAM, used to restore a `.sav` file.
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+    /// Output of the board's sound chip, on the scale of the APU's mixed output. Called
+    /// once per CPU cycle, so boards with expansion audio can clock their chip here.
+    fn expansion_audio(&mut self) -> f32 {
+        0.0
+    }
     // fn write_to_prgram(&mut self){}
-}
\ No newline at end of file
+}
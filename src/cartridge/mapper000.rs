@@ -1,4 +1,8 @@
+use std::io;
+
 use super::{mapper::Mapper, MirrorMode};
+use crate::states::{StateReader, StateWriter};
+
 
 pub struct Mapper000{
     pub(crate) n_chr: u8,
@@ -47,11 +51,6 @@ impl Mapper for Mapper000{
         self.nametable.clone()
     }
     
-    fn savestate(&self) {
-        panic!("mapper 000 doesn't have prg-ram");
-    }
-    
-
     fn hasirq(&mut self) -> bool {
         return false;
     }
@@ -66,4 +65,10 @@ impl Mapper for Mapper000{
     fn irq_clear(&mut self) {
         
     }
+
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> io::Result<()> {
+        Ok(())
+    }
 }
@@ -1,12 +1,13 @@
-use std::{fs::File, io::Read};
+use std::io;
 
 use super::{mapper::Mapper, MirrorMode};
+use crate::states::{StateReader, StateWriter};
 
 /// Mapper001 (MMC1) implementation for NES emulator.
 ///
 /// Supports CHR-ROM/CHR-RAM, SRAM, and PRG bank switching.
 /// Includes serial register loading (5-bit shift register),
-/// mirroring control, and SRAM save/load functionality.
+/// mirroring control, and battery backed SRAM.
 pub struct Mapper001 {
     n_load_register: u8,
     n_load_register_count: u8,
@@ -27,21 +28,14 @@ impl Mapper001 {
     /// Constructs a new `Mapper001` instance.
     ///
     /// Initializes internal registers and memory based on
-    /// PRG and CHR ROM sizes, and loads battery-backed SRAM
-    /// from a file if provided.
+    /// PRG and CHR ROM sizes. Battery backed SRAM is restored by the cartridge.
     ///
     /// # Arguments
     ///
     /// * `prg_rom_size` - Number of 16KB PRG banks.
     /// * `chr_rom_size` - Number of 8KB CHR banks.
     /// * `_nametable_arrangement` - Reserved for mirroring setup (unused).
-    /// * `save` - Optional path to a saved SRAM file.
-    pub fn new(
-        prg_rom_size: u8,
-        chr_rom_size: u8,
-        _nametable_arrangement: MirrorMode,
-        save: Option<String>,
-    ) -> Self {
+    pub fn new(prg_rom_size: u8, chr_rom_size: u8, _nametable_arrangement: MirrorMode) -> Self {
         let mut toreturn = Self {
             n_load_register: 0,
             n_load_register_count: 0,
@@ -58,11 +52,6 @@ impl Mapper001 {
             n_chrbanks: chr_rom_size,
         };
         toreturn.reset();
-        if let Some(path) = save {
-            if let Ok(mut file) = File::open(path) {
-                let _ = file.read(&mut toreturn.ram);
-            }
-        }
         toreturn
     }
 }
@@ -232,23 +221,6 @@ impl Mapper for Mapper001 {
         false
     }
 
-    /// Saves the internal 8KB SRAM to a file chosen by the user.
-    ///
-    /// Uses a GUI file picker to choose the save file path.
-    fn savestate(&self) {
-        use std::fs::File;
-        use std::io::Write;
-        let file = rfd::FileDialog::new()
-            .set_title("Save")
-            .save_file();
-        let file = match file {
-            Some(file) => file,
-            None => return,
-        };
-        let mut file = File::create(file).unwrap();
-        file.write_all(&self.ram).unwrap();
-    }
-
     /// Returns false; MMC1 does not support IRQs.
     fn hasirq(&mut self) -> bool {
         false
@@ -259,4 +231,42 @@ impl Mapper for Mapper001 {
 
     /// Placeholder to clear IRQ flags (not used in MMC1).
     fn irq_clear(&mut self) {}
+
+    /// The 8KB SRAM at $6000-$7FFF.
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.ram)
+    }
+
+    /// Saves the shift register, bank registers and the 8KB SRAM.
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.n_load_register);
+        w.u8(self.n_load_register_count);
+        w.u8(self.n_control_register);
+        w.u8(self.n_chrbank_select4_lo);
+        w.u8(self.n_chrbank_select4_hi);
+        w.u8(self.n_chrbank_select8);
+        w.u8(self.n_prgbank_select16_lo);
+        w.u8(self.n_prgbank_select16_hi);
+        w.u8(self.n_prgbank_select32);
+        w.u8(self.mirrormode.to_u8());
+        w.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.n_load_register = r.u8()?;
+        self.n_load_register_count = r.u8()?;
+        self.n_control_register = r.u8()?;
+        self.n_chrbank_select4_lo = r.u8()?;
+        self.n_chrbank_select4_hi = r.u8()?;
+        self.n_chrbank_select8 = r.u8()?;
+        self.n_prgbank_select16_lo = r.u8()?;
+        self.n_prgbank_select16_hi = r.u8()?;
+        self.n_prgbank_select32 = r.u8()?;
+        self.mirrormode = MirrorMode::from_u8(r.u8()?)?;
+        r.bytes_into(&mut self.ram)
+    }
 }
@@ -1,4 +1,8 @@
+use std::io;
+
 use super::{mapper::Mapper, MirrorMode};
+use crate::states::{StateReader, StateWriter};
+
 
 pub struct Mapper002 {
     n_prgbank_select_lo: u8,
@@ -69,10 +73,6 @@ impl Mapper for Mapper002 {
         self.nametable.clone()
     }
 
-    fn savestate(&self) {
-        panic!("mapper 002 doesn't have prg-ram");
-    }
-
     fn hasirq(&mut self) -> bool {
         return false;
     }
@@ -82,4 +82,15 @@ impl Mapper for Mapper002 {
     
     fn irq_clear(&mut self) {
     }
-}
\ No newline at end of file
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.n_prgbank_select_lo);
+        w.u8(self.n_prgbank_select_hi);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.n_prgbank_select_lo = r.u8()?;
+        self.n_prgbank_select_hi = r.u8()?;
+        Ok(())
+    }
+}
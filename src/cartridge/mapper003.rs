@@ -1,4 +1,8 @@
+use std::io;
+
 use super::{mapper::Mapper, MirrorMode};
+use crate::states::{StateReader, StateWriter};
+
 
 pub struct Mapper003{
     _n_prgbanks: u8,
@@ -46,10 +50,6 @@ impl Mapper for Mapper003{
         self.mirrormode.clone()
     }
 
-    fn savestate(&self) {
-        panic!("save not available on mapper 3");
-    }
-
     fn hasirq(&mut self) -> bool {
         return false;
     }
@@ -62,4 +62,13 @@ impl Mapper for Mapper003{
     
     fn irq_clear(&mut self) {
     }
-}
\ No newline at end of file
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.n_chrbank_select);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.n_chrbank_select = r.u8()?;
+        Ok(())
+    }
+}
@@ -1,4 +1,8 @@
+use std::io;
+
 use super::{mapper::Mapper, MirrorMode};
+use crate::states::{StateReader, StateWriter};
+
 
 pub struct Mapper004 {
     n_prgbanks: u8,
@@ -38,7 +42,7 @@ impl Mapper004 {
             p_register: [0; 8],
             p_chrbank: [0; 8],
             p_prgbank: [0; 4],
-            ram: vec![0; 32 * 1024],
+            ram: vec![0; 8 * 1024],
             n_irqreload: 0,
             n_irqcounter: 0,
             b_irqenable: false,
@@ -259,21 +263,52 @@ impl Mapper for Mapper004 {
         self.p_prgbank[3] = ((self.n_prgbanks as u32) * 2 - 1) * 0x2000;
     }
 
-    fn savestate(&self) {
-        use std::fs::File;
-        use std::io::Write;
-        let file = rfd::FileDialog::new()
-        .set_title("Save")
-        .save_file();
-        let file = match file{
-            Some(file) => file,
-            None => 
-            {
-                return;
-            },
-        };
-        let mut file = File::create(file).unwrap();
+    /// The 8KB PRG-RAM at $6000-$7FFF.
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.ram)
+    }
 
-        file.write_all(&self.ram).unwrap();
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.ram)
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.n_target_register);
+        w.bool(self.b_prgbank_mode);
+        w.bool(self.b_chrinversion);
+        w.u8(self.mirrormode.to_u8());
+        for reg in &self.p_register {
+            w.i32(*reg);
+        }
+        for bank in self.p_chrbank.iter().chain(&self.p_prgbank) {
+            w.u32(*bank);
+        }
+        w.u16(self.n_irqreload);
+        w.u16(self.n_irqcounter);
+        w.bool(self.b_irqenable);
+        w.bool(self.b_irqactive);
+        w.bool(self.b_irqupdate);
+        w.bool(self.last_a12_state);
+        w.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.n_target_register = r.u8()?;
+        self.b_prgbank_mode = r.bool()?;
+        self.b_chrinversion = r.bool()?;
+        self.mirrormode = MirrorMode::from_u8(r.u8()?)?;
+        for reg in self.p_register.iter_mut() {
+            *reg = r.i32()?;
+        }
+        for bank in self.p_chrbank.iter_mut().chain(self.p_prgbank.iter_mut()) {
+            *bank = r.u32()?;
+        }
+        self.n_irqreload = r.u16()?;
+        self.n_irqcounter = r.u16()?;
+        self.b_irqenable = r.bool()?;
+        self.b_irqactive = r.bool()?;
+        self.b_irqupdate = r.bool()?;
+        self.last_a12_state = r.bool()?;
+        r.bytes_into(&mut self.ram)
     }
 }
@@ -1,5 +1,9 @@
 
+use std::io;
+
 use super::{mapper::Mapper, MirrorMode};
+use crate::states::{StateReader, StateWriter};
+
 
 pub struct Mapper066 {
     _n_chrbanks: u8,
@@ -75,10 +79,6 @@ impl Mapper for Mapper066 {
         // Mapper 066 doesn't support IRQs
     }
 
-    fn savestate(&self) {
-        // Implementation depends on your savestate system
-    }
-
     fn hasirq(&mut self) -> bool {
         false
     }
@@ -92,5 +92,14 @@ impl Mapper for Mapper066 {
         self._n_prgbank_select = 0;
     }
 
-    
-}
\ No newline at end of file
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self._n_prgbank_select);
+        w.u8(self._n_chrbank_select);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self._n_prgbank_select = r.u8()?;
+        self._n_chrbank_select = r.u8()?;
+        Ok(())
+    }
+}
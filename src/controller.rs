@@ -1,7 +1,11 @@
 use bitflags::bitflags;
+use std::io;
+
+use crate::states::{Savestate, StateReader, StateWriter};
 
 // Define button mappings using bitflags
 bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct Buttons: u8 {
         const A = 0b0000_0001;
         const B = 0b0000_0010;
@@ -45,7 +49,8 @@ impl Controller {
         self.dataread = false;
         result
     }
-    // Reads button state in a serial fashion, one bit at a time
+    // Reads button state in a serial fashion, one bit at a time. Only bit 0 is driven,
+    // the bus fills in the other bits from open bus
     pub fn cpu_read(&mut self) -> u8 {
         if self.index > 7 {
             self.dataread = true;
@@ -59,6 +64,13 @@ impl Controller {
         
         response
     }
+    // Returns the bit the next read would return, without shifting
+    pub fn peek(&self) -> u8 {
+        if self.index > 7 {
+            return 1;
+        }
+        (self.button.bits() >> self.index) & 1
+    }
     pub fn _set_reg_value(&mut self, byte: u8){
         self.button = Buttons::from_bits_truncate(byte);
     } 
@@ -70,4 +82,21 @@ impl Controller {
     pub fn set_button(&mut self, button: Buttons, pressed: bool) {
         self.button.set(button, pressed);
     }
-}
\ No newline at end of file
+}
+
+impl Savestate for Controller {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.button.bits());
+        w.bool(self.strobe);
+        w.u8(self.index);
+        w.bool(self.dataread);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.button = Buttons::from_bits_truncate(r.u8()?);
+        self.strobe = r.bool()?;
+        self.index = r.u8()?;
+        self.dataread = r.bool()?;
+        Ok(())
+    }
+}
@@ -1,9 +1,11 @@
 mod mode;
 
-
-
 use crate::bus::Bus;
+use crate::states::{Savestate, StateReader, StateWriter};
+use std::io;
+mod cpubus;
 mod instructions;
+pub use cpubus::{BusAccess, CpuBus, FlatMemory, TraceBus};
 bitflags! {
     // Define CPU status flags as a bitfield structure
     pub struct Flags: u8 {
@@ -20,7 +22,7 @@ bitflags! {
 use bitflags::bitflags;
 
 /// Representation of the CPU state
-pub struct Cpu {
+pub struct Cpu<B: CpuBus = Bus> {
     flags: Flags,    // Processor status flags
     a: u8,          // Accumulator register
     x: u8,          // X register
@@ -29,17 +31,20 @@ pub struct Cpu {
     sp: u8,         // Stack pointer
     addrabs: u16,   // Absolute memory address
     relval: u16,    // Relative value for branch instructions
-    cycles_left: u16, // Remaining cycles for the current instruction
-    total_cycles: usize, // Total executed cycles
-    bus: Option<*mut Bus>, // Pointer to the system bus
+    total_cycles: usize, // Total executed cycles, one per bus access
+    bus: B,          // The system bus
     opcode: u8,      // Current opcode being executed
     oldpc: u16,      // Previous program counter value
-    irqset: bool,
+    nmi_level: bool,   // /NMI level at the last cycle, for the edge detector
+    nmi_pending: bool, // An NMI edge was seen and not serviced yet
+    irq_pending: bool, // /IRQ was asserted with the I flag clear at the last cycle
+    prev_nmi: bool,    // nmi_pending as of the cycle before the last one
+    prev_irq: bool,    // irq_pending as of the cycle before the last one
 }
 
-impl Cpu {
-    /// Constructor to initialize CPU state
-    pub fn new() -> Self {
+impl<B: CpuBus> Cpu<B> {
+    /// Constructor to initialize CPU state, attached to `bus`
+    pub fn new(bus: B) -> Self {
         let mut flags = Flags::empty();
         flags.set(Flags::Unused, true);
         Self {
@@ -48,49 +53,356 @@ impl Cpu {
             x: 0,
             y: 0,
             pc: 0x8000, // Typically the reset vector address
-            sp: 0xFD, // Stack starts near the top of memory
-            bus: None,
+            sp: 0x00, // Reset moves it down to $FD
+            bus,
             addrabs: 0,
             relval: 0,
-            cycles_left: 0,
             total_cycles: 0,
             opcode: 0,
             oldpc: 0,
-            irqset: false,
+            nmi_level: false,
+            nmi_pending: false,
+            irq_pending: false,
+            prev_nmi: false,
+            prev_irq: false,
         }
     }
-    
-    
-    /// Links the CPU to a system bus
-    pub fn linkbus(&mut self, bus: &mut Bus) {
-        self.bus = Some(bus);
-    }
-    
-    /// Reads a byte from memory via the system bus
-    fn cpu_read(&self, address: u16, rdonly: bool) -> u8 {
-        unsafe { (*self.bus.unwrap()).cpu_read(address, rdonly) }
-    }
-    fn cpu_write(&self, address: u16, byte: u8) {
-        unsafe {
-            (*self.bus.unwrap()).cpu_write(address, byte);
-        };
-    }
-    
-    /// Advances the CPU clock cycle, fetching and executing an instruction if needed
-    pub fn clock(&mut self) -> u64 {
-        // Fetch the next instruction if there are no remaining cycles
-        if self.cycles_left == 0 {
-            self.flags.set(Flags::Unused,true);
-            let opcode = self.cpu_read(self.pc,false);
-            self.oldpc = self.pc;
-            self.opcode = opcode;
-            self.pc = self.pc.wrapping_add(1);
-            self.handle_opcode(opcode); // Execute instruction
+
+    /// Returns the bus the CPU is attached to
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+
+    /// Returns the bus the CPU is attached to
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    /// Reads a byte from memory via the system bus, taking one cycle.
+    /// `rdonly` reads have no side effects and take no time.
+    fn cpu_read(&mut self, address: u16, rdonly: bool) -> u8 {
+        if rdonly {
+            self.bus.peek(address)
+        } else {
+            self.total_cycles = self.total_cycles.wrapping_add(1);
+            let data = self.bus.read(address);
+            self.poll_interrupts();
+            data
         }
-        
-        // Decrement cycle count and increment total executed cycles
-        self.cycles_left = self.cycles_left.wrapping_sub(1);
+    }
+    /// Writes a byte via the system bus, taking one cycle.
+    fn cpu_write(&mut self, address: u16, byte: u8) {
         self.total_cycles = self.total_cycles.wrapping_add(1);
-        (self.cycles_left as u64).wrapping_add(1)
+        self.bus.write(address, byte);
+        self.poll_interrupts();
+    }
+
+    /// Samples the interrupt lines at the end of a cycle. /NMI goes through an edge
+    /// detector, /IRQ is a level that only counts while the I flag is clear. The CPU acts
+    /// on what it saw at the end of an instruction's second-to-last cycle, so the sample
+    /// of the previous cycle is kept too. Instructions that change the I flag on their last
+    /// cycle (CLI, SEI, PLP) therefore only affect polling after the next instruction.
+    fn poll_interrupts(&mut self) {
+        self.prev_nmi = self.nmi_pending;
+        self.prev_irq = self.irq_pending;
+        let nmi = self.bus.nmi_line();
+        if nmi && !self.nmi_level {
+            self.nmi_pending = true;
+        }
+        self.nmi_level = nmi;
+        self.irq_pending = self.bus.irq_line() && !self.flags.contains(Flags::IDisable);
     }
-}
\ No newline at end of file
+
+    /// Cycles executed since the last reset.
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles as u64
+    }
+
+    /// Executes one whole instruction and returns the number of cycles it took.
+    /// The 6502 accesses the bus on every single cycle, so the instruction is made of one
+    /// read or write per cycle in the real order, dummy reads and writes included. A bus
+    /// that advances the rest of the console on each access therefore sees every access
+    /// land on its real cycle.
+    ///
+    /// If an interrupt was pending at the instruction's second-to-last cycle, the interrupt
+    /// sequence runs right after it and is part of the same step.
+    pub fn step(&mut self) -> u64 {
+        let start = self.total_cycles;
+        self.flags.set(Flags::Unused,true);
+        self.oldpc = self.pc;
+        self.opcode = self.fetch();
+        self.handle_opcode(self.opcode); // Execute instruction
+        if self.prev_nmi || self.prev_irq {
+            self.interrupt();
+        }
+        self.total_cycles.wrapping_sub(start) as u64
+    }
+}
+
+impl<B: CpuBus> Savestate for Cpu<B> {
+    /// Saves the registers; the bus is saved separately. `step` runs whole instructions, so
+    /// a state is always taken between two of them.
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.flags.bits());
+        w.u8(self.a);
+        w.u8(self.x);
+        w.u8(self.y);
+        w.u16(self.pc);
+        w.u8(self.sp);
+        w.u16(self.addrabs);
+        w.u16(self.relval);
+        w.u64(self.total_cycles as u64);
+        w.u8(self.opcode);
+        w.u16(self.oldpc);
+        w.bool(self.nmi_level);
+        w.bool(self.nmi_pending);
+        w.bool(self.irq_pending);
+        w.bool(self.prev_nmi);
+        w.bool(self.prev_irq);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.flags = Flags::from_bits_truncate(r.u8()?);
+        self.a = r.u8()?;
+        self.x = r.u8()?;
+        self.y = r.u8()?;
+        self.pc = r.u16()?;
+        self.sp = r.u8()?;
+        self.addrabs = r.u16()?;
+        self.relval = r.u16()?;
+        self.total_cycles = r.u64()? as usize;
+        self.opcode = r.u8()?;
+        self.oldpc = r.u16()?;
+        self.nmi_level = r.bool()?;
+        self.nmi_pending = r.bool()?;
+        self.irq_pending = r.bool()?;
+        self.prev_nmi = r.bool()?;
+        self.prev_irq = r.bool()?;
+        Ok(())
+    }
+}
+#[cfg(test)]
+///# Unit tests module
+/// - Runs small programs on a flat 64KB memory, no console attached
+mod cpu_tests {
+    use super::{BusAccess, Cpu, CpuBus, FlatMemory, TraceBus};
+
+    /// Loads `program` at $8000, points the reset vector at it and resets the CPU
+    fn cpu_with_program(program: &[u8]) -> Cpu<FlatMemory> {
+        let mut memory = FlatMemory::new();
+        memory.load(0x8000, program);
+        memory.load(0xFFFC, &[0x00, 0x80]);
+        let mut cpu = Cpu::new(memory);
+        cpu.reset();
+        cpu
+    }
+
+    /// Documented cycle counts of every opcode, without page crossings or taken branches.
+    /// Zero marks the JAM opcodes, which never finish.
+    const CYCLES: [u8; 256] = [
+        7, 6, 0, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6,
+        2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+        6, 6, 0, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6,
+        2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+        6, 6, 0, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6,
+        2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+        6, 6, 0, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6,
+        2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+        2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+        2, 6, 0, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5,
+        2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+        2, 5, 0, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4,
+        2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+        2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+        2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+        2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    ];
+
+    /// Unofficial opcodes that are not implemented yet.
+    const UNIMPLEMENTED: [u8; 9] = [0x0B, 0x2B, 0x4B, 0x6B, 0x93, 0x9B, 0x9C, 0x9E, 0x9F];
+
+    /// Runs `program` from $8000 with every register zero and records the bus accesses
+    fn trace_program(program: &[u8], x: u8) -> Cpu<TraceBus<FlatMemory>> {
+        let mut memory = FlatMemory::new();
+        memory.load(0x8000, program);
+        memory.load(0xFFFC, &[0x00, 0x80]);
+        let mut cpu = Cpu::new(TraceBus::new(memory));
+        cpu.reset();
+        cpu.x = x;
+        cpu.bus_mut().clear();
+        cpu.step();
+        cpu
+    }
+    /// Like `cpu_with_program`, with the NMI vector at $A000 and the IRQ vector at $9000
+    fn interrupt_program(program: &[u8]) -> Cpu<FlatMemory> {
+        let mut memory = FlatMemory::new();
+        memory.load(0x8000, program);
+        memory.load(0xFFFA, &[0x00, 0xA0, 0x00, 0x80, 0x00, 0x90]);
+        let mut cpu = Cpu::new(memory);
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    pub fn irq_is_polled_on_the_second_to_last_cycle(){
+        // CLI; NOP; LDA $0200; NOP. Reset takes cycles 1-7, LDA runs on cycles 12-15
+        let program = [0x58, 0xEA, 0xAD, 0x00, 0x02, 0xEA];
+        let mut cpu = interrupt_program(&program);
+        cpu.bus_mut().raise_irq_at(14);
+        cpu.step();
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x9000, "irq seen on cycle 14 taken after LDA, FAILED!");
+
+        let mut cpu = interrupt_program(&program);
+        cpu.bus_mut().raise_irq_at(15);
+        cpu.step();
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x8005, "irq on the last cycle waits, FAILED!");
+        cpu.step();
+        assert_eq!(cpu.pc, 0x9000, "irq taken after the next instruction, FAILED!");
+    }
+
+    #[test]
+    pub fn cli_and_sei_act_one_instruction_late(){
+        // CLI; SEI; NOP with /IRQ held from the start
+        let mut cpu = interrupt_program(&[0x58, 0x78, 0xEA]);
+        cpu.bus_mut().raise_irq_at(0);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x8001, "no irq right after CLI, FAILED!");
+        cpu.step();
+        assert_eq!(cpu.pc, 0x9000, "irq taken after SEI, FAILED!");
+        assert_eq!(
+            cpu.bus_mut().peek(0x01FB) & 0x04,
+            0x04,
+            "pushed status has I set by SEI, FAILED!"
+        );
+    }
+
+    #[test]
+    pub fn nmi_hijacks_brk(){
+        // BRK pushes PC on cycles 10-11 and picks its vector after that
+        let mut cpu = interrupt_program(&[0x00, 0x00]);
+        cpu.bus_mut().load(0xA000, &[0xEA]);
+        cpu.bus_mut().raise_nmi_at(11);
+        cpu.step();
+        assert_eq!(cpu.pc, 0xA000, "brk jumps to the nmi vector, FAILED!");
+        assert_eq!(
+            cpu.bus_mut().peek(0x01FB) & 0x10,
+            0x10,
+            "pushed status still has B set, FAILED!"
+        );
+        cpu.step();
+        assert_eq!(cpu.pc, 0xA001, "nmi is not taken twice, FAILED!");
+
+        // An NMI one cycle later only follows the BRK
+        let mut cpu = interrupt_program(&[0x00, 0x00]);
+        cpu.bus_mut().load(0x9000, &[0xEA]);
+        cpu.bus_mut().raise_nmi_at(12);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x9000, "brk uses its own vector, FAILED!");
+        cpu.step();
+        assert_eq!(cpu.pc, 0xA000, "nmi after the first handler instruction, FAILED!");
+    }
+
+    #[test]
+    pub fn load_add_store(){
+        // LDA #$10; CLC; ADC #$22; STA $0200
+        let mut cpu = cpu_with_program(&[0xA9, 0x10, 0x18, 0x69, 0x22, 0x8D, 0x00, 0x02]);
+        for _ in 0..4 {
+            cpu.step();
+        }
+        assert_eq!(cpu.a, 0x32, "accumulator, FAILED!");
+        assert_eq!(cpu.bus_mut().peek(0x0200), 0x32, "stored value, FAILED!");
+        assert_eq!(cpu.pc, 0x8008, "program counter, FAILED!");
+    }
+
+    #[test]
+    pub fn subroutine_round_trip(){
+        // JSR $8010; BRK ... $8010: LDX #$05; RTS
+        let mut cpu = cpu_with_program(&[0x20, 0x10, 0x80]);
+        cpu.bus_mut().load(0x8010, &[0xA2, 0x05, 0x60]);
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.x, 0x05, "x register, FAILED!");
+        assert_eq!(cpu.pc, 0x8003, "return address, FAILED!");
+        assert_eq!(cpu.sp, 0xFD, "stack pointer, FAILED!");
+    }
+
+    #[test]
+    pub fn trace_records_accesses(){
+        // STA $0300
+        let mut memory = FlatMemory::new();
+        memory.load(0x8000, &[0x8D, 0x00, 0x03]);
+        memory.load(0xFFFC, &[0x00, 0x80]);
+        let mut cpu = Cpu::new(TraceBus::new(memory));
+        cpu.reset();
+        cpu.bus_mut().clear();
+        cpu.step();
+        assert_eq!(
+            cpu.bus().log().last(),
+            Some(&BusAccess::Write(0x0300, 0x00)),
+            "trace, FAILED!"
+        );
+    }
+
+    #[test]
+    pub fn cycle_counts_match_opcode_table(){
+        for opcode in 0..=255u8 {
+            if CYCLES[opcode as usize] == 0 || UNIMPLEMENTED.contains(&opcode) {
+                continue;
+            }
+            // All operands are zero, so nothing crosses a page. After reset every flag is
+            // clear, which makes BPL, BVC, BCC and BNE branch
+            let cpu = trace_program(&[opcode, 0x00, 0x00], 0);
+            let taken = matches!(opcode, 0x10 | 0x50 | 0x90 | 0xD0) as usize;
+            assert_eq!(
+                cpu.bus().log().len(),
+                CYCLES[opcode as usize] as usize + taken,
+                "bus accesses of opcode {:02X}, FAILED!",
+                opcode
+            );
+            assert_eq!(
+                cpu.total_cycles(),
+                7 + cpu.bus().log().len() as u64,
+                "one cycle per access of opcode {:02X}, FAILED!",
+                opcode
+            );
+        }
+    }
+
+    #[test]
+    pub fn page_crossing_dummy_read(){
+        // LDA $80F0,X with X = $20 first reads $8010 on the wrong page
+        let cpu = trace_program(&[0xBD, 0xF0, 0x80], 0x20);
+        assert_eq!(
+            &cpu.bus().log()[3..],
+            &[BusAccess::Read(0x8010, 0x00), BusAccess::Read(0x8110, 0x00)],
+            "dummy read before the real one, FAILED!"
+        );
+        // STA $0300,X always reads before writing, even without a page crossing
+        let cpu = trace_program(&[0x9D, 0x00, 0x03], 0x01);
+        assert_eq!(
+            &cpu.bus().log()[3..],
+            &[BusAccess::Read(0x0301, 0x00), BusAccess::Write(0x0301, 0x00)],
+            "store dummy read, FAILED!"
+        );
+    }
+
+    #[test]
+    pub fn read_modify_write_writes_twice(){
+        // INC $0200
+        let cpu = trace_program(&[0xEE, 0x00, 0x02], 0);
+        assert_eq!(
+            &cpu.bus().log()[3..],
+            &[
+                BusAccess::Read(0x0200, 0x00),
+                BusAccess::Write(0x0200, 0x00),
+                BusAccess::Write(0x0200, 0x01),
+            ],
+            "old value written back before the new one, FAILED!"
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! # CPU Bus
+//! The interface the 6502 core uses to reach memory. The CPU owns a value implementing
+//! [`CpuBus`], so it can run against the real console [`Bus`](crate::bus::Bus), a flat
+//! 64KB test memory, or a wrapper that records every access.
+
+/// Memory as seen from the CPU. The 6502 accesses the bus on every cycle, so each call to
+/// `read` or `write` stands for exactly one CPU cycle and implementations may advance the
+/// rest of the machine by one cycle per call.
+pub trait CpuBus {
+    /// Reads a byte, with whatever side effects the read has on the hardware behind it.
+    fn read(&mut self, address: u16) -> u8;
+
+    /// Writes a byte.
+    fn write(&mut self, address: u16, data: u8);
+
+    /// Reads a byte without any side effects (no register clears, no latch updates).
+    /// Meant for debuggers and tracers.
+    fn peek(&mut self, address: u16) -> u8;
+
+    /// Whether /NMI is asserted. The CPU samples it after every cycle and reacts to the
+    /// edge, so a source can simply report its current level.
+    fn nmi_line(&mut self) -> bool {
+        false
+    }
+
+    /// Whether /IRQ is asserted by any of the sources wired to it. The line is level
+    /// triggered: the CPU keeps taking the interrupt until the source is acknowledged.
+    fn irq_line(&mut self) -> bool {
+        false
+    }
+}
+
+/// 64KB of plain RAM with nothing mapped, for running the CPU on its own.
+/// The interrupt lines can be raised at a given cycle to test interrupt timing.
+pub struct FlatMemory {
+    memory: Vec<u8>,
+    /// Reads and writes so far, one per cycle.
+    cycles: u64,
+    /// Cycle from which /NMI is asserted.
+    nmi_from: Option<u64>,
+    /// Cycle from which /IRQ is asserted.
+    irq_from: Option<u64>,
+}
+
+impl FlatMemory {
+    /// Creates a zero filled 64KB address space.
+    pub fn new() -> Self {
+        Self {
+            memory: vec![0; 0x10000],
+            cycles: 0,
+            nmi_from: None,
+            irq_from: None,
+        }
+    }
+
+    /// Asserts /NMI from the end of the `cycle`th access on, counting from 1.
+    pub fn raise_nmi_at(&mut self, cycle: u64) {
+        self.nmi_from = Some(cycle);
+    }
+
+    /// Asserts /IRQ from the end of the `cycle`th access on, counting from 1.
+    pub fn raise_irq_at(&mut self, cycle: u64) {
+        self.irq_from = Some(cycle);
+    }
+
+    /// Copies `bytes` into memory starting at `address`, wrapping at $FFFF.
+    pub fn load(&mut self, address: u16, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.memory[address.wrapping_add(i as u16) as usize] = *byte;
+        }
+    }
+}
+
+impl Default for FlatMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CpuBus for FlatMemory {
+    fn read(&mut self, address: u16) -> u8 {
+        self.cycles += 1;
+        self.memory[address as usize]
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.cycles += 1;
+        self.memory[address as usize] = data;
+    }
+
+    fn peek(&mut self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn nmi_line(&mut self) -> bool {
+        self.nmi_from.is_some_and(|cycle| self.cycles >= cycle)
+    }
+
+    fn irq_line(&mut self) -> bool {
+        self.irq_from.is_some_and(|cycle| self.cycles >= cycle)
+    }
+}
+
+/// A single bus cycle recorded by [`TraceBus`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusAccess {
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+/// Wraps another bus and records every read and write that goes through it.
+pub struct TraceBus<B: CpuBus> {
+    inner: B,
+    log: Vec<BusAccess>,
+}
+
+impl<B: CpuBus> TraceBus<B> {
+    pub fn new(inner: B) -> Self {
+        Self {
+            inner,
+            log: Vec::new(),
+        }
+    }
+
+    /// Accesses recorded since creation or the last `clear`.
+    pub fn log(&self) -> &[BusAccess] {
+        &self.log
+    }
+
+    pub fn clear(&mut self) {
+        self.log.clear();
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+}
+
+impl<B: CpuBus> CpuBus for TraceBus<B> {
+    fn read(&mut self, address: u16) -> u8 {
+        let data = self.inner.read(address);
+        self.log.push(BusAccess::Read(address, data));
+        data
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.inner.write(address, data);
+        self.log.push(BusAccess::Write(address, data));
+    }
+
+    fn peek(&mut self, address: u16) -> u8 {
+        self.inner.peek(address)
+    }
+
+    fn nmi_line(&mut self) -> bool {
+        self.inner.nmi_line()
+    }
+
+    fn irq_line(&mut self) -> bool {
+        self.inner.irq_line()
+    }
+}
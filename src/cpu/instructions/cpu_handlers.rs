@@ -1,6 +1,6 @@
 
-use super::inst_enum::{AddressMode, Instruction};
-use crate::cpu::Cpu;
+use super::inst_enum::{Access, AddressMode, Instruction};
+use crate::cpu::{Cpu, CpuBus};
 use crate::cpu::Flags;
 mod arithmetic;
 mod branches;
@@ -14,7 +14,7 @@ mod stackoperations;
 mod systemfunctions;
 mod unofficial;
 
-impl Cpu {
+impl<B: CpuBus> Cpu<B> {
     pub fn push(&mut self, byte: u8) {
         let address = 0x100 + (self.sp as u16);
         self.sp = self.sp.wrapping_sub(1);
@@ -25,6 +25,19 @@ impl Cpu {
         let address = 0x100 + (self.sp as u16);
         self.cpu_read(address, false)
     }
+    /// Spends a cycle reading the top of the stack without pulling from it, which is what
+    /// the 6502 does on the cycle it increments the stack pointer before a pull.
+    pub fn stack_dummy_read(&mut self) {
+        self.cpu_read(0x100 + (self.sp as u16), false);
+    }
+    /// Reads the operand of a read-modify-write instruction. The 6502 writes the unmodified
+    /// value back on the next cycle while it computes the new one, so the location sees
+    /// two writes, which mapper and PPU registers can tell apart from one.
+    pub fn read_modify(&mut self) -> u8 {
+        let value = self.cpu_read(self.addrabs, false);
+        self.cpu_write(self.addrabs, value);
+        value
+    }
     pub fn handle_opcode(&mut self, opcode: u8) {
         match opcode {
             0xA9 => self.handle_operation(AddressMode::Immediate, Instruction::LDA, 2),
@@ -274,17 +287,29 @@ impl Cpu {
         };
     }
 
+    /// Runs the addressing mode and the instruction after the opcode was fetched. `cycles` is
+    /// the documented length of the instruction without page crossings or taken branches;
+    /// the actual length follows from the bus accesses made.
     pub fn handle_operation(
         &mut self,
         addrmode: AddressMode,
         instruction: Instruction,
         cycles: u8,
     ) {
-        self.handle_addrmode(&addrmode);
+        let start = self.total_cycles;
+        // JSR pushes the return address between fetching the two halves of its operand,
+        // so it reads the operand itself
+        if !matches!(instruction, Instruction::JSR) {
+            self.handle_addrmode(&addrmode, instruction.access());
+        }
         self.handle_instruction(instruction);
-        self.cycles_left = self.cycles_left.wrapping_add(cycles as u16);
+        debug_assert!(
+            self.total_cycles.wrapping_sub(start) + 1 >= cycles as usize,
+            "opcode {:02X} finished early",
+            self.opcode
+        );
     }
-    pub fn handle_addrmode(&mut self, addrmode: &AddressMode) {
+    pub fn handle_addrmode(&mut self, addrmode: &AddressMode, access: Access) {
         match addrmode {
             AddressMode::Implicit => self.implicit(),
             AddressMode::Accumulator => self.accumulator(),
@@ -293,11 +318,11 @@ impl Cpu {
             AddressMode::ZeroPageY => self.zeropagey(),
             AddressMode::Relative => self.relative(),
             AddressMode::Absolute => self.absolute(),
-            AddressMode::AbsoluteX => self.absolutex(),
-            AddressMode::AbsoluteY => self.absolutey(),
+            AddressMode::AbsoluteX => self.absolutex(access),
+            AddressMode::AbsoluteY => self.absolutey(access),
             AddressMode::Indirect => self.indirect(),
             AddressMode::IDX => self.idx(),
-            AddressMode::IDY => self.idy(),
+            AddressMode::IDY => self.idy(access),
             AddressMode::Immediate => self.immediate(),
         };
     }
@@ -357,7 +382,7 @@ impl Cpu {
             Instruction::SED => self.flags.set(Flags::Decimal, true),
             Instruction::SEI => self.flags.set(Flags::IDisable, true),
             Instruction::BRK => self.brk(),
-            Instruction::NOP => {}
+            Instruction::NOP => self.nop(),
             Instruction::RTI => self.rti(),
             Instruction::SRE => self.sre(),
             Instruction::LAX => self.lax(),
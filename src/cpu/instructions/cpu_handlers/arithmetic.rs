@@ -1,6 +1,6 @@
-use crate::cpu::Cpu;
+use crate::cpu::{Cpu, CpuBus};
 use crate::cpu::Flags;
-impl Cpu {
+impl<B: CpuBus> Cpu<B> {
     ///# `ADC` - Add with Carry
     /// - A,Z,C,N = A+M+C
     /// - This instruction adds the contents of a memory location to the accumulator together with the carry bit. If overflow occurs the carry bit is set, this enables multiple byte addition to be performed.
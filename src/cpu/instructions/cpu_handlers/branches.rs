@@ -1,102 +1,66 @@
-use crate::cpu::Cpu;
+use crate::cpu::{Cpu, CpuBus};
 use crate::cpu::Flags;
-impl Cpu{
-    // #`BCC` - Branch if Carry Clear
-    /// - If the carry flag is clear then add the relative displacement to the program counter to cause a branch to a new location.
-    pub fn bcc(&mut self) {
-        if !self.flags.contains(Flags::Carry){
-            self.cycles_left = self.cycles_left.wrapping_add(1);
+impl<B: CpuBus> Cpu<B> {
+    ///# Branch
+    /// Helper shared by all branches. A taken branch spends a cycle reading the next opcode
+    /// while it adds the offset, and one more reading from the wrong page if the target is
+    /// on another page.
+    fn branch(&mut self, condition: bool) {
+        if condition {
             let jump_addr = self.pc.wrapping_add(self.relval);
-            if jump_addr & 0xFF00 != self.pc & 0xFF00{
-                self.cycles_left = self.cycles_left.wrapping_add(2);
+            let crosses_page = jump_addr & 0xFF00 != self.pc & 0xFF00;
+            // A taken branch that stays on its page does not poll on its last cycle, so an
+            // IRQ that showed up on its second cycle waits for the next instruction
+            if !crosses_page && self.irq_pending && !self.prev_irq {
+                self.irq_pending = false;
+            }
+            self.cpu_read(self.pc, false);
+            if crosses_page {
+                self.cpu_read((self.pc & 0xFF00) | (jump_addr & 0x00FF), false);
             }
             self.pc = jump_addr;
         }
     }
+    // #`BCC` - Branch if Carry Clear
+    /// - If the carry flag is clear then add the relative displacement to the program counter to cause a branch to a new location.
+    pub fn bcc(&mut self) {
+        self.branch(!self.flags.contains(Flags::Carry));
+    }
     ///# `BCS` - Branch if Carry Set
     /// - If the carry flag is set then add the relative displacement to the program counter to cause a branch to a new location.
     pub fn bcs(&mut self) {
-        if self.flags.contains(Flags::Carry){
-            self.cycles_left = self.cycles_left.wrapping_add(1);
-            let jump_addr = self.pc.wrapping_add(self.relval);
-            if jump_addr & 0xFF00 != self.pc & 0xFF00{
-                self.cycles_left = self.cycles_left.wrapping_add(2);
-            }
-            self.pc = jump_addr;
-        }
+        self.branch(self.flags.contains(Flags::Carry));
     }
     ///# `BEQ` - Branch if Equal
     ///- If the zero flag is set then add the relative displacement to the program counter to cause a branch to a new location.
     pub fn beq(&mut self) {
-        if self.flags.contains(Flags::Zero){
-            self.cycles_left = self.cycles_left.wrapping_add(1);
-            let jump_addr = self.pc.wrapping_add(self.relval);
-            if jump_addr & 0xFF00 != self.pc & 0xFF00{
-                self.cycles_left = self.cycles_left.wrapping_add(2);
-            }
-            self.pc = jump_addr;
-        }
+        self.branch(self.flags.contains(Flags::Zero));
     }
     ///# `BMI` - Branch if Minus
     /// - If the negative flag is set then add the relative displacement to the program counter to cause a branch to a new location.
     pub fn bmi(&mut self) {
-        if self.flags.contains(Flags::Negative){
-            self.cycles_left = self.cycles_left.wrapping_add(1);
-            let jump_addr = self.pc.wrapping_add(self.relval);
-            if jump_addr & 0xFF00 != self.pc & 0xFF00{
-                self.cycles_left = self.cycles_left.wrapping_add(2);
-            }
-            self.pc = jump_addr;
-        }
+        self.branch(self.flags.contains(Flags::Negative));
     }
     ///# `BNE` - Branch if Not Equal
     /// If the zero flag is clear then add the relative displacement to the program counter to cause a branch to a new location.
     pub fn bne(&mut self) {
-        if !self.flags.contains(Flags::Zero){
-            self.cycles_left = self.cycles_left.wrapping_add(1);
-            let jump_addr = self.pc.wrapping_add(self.relval);
-            if jump_addr & 0xFF00 != self.pc & 0xFF00{
-                self.cycles_left = self.cycles_left.wrapping_add(2);
-            }
-            self.pc = jump_addr;
-        }
+        self.branch(!self.flags.contains(Flags::Zero));
     }
     ///# `BPL` - Branch if Positive
     /// - If the negative flag is clear then add the relative displacement to the program counter to cause a branch to a new location.
     pub fn bpl(&mut self) {
-        if !self.flags.contains(Flags::Negative){
-            self.cycles_left = self.cycles_left.wrapping_add(1);
-            let jump_addr = self.pc.wrapping_add(self.relval);
-            if jump_addr & 0xFF00 != self.pc & 0xFF00{
-                self.cycles_left = self.cycles_left.wrapping_add(2);
-            }
-            self.pc = jump_addr;
-        }
+        self.branch(!self.flags.contains(Flags::Negative));
     }
     ///# `BVC` - Branch if Overflow Clear
     /// - If the overflow flag is clear then add the relative displacement to the program counter to cause a branch to a new location.
 
 
     pub fn bvc(&mut self) {
-        if !self.flags.contains(Flags::Overflow){
-            self.cycles_left = self.cycles_left.wrapping_add(1);
-            let jump_addr = self.pc.wrapping_add(self.relval);
-            if jump_addr & 0xFF00 != self.pc & 0xFF00{
-                self.cycles_left = self.cycles_left.wrapping_add(2);
-            }
-            self.pc = jump_addr;
-        }
+        self.branch(!self.flags.contains(Flags::Overflow));
     }
     ///# `BVS` - Branch if Overflow Set
     /// - If the overflow flag is set then add the relative displacement to the program counter to cause a branch to a new location.
     pub fn bvs(&mut self) {
-        if self.flags.contains(Flags::Overflow){
-            self.cycles_left = self.cycles_left.wrapping_add(1);
-            let jump_addr = self.pc.wrapping_add(self.relval);
-            if jump_addr & 0xFF00 != self.pc & 0xFF00{
-                self.cycles_left = self.cycles_left.wrapping_add(2);
-            }
-            self.pc = jump_addr;
-        }
+        self.branch(self.flags.contains(Flags::Overflow));
     }
 }
\ No newline at end of file
@@ -1,11 +1,11 @@
-use crate::cpu::Cpu;
+use crate::cpu::{Cpu, CpuBus};
 use crate::cpu::Flags;
-impl Cpu{
+impl<B: CpuBus> Cpu<B> {
     ///# `INC` - Increment Memory
     /// - M,Z,N = M+1
     /// - Adds one to the value held at a specified memory location setting the zero and negative flags as appropriate.
     pub fn inc(&mut self) {
-        let immval = self.cpu_read(self.addrabs, false);
+        let immval = self.read_modify();
         let immval = immval.wrapping_add(1);
         self.flags.set(Flags::Zero,immval == 0);
         self.flags.set(Flags::Negative,immval & 0x80 != 0);
@@ -31,7 +31,7 @@ impl Cpu{
     /// - M,Z,N = M-1
     /// - Subtracts one from the value held at a specified memory location setting the zero and negative flags as appropriate.
     pub fn dec(&mut self) {
-        let immval = self.cpu_read(self.addrabs, false);
+        let immval = self.read_modify();
         let immval = immval.wrapping_sub(1);
         self.flags.set(Flags::Zero,immval == 0);
         self.flags.set(Flags::Negative,immval & 0x80 != 0);
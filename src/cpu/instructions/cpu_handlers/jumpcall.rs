@@ -1,5 +1,5 @@
-use crate::cpu::Cpu;
-impl Cpu{
+use crate::cpu::{Cpu, CpuBus};
+impl<B: CpuBus> Cpu<B> {
     ///# `JMP` - Jump
     /// - Sets the program counter to the address specified by the operand.
     pub fn jmp(&mut self) {
@@ -9,25 +9,27 @@ impl Cpu{
     ///# `JSR` - Jump to Subroutine
     /// - The JSR instruction pushes the address (minus one) of the return point on to the stack and then sets the program counter to the target memory address.
     pub fn jsr(&mut self) {
-        let pc = self.pc.wrapping_sub(1);
-        let hi_byte = pc >> 8;
-        let lo_byte = pc & 0xFF;
-        let hi_byte = hi_byte as u8;
-        let lo_byte = lo_byte as u8;
+        // The 6502 pushes the return address between fetching the two halves of the
+        // target, so JSR reads its own operand instead of using an addressing mode
+        let lo_byte = self.fetch() as u16;
+        self.stack_dummy_read();
 
-        /* Push high byte first (little endian)*/
-        self.push(hi_byte);
+        /* Push high byte first (little endian), PC points at the last byte of the instruction */
+        self.push((self.pc >> 8) as u8);
         /* Push the low byte */
-        self.push(lo_byte);
-        self.pc = self.addrabs;
-
+        self.push((self.pc & 0xFF) as u8);
+        let hi_byte = self.cpu_read(self.pc, false) as u16;
+        self.pc = (hi_byte << 8) | lo_byte;
     }
     ///RTS - Return from Subroutine
     /// - The RTS instruction is used at the end of a subroutine to return to the calling routine. It pulls the program counter (minus one) from the stack.
     pub fn rts(&mut self) {
+        self.stack_dummy_read();
         let lo_byte = self.pop() as u16;
         let hi_byte = self.pop() as u16;
         self.pc = (hi_byte << 8) | lo_byte;
+        // One more cycle to increment the pulled address past the JSR
+        self.cpu_read(self.pc, false);
         self.pc = self.pc.wrapping_add(1);
     }
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
-use crate::cpu::Cpu;
+use crate::cpu::{Cpu, CpuBus};
 use crate::cpu::Flags;
-impl Cpu{
+impl<B: CpuBus> Cpu<B> {
     ///# `LDA` - Load Accumulator
     /// - A,Z,N = M
     /// - Loads a byte of memory into the accumulator setting the zero and negative flags as appropriate.
@@ -1,6 +1,6 @@
-use crate::cpu::Cpu;
+use crate::cpu::{Cpu, CpuBus};
 use crate::cpu::Flags;
-impl Cpu{
+impl<B: CpuBus> Cpu<B> {
     ///# `AND` - Logical AND
     /// A,Z,N = A&M
     /// A logical AND is performed, bit by bit, on the accumulator contents using the contents of a byte of memory.
@@ -1,6 +1,6 @@
-use crate::cpu::Cpu;
+use crate::cpu::{Cpu, CpuBus};
 use crate::cpu::Flags;
-impl Cpu{
+impl<B: CpuBus> Cpu<B> {
     ///# `TAX` - Transfer Accumulator to X
     /// - X = A
     /// - Copies the current contents of the accumulator into the X register and sets the zero and negative flags as appropriate.
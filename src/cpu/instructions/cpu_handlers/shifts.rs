@@ -1,6 +1,6 @@
-use crate::cpu::Cpu;
+use crate::cpu::{Cpu, CpuBus};
 use crate::cpu::Flags;
-impl Cpu {
+impl<B: CpuBus> Cpu<B> {
     fn asl_accumulator(&mut self) {
         self.flags.set(Flags::Carry, self.a & 0x80 != 0);
         self.a <<= 1;
@@ -9,7 +9,7 @@ impl Cpu {
     }
 
     fn asl_memory(&mut self) {
-        let mut immval = self.cpu_read(self.addrabs, false);
+        let mut immval = self.read_modify();
         self.flags.set(Flags::Carry, immval & 0x80 != 0);
         immval <<= 1;
         self.flags.set(Flags::Zero, immval == 0);
@@ -36,7 +36,7 @@ impl Cpu {
     }
 
     fn lsr_memory_helper(&mut self) {
-        let mut immval = self.cpu_read(self.addrabs, false);
+        let mut immval = self.read_modify();
         self.flags.set(Flags::Carry, immval & 0x01 != 0); // Set carry flag to bit 0
         immval >>= 1;
         self.flags.set(Flags::Zero, immval == 0);
@@ -70,7 +70,7 @@ impl Cpu {
 
     fn rol_memory(&mut self) {
         let bit = if self.flags.contains(Flags::Carry) { 1 } else { 0 };
-        let mut immval = self.cpu_read(self.addrabs, false);
+        let mut immval = self.read_modify();
         self.flags.set(Flags::Carry, immval & 0x80 != 0);
         immval <<= 1;
         immval |= bit;
@@ -104,7 +104,7 @@ impl Cpu {
 
     fn ror_memory(&mut self) {
         let mask = if self.flags.contains(Flags::Carry) { 0x80 } else { 0 };
-        let mut immval = self.cpu_read(self.addrabs, false);
+        let mut immval = self.read_modify();
         self.flags.set(Flags::Carry, immval & 0x01 != 0);
         immval >>= 1;
         immval |= mask;
@@ -1,6 +1,6 @@
-use crate::cpu::Cpu;
+use crate::cpu::{Cpu, CpuBus};
 use crate::cpu::Flags;
-impl Cpu{
+impl<B: CpuBus> Cpu<B> {
     ///# `TSX` - Transfer Stack Pointer to X
     /// - X = S
     /// - Copies the current contents of the stack register into the X register and sets the zero and negative flags as appropriate.
@@ -30,6 +30,7 @@ impl Cpu{
     ///# `PLA` - Pull Accumulator
     /// - Pulls an 8 bit value from the stack and into the accumulator. The zero and negative flags are set as appropriate.
     pub fn pla(&mut self) {
+        self.stack_dummy_read();
         self.a = self.pop();
         self.flags.set(Flags::Negative,self.a & 0x80 != 0);
         self.flags.set(Flags::Zero,self.a == 0);
@@ -40,6 +41,7 @@ impl Cpu{
         // When pulling flags from stack, the Break flag should be ignored
         // and retain its current value
         let current_break = self.flags.contains(Flags::Break);
+        self.stack_dummy_read();
         self.flags = Flags::from_bits_truncate(self.pop());
         self.flags.set(Flags::Unused, true);
         self.flags.set(Flags::Break, current_break);
@@ -1,11 +1,11 @@
-use crate::cpu::Cpu;
+use crate::cpu::{Cpu, CpuBus};
 use crate::cpu::Flags;
-impl Cpu {
+impl<B: CpuBus> Cpu<B> {
 
     pub fn brk(&mut self) {
-        // BRK pushes PC+2, but we need to increment by 1 here since
-        // the CPU will have already incremented PC by 1 when fetching this instruction
-        self.pc += 1;
+        // BRK pushes PC+2: the CPU already incremented PC when fetching this instruction,
+        // and the padding byte after it was read by the implied mode's dummy read
+        self.pc = self.pc.wrapping_add(1);
 
         // Push the program counter to the stack
         let hi_byte = (self.pc >> 8) as u8;
@@ -13,6 +13,9 @@ impl Cpu {
         self.push(hi_byte);
         self.push(lo_byte);
 
+        // An NMI that arrived by now hijacks the BRK: the vector is picked here
+        let vector = self.interrupt_vector();
+
         // Push the status register to the stack with B flag set
         // Note: The B flag should be set in the copy pushed to the stack
         let mut status_copy = Flags::from_bits_truncate(self.flags.bits());
@@ -23,18 +26,39 @@ impl Cpu {
         // Set the interrupt disable flag
         self.flags.set(Flags::IDisable, true); // Set I flag, not clear it
 
-        // Load the IRQ/BRK vector (0xFFFE-0xFFFF)
-        let lo_byte = self.cpu_read(0xFFFE, false) as u16;
-        let hi_byte = self.cpu_read(0xFFFF, false) as u16;
+        // Load the IRQ/BRK vector (0xFFFE-0xFFFF), or the NMI one when hijacked
+        let lo_byte = self.cpu_read(vector, false) as u16;
+        let hi_byte = self.cpu_read(vector + 1, false) as u16;
         self.pc = (hi_byte << 8) | lo_byte;
 
-        // BRK takes 7 cycles
-        self.cycles_left = 7;
+        // The first instruction of the handler always runs before the next interrupt
+        self.prev_nmi = false;
+    }
+
+    /// Picks the vector of an interrupt sequence once the return address is pushed.
+    /// A pending NMI wins and is consumed; otherwise the sequence uses the IRQ/BRK vector.
+    fn interrupt_vector(&mut self) -> u16 {
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            0xFFFA
+        } else {
+            0xFFFE
+        }
+    }
+
+    ///# `NOP` - No Operation
+    /// - The official NOP does nothing. The unofficial ones with an operand still read it,
+    ///   which takes the same cycles as a load and has the same side effects.
+    pub fn nop(&mut self) {
+        if self.opcode & 0x0F != 0x0A {
+            self.cpu_read(self.addrabs, false);
+        }
     }
 
     ///# `RTI` - Return from Interrupt
     /// - The RTI instruction is used at the end of an interrupt processing routine. It pulls the processor flags from the stack followed by the program counter.
     pub fn rti(&mut self) {
+        self.stack_dummy_read();
         self.flags = Flags::from_bits_truncate(self.pop());
         self.flags.set(Flags::Unused, true);
         self.flags.set(Flags::Break, false);
@@ -45,22 +69,32 @@ impl Cpu {
 
     }
 
-    ///# `nmi` - Non Maskable Interrupts
-    /// - NMI (Non-Maskable Interrupt) is the type of interrupt generated by the PPU when V-Blank occurs at the end of each frame.
+    ///# `interrupt` - Interrupt sequence
+    /// - Runs when an NMI or IRQ was pending at the second-to-last cycle of an instruction.
+    /// - NMI (Non-Maskable Interrupt) is generated by the PPU when V-Blank begins; IRQ by any
+    ///   source pulling the shared /IRQ line low, and only while the I flag is clear.
     /// - To facilitate a interrupt, we must follow the following steps
-    /// - Push the program counter and status register on to the stack.
+    /// - Push the program counter and status register (with the B flag clear) on to the stack.
     /// - Set the interrupt disable flag to prevent further interrupts.
-    /// - Load the address of the interrupt handling routine from the vector table into the program counter.
-    /// - Execute the interrupt handling routine(located at address $FFFA and $FFFB)
-    pub fn nmi(&mut self) {
+    /// - Load the address of the interrupt handling routine from the vector table into the
+    ///   program counter: $FFFA for NMI, $FFFE for IRQ.
+    /// - The vector is only picked after the program counter is pushed, so an NMI arriving
+    ///   during an IRQ sequence hijacks it and the IRQ is lost if its source let go by then.
+    pub fn interrupt(&mut self) {
+        // The interrupted opcode is fetched and thrown away, then fetched once more
+        self.cpu_read(self.pc, false);
+        self.cpu_read(self.pc, false);
+
         // Push the program counter to the stack
         let hi_byte = (self.pc >> 8) as u8;
         let lo_byte = (self.pc & 0xFF) as u8;
         self.push(hi_byte);
         self.push(lo_byte);
 
+        let vector = self.interrupt_vector();
+
         // Push the status register to the stack
-        // For NMI, the B flag should be clear in the copy pushed to the stack
+        // For NMI and IRQ, the B flag should be clear in the copy pushed to the stack
         let mut status_copy = Flags::from_bits_truncate(self.flags.bits());
         status_copy.set(Flags::Break, false);
         status_copy.set(Flags::Unused, true);
@@ -69,64 +103,31 @@ impl Cpu {
         // Set the interrupt disable flag
         self.flags.set(Flags::IDisable, true);
 
-        // Load the NMI vector address (0xFFFA-0xFFFB)
-        let lo_byte = self.cpu_read(0xFFFA, false) as u16;
-        let hi_byte = self.cpu_read(0xFFFB, false) as u16;
+        let lo_byte = self.cpu_read(vector, false) as u16;
+        let hi_byte = self.cpu_read(vector + 1, false) as u16;
         self.pc = (hi_byte << 8) | lo_byte;
-
-        // NMI takes 7 cycles
-        self.cycles_left = 7;
     }
 
     pub fn reset(&mut self) {
         self.a = 0;
         self.x = 0;
         self.y = 0;
-        let lo_byte = self.cpu_read(0xFFFC, false) as u16;
-        let hi_byte = self.cpu_read(0xFFFD, false) as u16;
-        self.pc = (hi_byte << 8) | lo_byte;
-        self.flags = Flags::empty();
-        self.flags.set(Flags::Unused, true);
-        self.cycles_left = 8;
         self.total_cycles = 0;
-    }
-
-    /// # `irq` - Interrupt Request
-    /// - IRQ (Interrupt Request) is a type of interrupt that can be masked by the I flag in the status register.
-    /// - When an IRQ occurs and the I flag is clear (0), the CPU will:
-    ///   1. Push the program counter to the stack
-    ///   2. Push the status register to the stack (with the B flag clear)
-    ///   3. Set the I flag to prevent further interrupts
-    ///   4. Load the address of the interrupt handler from the vector table ($FFFE-$FFFF)
-    /// - If the I flag is set (1), the IRQ will be ignored.
-    pub fn irq(&mut self) {
-        // If the interrupt disable flag is set, ignore the IRQ
-        if self.flags.contains(Flags::IDisable) {
-            return;
+        self.nmi_pending = false;
+        // Reset runs the interrupt sequence with the stack writes turned into reads,
+        // so the stack pointer still moves down by three
+        self.cpu_read(self.pc, false);
+        self.cpu_read(self.pc, false);
+        for _ in 0..3 {
+            self.stack_dummy_read();
+            self.sp = self.sp.wrapping_sub(1);
         }
-        self.irqset = true;
-        // Push the program counter to the stack
-        let hi_byte = (self.pc >> 8) as u8;
-        let lo_byte = (self.pc & 0xFF) as u8;
-        self.push(hi_byte);
-        self.push(lo_byte);
-
-        // Push the status register to the stack
-        // For IRQ, the B flag should be clear in the copy pushed to the stack
-        let mut status_copy = Flags::from_bits_truncate(self.flags.bits());
-        status_copy.set(Flags::Break, false);
-        status_copy.set(Flags::Unused, true);
-        self.push(status_copy.bits());
-
-        // Set the interrupt disable flag
+        // Like any interrupt, reset sets the I flag before reading the vector
+        self.flags = Flags::empty();
+        self.flags.set(Flags::Unused, true);
         self.flags.set(Flags::IDisable, true);
-
-        // Load the IRQ vector address (0xFFFE-0xFFFF)
-        let lo_byte = self.cpu_read(0xFFFE, false) as u16;
-        let hi_byte = self.cpu_read(0xFFFF, false) as u16;
+        let lo_byte = self.cpu_read(0xFFFC, false) as u16;
+        let hi_byte = self.cpu_read(0xFFFD, false) as u16;
         self.pc = (hi_byte << 8) | lo_byte;
-        // IRQ takes 7 cycles
-        self.cycles_left = 7;
-        
     }
 }
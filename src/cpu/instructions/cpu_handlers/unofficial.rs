@@ -13,11 +13,11 @@
 // (indirect,X)	SRE (oper,X)	43	2	8
 // (indirect),Y	SRE (oper),Y	53	2	8
 
-use crate::cpu::{Cpu, Flags};
+use crate::cpu::{Cpu, CpuBus, Flags};
 
-impl Cpu {
+impl<B: CpuBus> Cpu<B> {
     pub fn sre(&mut self) {
-        let mut immval = self.cpu_read(self.addrabs, false);
+        let mut immval = self.read_modify();
         self.flags.set(Flags::Carry, immval & 0x01 != 0); // Set carry flag to bit 0
         immval >>= 1;
 
@@ -58,7 +58,7 @@ impl Cpu {
         } else {
             0
         };
-        let mut immval = self.cpu_read(self.addrabs, false);
+        let mut immval = self.read_modify();
         self.flags.set(Flags::Carry, immval & 0x01 != 0);
         immval >>= 1;
         immval |= mask;
@@ -98,13 +98,12 @@ impl Cpu {
     }
 
     pub fn dcp(&mut self) {
-        let immval = self.cpu_read(self.addrabs, false);
+        let immval = self.read_modify();
         let immval = immval.wrapping_sub(1);
         self.flags.set(Flags::Zero, immval == 0);
         self.flags.set(Flags::Negative, immval & 0x80 != 0);
         self.cpu_write(self.addrabs, immval);
 
-        let immval = self.cpu_read(self.addrabs, true);
         let temp = self.a.wrapping_sub(immval);
         self.flags.set(Flags::Carry, self.a >= immval);
         self.flags.set(Flags::Zero, temp == 0);
@@ -122,7 +121,7 @@ impl Cpu {
         } else {
             0
         };
-        let mut immval = self.cpu_read(self.addrabs, false);
+        let mut immval = self.read_modify();
         self.flags.set(Flags::Carry, immval & 0x80 != 0);
         immval <<= 1;
         immval |= bit;
@@ -135,7 +134,7 @@ impl Cpu {
     }
 
     pub fn slo(&mut self) {
-        let mut immval = self.cpu_read(self.addrabs, false);
+        let mut immval = self.read_modify();
         self.flags.set(Flags::Carry, immval & 0x80 != 0);
         immval <<= 1;
         self.cpu_write(self.addrabs, immval);
@@ -145,7 +144,7 @@ impl Cpu {
     }
 
     pub fn isc(&mut self) {
-        let immval = self.cpu_read(self.addrabs, false);
+        let immval = self.read_modify();
         let immval = immval.wrapping_add(1);
         self.flags.set(Flags::Zero, immval == 0);
         self.flags.set(Flags::Negative, immval & 0x80 != 0);
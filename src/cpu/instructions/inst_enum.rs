@@ -42,4 +42,32 @@ pub enum AddressMode {
     Indirect,
     IDX,
     IDY,
-}
\ No newline at end of file
+}
+/// How an instruction uses the memory its addressing mode points at. This decides the
+/// dummy cycles of the indexed modes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Only reads the operand, or does not touch memory at all.
+    Read,
+    /// Only writes.
+    Write,
+    /// Reads, writes the old value back, then writes the new one.
+    ReadModifyWrite,
+}
+
+impl Instruction {
+    pub fn access(&self) -> Access {
+        match self {
+            Instruction::STA | Instruction::STX | Instruction::STY | Instruction::SAX
+            | Instruction::SHA | Instruction::SHX | Instruction::SHY | Instruction::TAS => {
+                Access::Write
+            }
+            Instruction::ASL | Instruction::LSR | Instruction::ROL | Instruction::ROR
+            | Instruction::INC | Instruction::DEC | Instruction::SLO | Instruction::SRE
+            | Instruction::RLA | Instruction::RRA | Instruction::ISC | Instruction::DCP => {
+                Access::ReadModifyWrite
+            }
+            _ => Access::Read,
+        }
+    }
+}
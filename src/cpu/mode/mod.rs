@@ -1,38 +1,38 @@
 //! # Addressing Modes
 //! This file contains the implementations for all the addressing modes of the 6502
-use super::Cpu;
+use super::instructions::inst_enum::Access;
+use super::{Cpu, CpuBus};
 
-impl Cpu {
+impl<B: CpuBus> Cpu<B> {
     ///# Fetch
     /// This is a helper function for the addressing modes.
-    /// ## Unsafe
-    /// This function does use unsafe rust, hence why it's covered in a function.
-    fn fetch(&mut self) -> u8{
-        let result = unsafe{
-            (*self.bus.unwrap()).cpu_read(self.pc, false)
-        };
+    /// Reads the byte at the program counter and advances it.
+    pub(crate) fn fetch(&mut self) -> u8{
+        let result = self.cpu_read(self.pc, false);
         self.pc = self.pc.wrapping_add(1);
         result
     }
     ///# Implicit
     /// For many 6502 instructions the source and destination of the information to be manipulated is implied directly by the function of the instruction itself and no further operand needs to be specified. Operations like 'Clear Carry Flag' (CLC) and 'Return from Subroutine' (RTS) are implicit.
     pub fn implicit(&mut self) {
-        /* Nothing to do here */
+        // The 6502 reads the byte after the opcode anyway and ignores it
+        self.cpu_read(self.pc, false);
     }
 
     ///# Accumulator
     /// Some instructions have an option to operate directly upon the accumulator. The programmer specifies this by using a special operand value, 'A'. For example:
-    /// ```
+    /// ```text
     ///  LSR A           ;Logical shift right one bit
     ///  ROR A           ;Rotate right one bit
     /// ```
     pub fn accumulator(&mut self) {
-        /* Nothing to do here */
+        // The 6502 reads the byte after the opcode anyway and ignores it
+        self.cpu_read(self.pc, false);
     }
 
     ///# Immediate
     /// Immediate addressing allows the programmer to directly specify an 8 bit constant within the instruction. It is indicated by a '#' symbol followed by an numeric expression. For example:
-    /// ```
+    /// ```text
     /// LDA #10         ;Load 10 ($0A) into the accumulator
     /// LDX #LO LABEL   ;Load the LSB of a 16 bit address into X
     /// LDY #HI LABEL   ;Load the MSB of a 16 bit address into Y
@@ -58,6 +58,8 @@ impl Cpu {
     /// The address calculation wraps around if the sum of the base address and the register exceed $FF. If we repeat the last example but with $FF in the X register then the accumulator will be loaded from $007F (e.g. $80 + $FF => $7F) and not $017F.
     pub fn zeropagex(&mut self) {
         let byte = self.fetch() as u16;
+        // The unindexed address is read while X is added
+        self.cpu_read(byte, false);
         let byte = byte + (self.x as u16);
         self.addrabs = byte & 0xFF;
     }
@@ -66,6 +68,8 @@ impl Cpu {
     /// The address to be accessed by an instruction using indexed zero page addressing is calculated by taking the 8 bit zero page address from the instruction and adding the current value of the Y register to it. This mode can only be used with the LDX and STX instructions.
     pub fn zeropagey(&mut self) {
         let byte = self.fetch() as u16;
+        // The unindexed address is read while Y is added
+        self.cpu_read(byte, false);
         let byte = byte + (self.y as u16);
         self.addrabs = byte & 0xFF;
     }
@@ -83,7 +87,7 @@ impl Cpu {
     }
     ///# Absolute
     /// Instructions using absolute addressing contain a full 16 bit address to identify the target location.
-    /// ```
+    /// ```text
     /// JMP $1234       ;Jump to location $1234
     /// JSR WIBBLE      ;Call subroutine WIBBLE
     /// ```
@@ -95,33 +99,27 @@ impl Cpu {
 
     ///# Absolute,X
     /// The address to be accessed by an instruction using X register indexed absolute addressing is computed by taking the 16 bit address from the instruction and added the contents of the X register. For example if X contains $92 then an STA $2000,X instruction will store the accumulator at $2092 (e.g. $2000 + $92).
-    /// ```
+    /// ```text
     /// STA $3000,X     ;Store accumulator between $3000 and $30FF
     /// ROR CRC,X       ;Rotate right one bit
     /// ```
-    pub fn absolutex(&mut self) {
+    pub fn absolutex(&mut self, access: Access) {
         let lobyte = self.fetch() as u16;
         let hibyte = self.fetch() as u16;
         let temp = (hibyte << 8) | lobyte;
-        self.addrabs = temp.wrapping_add(self.x as u16);
-        if self.addrabs & 0xFF00 != temp & 0xFF00{
-            self.cycles_left = self.cycles_left.wrapping_add(1);
-        }
+        self.addrabs = self.index(temp, self.x, access);
     }
     ///# Absolute,Y
     /// The Y register indexed absolute addressing mode is the same as the previous mode only with the contents of the Y register added to the 16 bit address from the instruction.
-    /// ```
+    /// ```text
     /// AND $4000,Y     ;Perform a logical AND with a byte of memory
     /// STA MEM,Y       ;Store accumulator in memory
     /// ```
-    pub fn absolutey(&mut self) {
+    pub fn absolutey(&mut self, access: Access) {
         let lobyte = self.fetch() as u16;
         let hibyte = self.fetch() as u16;
         let temp = (hibyte << 8) | lobyte;
-        self.addrabs = temp.wrapping_add(self.y as u16);
-        if self.addrabs & 0xFF00 != temp & 0xFF00{
-            self.cycles_left = self.cycles_left.wrapping_add(1);
-        }
+        self.addrabs = self.index(temp, self.y, access);
     }
 
     ///Indirect
@@ -129,7 +127,7 @@ impl Cpu {
     /// JMP is the only 6502 instruction to support indirection. The instruction contains a 16 bit address which identifies the location of the least significant byte of another 16 bit memory address which is the real target of the instruction.
     /// 
     /// For example if location $0120 contains $FC and location $0121 contains $BA then the instruction JMP ($0120) will cause the next instruction execution to occur at $BAFC (e.g. the contents of $0120 and $0121).
-    /// ```
+    /// ```text
     /// JMP ($FFFC)     ;Force a power on reset
     /// JMP (TARGET)    ;Jump via a labelled memory area
     /// ```
@@ -156,6 +154,8 @@ impl Cpu {
     /// Indexed indirect addressing is normally used in conjunction with a table of address held on zero page. The address of the table is taken from the instruction and the X register added to it (with zero page wrap around) to give the location of the least significant byte of the target address.
     pub fn idx(&mut self) {
         let address = self.fetch() as u16;
+        // The pointer is read while X is added
+        self.cpu_read(address, false);
         let address = (address + (self.x as u16)) & 0xFF;
         let lobyte = self.cpu_read(address, false) as u16;
         let address = address + 1;
@@ -166,15 +166,25 @@ impl Cpu {
 
     ///# Indirect Indexed
     /// Indirect indexed (also known as post-indexed) addressing takes a single operand which gives the zero page address of the least significant byte of a 16-bit address which is then added to the Y register to give the target address. For example, if the operand is bb, 00bb is xx and 00bb + 1 is yy, then the data can be found at yyxx. An example of this addressing mode is AND ($12),Y.
-    pub fn idy(&mut self) {
+    pub fn idy(&mut self, access: Access) {
         let address = self.fetch() as u16;
         let lo_byte = self.cpu_read(address & 0xFF, false) as u16;
         let hi_byte = self.cpu_read((address + 1) & 0xFF, false) as u16;
         let address = (hi_byte << 8) | lo_byte;
-        let address = address.wrapping_add(self.y as u16);
-        if address & 0xFF00 != hi_byte << 8{
-            self.cycles_left = self.cycles_left.wrapping_add(1);
+        self.addrabs = self.index(address, self.y, access);
+    }
+
+    ///# Index
+    /// This is a helper function for the indexed absolute modes.
+    /// The 6502 adds the index to the low byte first and reads from that address while it
+    /// fixes up the high byte, so the read lands on the wrong page when a page is crossed.
+    /// A read instruction that did not cross a page uses that read as its operand; in every
+    /// other case it is a dummy read and the instruction takes one more cycle.
+    fn index(&mut self, base: u16, index: u8, access: Access) -> u16 {
+        let address = base.wrapping_add(index as u16);
+        if address & 0xFF00 != base & 0xFF00 || access != Access::Read {
+            self.cpu_read((base & 0xFF00) | (address & 0x00FF), false);
         }
-        self.addrabs = address;
+        address
     }
 }
@@ -0,0 +1,81 @@
+//! # Hash
+//! FNV-1a, used to fingerprint frames and memory so runs can be compared without
+//! storing the data itself. Not cryptographic, only meant to spot differences.
+//! Also home to the seeded generator used to fill RAM in deterministic runs.
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Running FNV-1a 64-bit hash.
+#[derive(Debug, Clone, Copy)]
+pub struct Fnv1a {
+    state: u64,
+}
+
+impl Fnv1a {
+    pub fn new() -> Self {
+        Self { state: FNV_OFFSET }
+    }
+
+    /// Feeds `bytes` into the hash.
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= *byte as u64;
+            self.state = self.state.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    /// Feeds each pixel of a framebuffer in little-endian byte order.
+    pub fn write_pixels(&mut self, pixels: &[u32]) {
+        for pixel in pixels {
+            self.write(&pixel.to_le_bytes());
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.state
+    }
+}
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hashes `bytes` in one go.
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// Hashes a framebuffer of 0xRRGGBB pixels.
+pub fn hash_pixels(pixels: &[u32]) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.write_pixels(pixels);
+    hasher.finish()
+}
+
+/// SplitMix64 step: advances `state` and returns the next pseudo random value.
+/// Implemented here rather than taken from `rand` so the sequence never changes between
+/// dependency versions, which would break recorded hash logs.
+pub fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod hash_tests {
+    use super::*;
+
+    #[test]
+    pub fn known_vectors() {
+        assert_eq!(hash_bytes(b""), 0xcbf2_9ce4_8422_2325, "empty input, FAILED!");
+        assert_eq!(hash_bytes(b"a"), 0xaf63_dc4c_8601_ec8c, "single byte, FAILED!");
+    }
+}
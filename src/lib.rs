@@ -0,0 +1,20 @@
+//! # NES Emulator
+//! Library crate for the emulator core. The console is assembled by [`Nes`], which wires the
+//! CPU, PPU, APU, cartridge and controllers together so frontends, tools and tests can embed
+//! the emulator without duplicating the setup in `main.rs`.
+
+pub mod apu;
+pub mod bus;
+pub mod cartridge;
+pub mod controller;
+pub mod cpu;
+pub mod hash;
+pub mod movie;
+pub mod nes;
+pub mod pacer;
+pub mod ppu;
+pub mod rewind;
+pub mod states;
+
+pub use controller::Buttons;
+pub use nes::Nes;
@@ -1,31 +1,110 @@
-use apu::Apu;
-use args::Args;
-use bus::Bus;
-use cartridge::Cartridge;
+use args::{Args, AudioOutput};
 use clap::Parser;
-use cpu::Cpu;
 use device_query::Keycode;
 use device_query::{DeviceQuery, DeviceState};
+use emulator::nes::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use emulator::apu::backend::{AudioBackend, AudioConfig, FileBackend, NullBackend, RodioBackend};
+use emulator::apu::Channel;
+use emulator::movie::Movie;
+use emulator::pacer::{FramePacer, NTSC_FRAME_RATE};
+use emulator::ppu::frame::Frame;
+use emulator::{Buttons, Nes};
 use flexi_logger::{Logger, WriteMode};
 use minifb::Scale;
 use minifb::{Window, WindowOptions};
-use ppu::{frame::Frame, Ppu};
-use std::cell::RefCell;
 use std::error::Error;
-use std::sync::{Arc, Condvar, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
-use std::{rc::Rc, time::Instant};
+use std::time::Instant;
 
-use crate::controller::Buttons;
-
-mod apu;
 mod args;
-mod bus;
-mod cartridge;
-mod controller;
-mod cpu;
-mod ppu;
+
+/// Frames between two rewind snapshots.
+const REWIND_INTERVAL: u32 = 2;
+/// How far back rewind can go.
+const REWIND_SECONDS: usize = 20;
+
+/// Number keys 1-6 select the channels in the order of `Channel::ALL`.
+const CHANNEL_KEYS: [Keycode; 6] = [
+    Keycode::Key1,
+    Keycode::Key2,
+    Keycode::Key3,
+    Keycode::Key4,
+    Keycode::Key5,
+    Keycode::Key6,
+];
+/// Bits of the channel hotkey state after the six channel keys.
+const CHANNEL_RESET: u16 = 1 << 6;
+const CHANNEL_SOLO: u16 = 1 << 7;
+const CHANNEL_QUIETER: u16 = 1 << 8;
+const CHANNEL_LOUDER: u16 = 1 << 9;
+/// Volume change per key press.
+const VOLUME_STEP: f32 = 0.1;
+/// Stereo layout used unless `--pan` is given: the two pulses half left and half right.
+const DEFAULT_PANS: [(Channel, f32); 2] = [(Channel::Pulse1, -0.5), (Channel::Pulse2, 0.5)];
+
+/// A channel key toggles the channel's mute, with Shift its solo, and with Ctrl or Alt
+/// lowers or raises its volume. 0 resets all channels. Only newly pressed keys act.
+fn channel_hotkeys(nes: &mut Nes, pressed: u16, held: u16) {
+    if pressed & !held & CHANNEL_RESET != 0 {
+        nes.reset_channel_controls();
+        println!("all channels reset");
+    }
+    for (bit, channel) in Channel::ALL.into_iter().enumerate() {
+        if pressed & !held & (1 << bit) == 0 {
+            continue;
+        }
+        let name = channel.name();
+        if pressed & CHANNEL_SOLO != 0 {
+            let solo = !nes.is_channel_solo(channel);
+            nes.set_channel_solo(channel, solo);
+            println!("{} {}", name, if solo { "soloed" } else { "unsoloed" });
+        } else if pressed & (CHANNEL_QUIETER | CHANNEL_LOUDER) != 0 {
+            let step = if pressed & CHANNEL_LOUDER != 0 {
+                VOLUME_STEP
+            } else {
+                -VOLUME_STEP
+            };
+            nes.set_channel_volume(channel, nes.channel_volume(channel) + step);
+            println!("{} volume {:.0}%", name, nes.channel_volume(channel) * 100.0);
+        } else {
+            let muted = !nes.is_channel_muted(channel);
+            nes.set_channel_muted(channel, muted);
+            println!("{} {}", name, if muted { "muted" } else { "unmuted" });
+        }
+    }
+}
+
+/// Builds the console with the audio output picked on the command line. A missing audio
+/// device is not fatal: the game runs silently, paced by the wall clock.
+fn open_console(args: &Args) -> Nes {
+    let config = AudioConfig {
+        sample_rate: args.sample_rate,
+        latency_ms: args.audio_latency,
+        stereo: args.stereo,
+    };
+    let backend: Box<dyn AudioBackend> = match args.audio {
+        AudioOutput::Device => Box::new(RodioBackend::default()),
+        AudioOutput::Null => Box::new(NullBackend),
+        AudioOutput::File => Box::new(FileBackend::new(&args.audio_file)),
+    };
+    let mut nes = Nes::with_audio(&args.rom, backend, config).unwrap_or_else(|err| {
+        eprintln!("could not start audio output, running without sound: {}", err);
+        Nes::with_audio(&args.rom, Box::new(NullBackend), config)
+            .expect("the null audio backend cannot fail")
+    });
+    let pans = if args.pan.is_empty() {
+        &DEFAULT_PANS[..]
+    } else {
+        &args.pan[..]
+    };
+    for (channel, pan) in pans {
+        nes.set_channel_pan(*channel, *pan);
+    }
+    nes
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
     Logger::try_with_env()
@@ -38,28 +117,16 @@ fn main() -> Result<(), Box<dyn Error>> {
     let debugmode = !vec.debug;
     let byte = Arc::new(Mutex::new(0u8));
     /* Initialize peripherals */
-    let cartridge = Rc::new(RefCell::new(Cartridge::new(&vec.rom)));
-    let mut cpu = Cpu::new();
-    let mut game_frame = if debugmode {
-        Frame::new(512, 240)
-    } else {
-        Frame::new(255, 240)
-    };
-    let ppu = Rc::new(RefCell::new(Ppu::new(Rc::clone(&cartridge))));
-    let mut bus = Bus::new(Rc::clone(&ppu));
-    let controller = Rc::new(RefCell::new(controller::Controller::new()));
-    let controller2 = Rc::new(RefCell::new(controller::Controller::new()));
-    let activate = Arc::new((Mutex::new(false), Condvar::new()));
-    let apu: Rc<RefCell<Apu>> = Rc::new(RefCell::new(Apu::new(activate.clone())));
-    bus.link_cartridge(Rc::clone(&cartridge));
-    bus.link_apu(Rc::clone(&apu));
-    bus.link_controller1(Rc::clone(&controller));
-    bus.link_controller2(Rc::clone(&controller2));
-
-    // Connect APU to the bus
-
-    cpu.linkbus(&mut bus);
-    cpu.reset();
+    let mut nes = open_console(&vec);
+    nes.set_audio_filter(vec.audio_filter);
+    nes.set_pal_noise(vec.pal_noise);
+    nes.enable_rewind(REWIND_INTERVAL, REWIND_SECONDS * 60 / REWIND_INTERVAL as usize);
+    if let Some(path) = &vec.play {
+        nes.play_movie(Movie::load(path)?)?;
+    } else if vec.record.is_some() {
+        nes.record_movie(true);
+    }
+    let mut debug_frame = Frame::new(512, 240);
 
     let windowoption = if debugmode {
         WindowOptions {
@@ -79,9 +146,9 @@ fn main() -> Result<(), Box<dyn Error>> {
     let mut frame_count = 0;
     let saverom = Arc::new(Mutex::new(false));
     let mut window = if debugmode {
-        Window::new("NES Emulator - FPS: ", 512, 240, windowoption)
+        Window::new("NES Emulator - FPS: ", 512, SCREEN_HEIGHT, windowoption)
     } else {
-        Window::new("NES Emulator - FPS: ", 255, 240, windowoption)
+        Window::new("NES Emulator - FPS: ", SCREEN_WIDTH, SCREEN_HEIGHT, windowoption)
     }?;
     // window.set_target_fps(59);
     let button_state = Arc::clone(&byte);
@@ -97,6 +164,30 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let turbob = Arc::new(Mutex::new(false));
     let turbobclone = turbob.clone();
+
+    let savestate = Arc::new(Mutex::new(false));
+    let savestateclone = savestate.clone();
+    let loadstate = Arc::new(Mutex::new(false));
+    let loadstateclone = loadstate.clone();
+    let rewind = Arc::new(Mutex::new(false));
+    let rewindclone = rewind.clone();
+    let record_audio = Arc::new(Mutex::new(false));
+    let record_audio_clone = record_audio.clone();
+    let vgm_loop = Arc::new(Mutex::new(false));
+    let vgm_loop_clone = vgm_loop.clone();
+    let channel_keys = Arc::new(Mutex::new(0u16));
+    let channel_keys_clone = channel_keys.clone();
+    let state_path = Path::new(&vec.rom).with_extension("state");
+    let recording_path = match &vec.record_audio {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(&vec.rom).with_extension("wav"),
+    };
+    if vec.record_audio.is_some() {
+        nes.start_audio_recording(&recording_path, vec.stems)?;
+    }
+    if let Some(path) = &vec.vgm {
+        nes.start_vgm_log(path)?;
+    }
     let thread = thread::spawn(move || {
         let device_state = DeviceState::new();
         while *game_running.lock().unwrap() {
@@ -133,65 +224,153 @@ fn main() -> Result<(), Box<dyn Error>> {
             *restartclone.lock().unwrap() =
                 keys.contains(&Keycode::Command) && keys.contains(&Keycode::O);
             *clonesave.lock().unwrap() = keys.contains(&Keycode::Semicolon);
+            *savestateclone.lock().unwrap() = keys.contains(&Keycode::F5);
+            *loadstateclone.lock().unwrap() = keys.contains(&Keycode::F8);
+            *rewindclone.lock().unwrap() = keys.contains(&Keycode::Backspace);
+            *record_audio_clone.lock().unwrap() = keys.contains(&Keycode::F9);
+            *vgm_loop_clone.lock().unwrap() = keys.contains(&Keycode::F10);
+            let mut channel_state = 0u16;
+            for (bit, key) in CHANNEL_KEYS.iter().enumerate() {
+                if keys.contains(key) {
+                    channel_state |= 1 << bit;
+                }
+            }
+            if keys.contains(&Keycode::Key0) {
+                channel_state |= CHANNEL_RESET;
+            }
+            if keys.contains(&Keycode::LShift) || keys.contains(&Keycode::RShift) {
+                channel_state |= CHANNEL_SOLO;
+            }
+            if keys.contains(&Keycode::LControl) || keys.contains(&Keycode::RControl) {
+                channel_state |= CHANNEL_QUIETER;
+            }
+            if keys.contains(&Keycode::LAlt) || keys.contains(&Keycode::RAlt) {
+                channel_state |= CHANNEL_LOUDER;
+            }
+            *channel_keys_clone.lock().unwrap() = channel_state;
             *button_state.lock().unwrap() = output;
         }
     });
 
+    let mut pacer = FramePacer::new(NTSC_FRAME_RATE);
+    let mut turbo_phase = false;
+    let mut savestate_held = false;
+    let mut loadstate_held = false;
+    let mut record_audio_held = false;
+    let mut vgm_loop_held = false;
+    let mut mute_held = false;
+    let mut channel_keys_held = 0u16;
+    let mut rewind_phase = 0;
     while *gamecont.lock().unwrap() {
         *gamecont.lock().unwrap() = window.is_open();
         if *saverom.lock().unwrap() {
-            cartridge.borrow_mut().savestate();
+            if let Err(err) = nes.flush_sram() {
+                eprintln!("could not write save file: {}", err);
+            }
         }
         if *restart.lock().unwrap() {
-            cpu.reset();
+            nes.reset();
         }
-        if *mute.lock().unwrap() {
-            apu.borrow_mut().toggle_sound();
+        let mute_pressed = *mute.lock().unwrap();
+        if mute_pressed && !mute_held {
+            nes.toggle_sound();
         }
-        // Clock components
-        for _ in 0..3 {
-            ppu.borrow_mut().clock(&mut game_frame);
+        mute_held = mute_pressed;
+        let channel_pressed = *channel_keys.lock().unwrap();
+        channel_hotkeys(&mut nes, channel_pressed, channel_keys_held);
+        channel_keys_held = channel_pressed;
+
+        // Save states only fire on the key press, not every frame the key is held
+        let save_pressed = *savestate.lock().unwrap();
+        if save_pressed && !savestate_held {
+            match nes.save_state_file(&state_path) {
+                Ok(()) => println!("saved state to {}", state_path.display()),
+                Err(err) => eprintln!("could not save state: {}", err),
+            }
         }
-        let _cycles_left = cpu.clock();
-        controller
-            .borrow_mut()
-            ._set_reg_value(*byte.lock().unwrap());
-        if cartridge.borrow_mut().irq() {
-            cartridge.borrow_mut().irq_clear();
-            cpu.irq();
+        savestate_held = save_pressed;
+        let load_pressed = *loadstate.lock().unwrap();
+        if load_pressed && !loadstate_held {
+            match nes.load_state_file(&state_path) {
+                Ok(()) => println!("loaded state from {}", state_path.display()),
+                Err(err) => eprintln!("could not load state: {}", err),
+            }
         }
-        if *turboa.lock().unwrap(){
-
+        loadstate_held = load_pressed;
+        let record_pressed = *record_audio.lock().unwrap();
+        if record_pressed && !record_audio_held {
+            if nes.is_recording_audio() {
+                match nes.stop_audio_recording() {
+                    Ok(()) => println!("saved audio to {}", recording_path.display()),
+                    Err(err) => eprintln!("could not save audio: {}", err),
+                }
+            } else {
+                match nes.start_audio_recording(&recording_path, vec.stems) {
+                    Ok(()) => println!("recording audio to {}", recording_path.display()),
+                    Err(err) => eprintln!("could not record audio: {}", err),
+                }
+            }
         }
-        if *turbob.lock().unwrap(){
-                controller.borrow_mut().set_button(Buttons::B, bus.get_controller1_state());
+        record_audio_held = record_pressed;
+        let loop_pressed = *vgm_loop.lock().unwrap();
+        if loop_pressed && !vgm_loop_held && nes.is_logging_vgm() {
+            nes.mark_vgm_loop();
+            println!("marked the VGM loop point");
         }
+        vgm_loop_held = loop_pressed;
 
-        if ppu.borrow_mut().get_nmi() {
-            cpu.nmi();
+        // Turbo buttons alternate between pressed and released every frame
+        let mut buttons = Buttons::from_bits_truncate(*byte.lock().unwrap());
+        turbo_phase = !turbo_phase;
+        if *turboa.lock().unwrap() {
+            buttons.set(Buttons::A, turbo_phase);
+        }
+        if *turbob.lock().unwrap() {
+            buttons.set(Buttons::B, turbo_phase);
+        }
+        nes.set_input(0, buttons);
 
-            frame_count += 1;
-            let elapsed = last_time.elapsed();
-            if elapsed >= Duration::from_secs(1) {
-                let fps = frame_count;
-                window.set_title(&format!("NES Emulator - FPS: {}", fps));
-                frame_count = 0;
-                last_time = Instant::now();
-            }
-            let (lock, cvar) = &*activate;
-            let mut go = lock.lock().unwrap();
-            while !*go{
-                go = cvar.wait(go).unwrap();
-            }
-            *go = false;
-            if debugmode {
-                ppu.borrow_mut().get_pattern_table(&mut game_frame);
-                window.update_with_buffer(game_frame.get_buf().as_slice(), 512, 240)?;
-            } else {
-                window.update_with_buffer(game_frame.get_buf().as_slice(), 255, 240)?;
+        // Holding backspace plays the game backwards at normal speed: one snapshot is
+        // restored every REWIND_INTERVAL frames instead of emulating
+        if *rewind.lock().unwrap() {
+            if rewind_phase == 0 {
+                nes.rewind();
             }
+            rewind_phase = (rewind_phase + 1) % REWIND_INTERVAL;
+        } else {
+            rewind_phase = 0;
+            nes.step_frame();
+        }
+
+        frame_count += 1;
+        let elapsed = last_time.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            let fps = frame_count;
+            window.set_title(&format!("NES Emulator - FPS: {}", fps));
+            frame_count = 0;
+            last_time = Instant::now();
+        }
+        pacer.wait(nes.audio_fill());
+        if debugmode {
+            debug_frame.copy_from(nes.frame());
+            nes.render_pattern_table(&mut debug_frame);
+            window.update_with_buffer(debug_frame.get_buf().as_slice(), 512, SCREEN_HEIGHT)?;
+        } else {
+            window.update_with_buffer(nes.framebuffer(), SCREEN_WIDTH, SCREEN_HEIGHT)?;
         }
     }
     thread.join().unwrap();
+    if nes.is_recording_audio() {
+        nes.stop_audio_recording()?;
+        println!("saved audio to {}", recording_path.display());
+    }
+    if let Some(path) = &vec.vgm {
+        nes.stop_vgm_log()?;
+        println!("saved VGM log to {}", path);
+    }
+    if let (Some(path), Some(movie)) = (&vec.record, nes.stop_movie()) {
+        movie.save(path)?;
+        println!("saved {} frame movie to {}", movie.frames.len(), path);
+    }
     Ok(())
 }
@@ -0,0 +1,319 @@
+//! # Movie
+//! Input movies: the controller bytes fed to the console on every frame, anchored either
+//! to power-on or to a save state. Playing a movie back on the same ROM reproduces the
+//! run exactly, which makes movies usable as regression tests for the CPU and PPU.
+//!
+//! Movies are stored in a small native binary format, and can be imported from and
+//! exported to FCEUX's FM2 text format so existing TAS movies can be reused.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::states::{invalid, StateReader, StateWriter};
+
+/// Identifies a native movie file.
+const MAGIC: &[u8; 4] = b"NESM";
+/// Bumped whenever the native movie layout changes.
+const VERSION: u32 = 1;
+
+/// Soft reset (the reset button) before the frame runs.
+pub const COMMAND_RESET: u8 = 0x01;
+/// Power cycle before the frame runs.
+pub const COMMAND_POWER: u8 = 0x02;
+
+/// Button order of an FM2 input field; character `i` is button bit `7 - i`.
+const FM2_BUTTONS: &[u8; 8] = b"RLDUTSBA";
+
+/// Where playback of a movie begins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovieStart {
+    /// The console is power cycled before the first frame.
+    PowerOn,
+    /// The console is loaded from this save state before the first frame.
+    SaveState(Vec<u8>),
+}
+
+/// Input for a single frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MovieFrame {
+    /// `COMMAND_*` flags run before the frame.
+    pub commands: u8,
+    /// Buttons held on controller 1 and 2, in `Buttons` bit order.
+    pub ports: [u8; 2],
+}
+
+/// A recorded run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub start: MovieStart,
+    /// Hash of the ROM the movie was recorded on, if known. FM2 imports have none.
+    pub rom_hash: Option<u64>,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn new(start: MovieStart, rom_hash: Option<u64>) -> Self {
+        Self {
+            start,
+            rom_hash,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Loads a movie, reading FM2 if the file ends in `.fm2` and the native format otherwise.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let data = fs::read(&path)?;
+        if is_fm2(path.as_ref()) {
+            let text = String::from_utf8(data).map_err(|_| invalid("FM2 file is not UTF-8"))?;
+            Self::from_fm2(&text)
+        } else {
+            Self::from_bytes(&data)
+        }
+    }
+
+    /// Saves the movie, writing FM2 if `path` ends in `.fm2` and the native format otherwise.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        if is_fm2(path.as_ref()) {
+            let name = path
+                .as_ref()
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            fs::write(path, self.to_fm2(&name)?)
+        } else {
+            fs::write(path, self.to_bytes())
+        }
+    }
+
+    /// Serializes the movie in the native format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.bytes(MAGIC);
+        w.u32(VERSION);
+        w.bool(self.rom_hash.is_some());
+        w.u64(self.rom_hash.unwrap_or(0));
+        match &self.start {
+            MovieStart::PowerOn => w.u8(0),
+            MovieStart::SaveState(state) => {
+                w.u8(1);
+                w.bytes(state);
+            }
+        }
+        w.u32(self.frames.len() as u32);
+        for frame in &self.frames {
+            w.u8(frame.commands);
+            w.u8(frame.ports[0]);
+            w.u8(frame.ports[1]);
+        }
+        w.finish()
+    }
+
+    /// Parses a movie in the native format.
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        let mut r = StateReader::new(data);
+        if r.bytes()? != MAGIC {
+            return Err(invalid("not a movie file"));
+        }
+        if r.u32()? != VERSION {
+            return Err(invalid("unsupported movie version"));
+        }
+        let has_hash = r.bool()?;
+        let hash = r.u64()?;
+        let start = match r.u8()? {
+            0 => MovieStart::PowerOn,
+            1 => MovieStart::SaveState(r.bytes()?.to_vec()),
+            _ => return Err(invalid("unknown movie start")),
+        };
+        let count = r.u32()?;
+        let mut frames = Vec::new();
+        for _ in 0..count {
+            frames.push(MovieFrame {
+                commands: r.u8()?,
+                ports: [r.u8()?, r.u8()?],
+            });
+        }
+        r.finish()?;
+        Ok(Self {
+            start,
+            rom_hash: has_hash.then_some(hash),
+            frames,
+        })
+    }
+
+    /// Exports the movie as FM2. Only power-on movies can be exported, since FM2 save
+    /// states are FCEUX snapshots. FCEUX's `romChecksum` is an MD5 we do not compute, so
+    /// it is left out and FCEUX will only warn about it.
+    pub fn to_fm2(&self, rom_name: &str) -> io::Result<String> {
+        if self.start != MovieStart::PowerOn {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only power-on movies can be exported to FM2",
+            ));
+        }
+        let hash = self.rom_hash.unwrap_or(0);
+        let mut out = String::new();
+        out.push_str("version 3\n");
+        out.push_str("emuVersion 22020\n");
+        out.push_str("rerecordCount 0\n");
+        out.push_str("palFlag 0\n");
+        out.push_str(&format!("romFilename {}\n", rom_name));
+        out.push_str(&format!(
+            "guid {:08X}-{:04X}-{:04X}-{:04X}-{:012X}\n",
+            (hash >> 32) as u32,
+            (hash >> 16) as u16,
+            hash as u16,
+            self.frames.len() as u16,
+            hash & 0xFFFF_FFFF_FFFF
+        ));
+        out.push_str("fourscore 0\n");
+        out.push_str("microphone 0\n");
+        out.push_str("port0 1\n");
+        out.push_str("port1 1\n");
+        out.push_str("port2 0\n");
+        out.push_str("FDS 0\n");
+        out.push_str("NewPPU 0\n");
+        for frame in &self.frames {
+            out.push_str(&format!(
+                "|{}|{}|{}||\n",
+                frame.commands,
+                fm2_port(frame.ports[0]),
+                fm2_port(frame.ports[1])
+            ));
+        }
+        Ok(out)
+    }
+
+    /// Imports an FM2 movie. Movies that start from an FCEUX save state, use the Four Score
+    /// or are PAL cannot be played here and are rejected.
+    pub fn from_fm2(text: &str) -> io::Result<Self> {
+        let mut frames = Vec::new();
+        let mut port1_is_gamepad = true;
+        for line in text.lines() {
+            let line = line.trim_end_matches('\r');
+            if let Some(record) = line.strip_prefix('|') {
+                frames.push(parse_fm2_frame(record, port1_is_gamepad)?);
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "savestate" => {
+                    return Err(invalid(
+                        "FM2 movies that start from a save state are not supported",
+                    ))
+                }
+                "fourscore" if value != "0" => {
+                    return Err(invalid("FM2 Four Score movies are not supported"))
+                }
+                "palFlag" if value != "0" => {
+                    return Err(invalid("PAL FM2 movies are not supported"))
+                }
+                "port0" if value != "1" => return Err(invalid("FM2 port 0 must be a gamepad")),
+                "port1" => port1_is_gamepad = value == "1",
+                _ => {}
+            }
+        }
+        Ok(Self {
+            start: MovieStart::PowerOn,
+            rom_hash: None,
+            frames,
+        })
+    }
+}
+
+fn is_fm2(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("fm2"))
+}
+
+/// Formats a controller byte as an FM2 `RLDUTSBA` field.
+fn fm2_port(buttons: u8) -> String {
+    FM2_BUTTONS
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            if buttons & (0x80 >> i) != 0 {
+                *name as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+/// Parses an FM2 `RLDUTSBA` field; any character other than '.' or ' ' is a pressed button.
+fn parse_fm2_port(field: &str) -> io::Result<u8> {
+    if field.len() != 8 {
+        return Err(invalid("FM2 gamepad field must have 8 buttons"));
+    }
+    Ok(field
+        .bytes()
+        .enumerate()
+        .filter(|(_, c)| *c != b'.' && *c != b' ')
+        .fold(0, |buttons, (i, _)| buttons | (0x80 >> i)))
+}
+
+/// Parses one `|commands|port0|port1|port2|` input line, without the leading '|'.
+fn parse_fm2_frame(record: &str, port1_is_gamepad: bool) -> io::Result<MovieFrame> {
+    let mut fields = record.split('|');
+    let commands = fields
+        .next()
+        .and_then(|field| field.trim().parse::<u8>().ok())
+        .ok_or_else(|| invalid("FM2 input line has no command field"))?;
+    let port0 = parse_fm2_port(fields.next().unwrap_or(""))?;
+    let port1 = match fields.next() {
+        Some(field) if port1_is_gamepad => parse_fm2_port(field)?,
+        _ => 0,
+    };
+    Ok(MovieFrame {
+        commands: commands & (COMMAND_RESET | COMMAND_POWER),
+        ports: [port0, port1],
+    })
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod movie_tests {
+    use super::*;
+
+    fn sample() -> Movie {
+        let mut movie = Movie::new(MovieStart::PowerOn, Some(0x1234));
+        movie.frames.push(MovieFrame {
+            commands: 0,
+            ports: [0x01, 0x00],
+        });
+        movie.frames.push(MovieFrame {
+            commands: COMMAND_RESET,
+            ports: [0x88, 0x40],
+        });
+        movie
+    }
+
+    #[test]
+    pub fn native_round_trip() {
+        let movie = sample();
+        let parsed = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert_eq!(parsed, movie, "native round trip, FAILED!");
+    }
+
+    #[test]
+    pub fn fm2_round_trip() {
+        let movie = sample();
+        let text = movie.to_fm2("game").unwrap();
+        assert!(
+            text.contains("|1|R...T...|.L......||"),
+            "fm2 input line, FAILED!"
+        );
+        let parsed = Movie::from_fm2(&text).unwrap();
+        assert_eq!(parsed.frames, movie.frames, "fm2 round trip, FAILED!");
+    }
+
+    #[test]
+    pub fn fm2_rejects_savestate_start() {
+        let text = "version 3\nsavestate base64:AAAA\n|0|........|........||\n";
+        assert!(
+            Movie::from_fm2(text).is_err(),
+            "savestate anchored fm2, FAILED!"
+        );
+    }
+}
@@ -0,0 +1,921 @@
+//! # Nes
+//! The console as a whole. `Nes` owns every component, wires them to the bus and runs the
+//! CPU one instruction at a time. The bus clocks the PPU in lockstep with every CPU bus
+//! access (three PPU dots per CPU cycle), so callers only have to feed input and pick up
+//! the finished frame and audio.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use log::warn;
+
+#[cfg(feature = "audio-device")]
+use crate::apu::backend::RodioBackend;
+use crate::apu::backend::{AudioBackend, AudioConfig};
+use crate::apu::{Apu, Channel, FilterPreset};
+use crate::bus::Bus;
+use crate::cartridge::Cartridge;
+use crate::controller::{Buttons, Controller};
+use crate::cpu::Cpu;
+use crate::hash;
+use crate::movie::{Movie, MovieFrame, MovieStart, COMMAND_POWER, COMMAND_RESET};
+use crate::ppu::{frame::Frame, Ppu};
+use crate::rewind::RewindBuffer;
+use crate::states::{self, Savestate, StateReader, StateWriter};
+
+/// Width of the framebuffer returned by [`Nes::framebuffer`].
+pub const SCREEN_WIDTH: usize = 255;
+/// Height of the framebuffer returned by [`Nes::framebuffer`].
+pub const SCREEN_HEIGHT: usize = 240;
+/// Battery saves are written out every this many frames (about five seconds) if they changed.
+const SRAM_FLUSH_INTERVAL: u32 = 300;
+
+/// What the console is doing with an input movie.
+enum MovieMode {
+    Recording(Movie),
+    Playing { movie: Movie, position: usize },
+}
+
+pub struct Nes {
+    cpu: Cpu<Bus>,
+    cartridge: Rc<RefCell<Cartridge>>,
+    ppu: Rc<RefCell<Ppu>>,
+    apu: Rc<RefCell<Apu>>,
+    controllers: [Rc<RefCell<Controller>>; 2],
+    /// Whether cartridges read and write `.sav` files for battery backed RAM.
+    persist_sram: bool,
+    frames_since_flush: u32,
+    /// Snapshot history, only kept once `enable_rewind` was called.
+    rewind: Option<RewindBuffer>,
+    /// Path of the inserted ROM, needed to power cycle.
+    rom_path: String,
+    /// Controller bytes last passed to `set_input`, recorded into movies.
+    inputs: [u8; 2],
+    movie: Option<MovieMode>,
+    /// `COMMAND_*` flags to run at the start of the next frame.
+    pending_commands: u8,
+    /// Seed for the power-on RAM contents in deterministic mode; RAM is zeroed otherwise.
+    ram_seed: Option<u64>,
+}
+
+/// Fingerprints of the machine after a frame, see [`Nes::frame_hashes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHashes {
+    /// The framebuffer.
+    pub frame: u64,
+    /// CPU RAM.
+    pub ram: u64,
+    /// Nametable RAM, palette RAM and OAM.
+    pub ppu: u64,
+}
+
+impl fmt::Display for FrameHashes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "frame={:016x} ram={:016x} ppu={:016x}", self.frame, self.ram, self.ppu)
+    }
+}
+
+impl Nes {
+    /// Builds a console with the ROM at `rom` inserted and powers it on. Audio plays on
+    /// the default device, or nowhere if it cannot be opened or the `audio-device`
+    /// feature is off.
+    pub fn new(rom: &str) -> Self {
+        let config = AudioConfig::default();
+        #[cfg(feature = "audio-device")]
+        match Self::with_audio(rom, Box::new(RodioBackend::default()), config) {
+            Ok(nes) => return nes,
+            Err(err) => warn!("no audio output: {}", err),
+        }
+        Self::with_apu(rom, Apu::new(config), true)
+    }
+
+    /// Builds a console like [`Nes::new`] whose audio goes to `backend` in the given
+    /// format. Fails if the backend cannot be started.
+    pub fn with_audio(
+        rom: &str,
+        backend: Box<dyn AudioBackend>,
+        config: AudioConfig,
+    ) -> io::Result<Self> {
+        let mut apu = Apu::new(config);
+        apu.start_backend(backend)?;
+        Ok(Self::with_apu(rom, apu, true))
+    }
+
+    /// Builds a console that never touches an audio device or `.sav` files, for CI and
+    /// scripted runs. [`Nes::audio_fill`] is always `None`, so pacing falls back to the
+    /// wall clock.
+    pub fn new_headless(rom: &str) -> Self {
+        Self::with_apu(rom, Apu::new_silent(), false)
+    }
+
+    /// Builds a headless console whose output depends only on the ROM, `seed` and the input
+    /// fed to it. CPU RAM starts filled from `seed` (also after a power cycle), nothing is
+    /// paced by the wall clock or an audio thread, and `.sav` files are neither read nor
+    /// written, so two runs with the same input produce identical [`Nes::frame_hashes`].
+    pub fn new_deterministic(rom: &str, seed: u64) -> Self {
+        let mut nes = Self::new_headless(rom);
+        nes.ram_seed = Some(seed);
+        nes.cpu.bus_mut().fill_ram(seed);
+        nes
+    }
+
+    fn with_apu(rom: &str, apu: Apu, persist_sram: bool) -> Self {
+        let apu = Rc::new(RefCell::new(apu));
+        let controllers = [
+            Rc::new(RefCell::new(Controller::new())),
+            Rc::new(RefCell::new(Controller::new())),
+        ];
+        let cartridge = Rc::new(RefCell::new(Self::open_cartridge(rom, persist_sram)));
+        let (ppu, bus) = Self::connect(&cartridge, &apu, &controllers);
+
+        let mut nes = Self {
+            cpu: Cpu::new(bus),
+            cartridge,
+            ppu,
+            apu,
+            controllers,
+            persist_sram,
+            frames_since_flush: 0,
+            rewind: None,
+            rom_path: rom.to_string(),
+            inputs: [0; 2],
+            movie: None,
+            pending_commands: 0,
+            ram_seed: None,
+        };
+        nes.cpu.reset();
+        nes
+    }
+
+    fn open_cartridge(rom: &str, persist_sram: bool) -> Cartridge {
+        if persist_sram {
+            Cartridge::new(rom)
+        } else {
+            Cartridge::new_volatile(rom)
+        }
+    }
+
+    /// Creates the PPU and bus for `cartridge` and links every component to the bus.
+    fn connect(
+        cartridge: &Rc<RefCell<Cartridge>>,
+        apu: &Rc<RefCell<Apu>>,
+        controllers: &[Rc<RefCell<Controller>>; 2],
+    ) -> (Rc<RefCell<Ppu>>, Bus) {
+        let ppu = Rc::new(RefCell::new(Ppu::new(Rc::clone(cartridge))));
+        let mut bus = Bus::new(Rc::clone(&ppu));
+        bus.link_cartridge(Rc::clone(cartridge));
+        bus.link_apu(Rc::clone(apu));
+        bus.link_controller1(Rc::clone(&controllers[0]));
+        bus.link_controller2(Rc::clone(&controllers[1]));
+        (ppu, bus)
+    }
+
+    /// Swaps in the ROM at `rom` and powers the console back on.
+    /// The APU and controllers are kept; everything else starts from scratch.
+    /// The old cartridge's battery save is written out when it is dropped.
+    pub fn load_rom(&mut self, rom: &str) {
+        self.insert_cartridge(rom, self.persist_sram);
+    }
+
+    fn insert_cartridge(&mut self, rom: &str, persist_sram: bool) {
+        self.rom_path = rom.to_string();
+        self.cartridge = Rc::new(RefCell::new(Self::open_cartridge(rom, persist_sram)));
+        let (ppu, bus) = Self::connect(&self.cartridge, &self.apu, &self.controllers);
+        self.ppu = ppu;
+        self.cpu = Cpu::new(bus);
+        if let Some(seed) = self.ram_seed {
+            self.cpu.bus_mut().fill_ram(seed);
+        }
+        self.cpu.reset();
+        if let Some(rewind) = &mut self.rewind {
+            rewind.clear();
+        }
+    }
+
+    /// Turns the console off and on again with the same ROM.
+    pub fn power_cycle(&mut self) {
+        let rom = self.rom_path.clone();
+        self.load_rom(&rom);
+    }
+
+    /// Presses the reset button. While a movie is recording the reset is recorded and
+    /// happens at the start of the next frame; during playback only the movie can reset.
+    pub fn reset(&mut self) {
+        match self.movie {
+            Some(MovieMode::Recording(_)) => self.pending_commands |= COMMAND_RESET,
+            Some(MovieMode::Playing { .. }) => {}
+            None => self.cpu.reset(),
+        }
+    }
+
+    /// Runs one CPU instruction, with the PPU advancing alongside each of its bus accesses.
+    /// The CPU watches the NMI and IRQ lines on the bus by itself, so an interrupt that was
+    /// raised in time is taken as part of the same step.
+    pub fn step_instruction(&mut self) {
+        self.cpu.step();
+    }
+
+    /// Runs until the PPU reaches vblank, i.e. until a full picture is in the framebuffer.
+    /// Every few seconds a changed battery save is written back to disk.
+    pub fn step_frame(&mut self) {
+        // The snapshot is taken before the frame runs, so the newest one is always older
+        // than what is on screen and the first rewind visibly goes back
+        if self.rewind.as_mut().is_some_and(|rewind| rewind.frame_done()) {
+            let state = self.save_state();
+            if let Some(rewind) = &mut self.rewind {
+                rewind.push(state);
+            }
+        }
+
+        self.movie_frame();
+        loop {
+            self.step_instruction();
+            if self.ppu.borrow_mut().frame_complete() {
+                break;
+            }
+        }
+
+        self.frames_since_flush += 1;
+        if self.frames_since_flush >= SRAM_FLUSH_INTERVAL {
+            self.frames_since_flush = 0;
+            if let Err(err) = self.flush_sram() {
+                warn!("could not write save file: {}", err);
+            }
+        }
+    }
+
+    /// Sets the buttons held on controller `port` (0 or 1).
+    /// Ignored while a movie is playing back.
+    pub fn set_input(&mut self, port: usize, buttons: Buttons) {
+        if matches!(self.movie, Some(MovieMode::Playing { .. })) {
+            return;
+        }
+        self.inputs[port] = buttons.bits();
+        self.controllers[port]
+            .borrow_mut()
+            ._set_reg_value(buttons.bits());
+    }
+
+    /// Starts recording an input movie, either from a fresh power-on or from the current
+    /// machine state. Power-on movies start with blank PRG-RAM, ignoring any `.sav` file,
+    /// so they play back the same way everywhere.
+    pub fn record_movie(&mut self, from_power_on: bool) {
+        let start = if from_power_on {
+            let rom = self.rom_path.clone();
+            self.insert_cartridge(&rom, false);
+            MovieStart::PowerOn
+        } else {
+            MovieStart::SaveState(self.save_state())
+        };
+        let hash = self.cartridge.borrow().rom_hash();
+        self.pending_commands = 0;
+        self.movie = Some(MovieMode::Recording(Movie::new(start, Some(hash))));
+    }
+
+    /// Starts playing `movie` back from its anchor. Fails if the movie was recorded on a
+    /// different ROM or its save state cannot be loaded.
+    pub fn play_movie(&mut self, movie: Movie) -> io::Result<()> {
+        if movie
+            .rom_hash
+            .is_some_and(|hash| hash != self.cartridge.borrow().rom_hash())
+        {
+            return Err(states::invalid("movie was recorded on a different ROM"));
+        }
+        // The anchor belongs to the new movie, not to one still running
+        let previous = self.movie.take();
+        match &movie.start {
+            MovieStart::PowerOn => {
+                let rom = self.rom_path.clone();
+                self.insert_cartridge(&rom, false);
+            }
+            MovieStart::SaveState(state) => {
+                if let Err(err) = self.load_state(state) {
+                    self.movie = previous;
+                    return Err(err);
+                }
+            }
+        }
+        self.pending_commands = 0;
+        self.movie = Some(MovieMode::Playing { movie, position: 0 });
+        Ok(())
+    }
+
+    /// Stops recording or playback and returns the movie.
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        match self.movie.take()? {
+            MovieMode::Recording(movie) | MovieMode::Playing { movie, .. } => Some(movie),
+        }
+    }
+
+    pub fn movie_recording(&self) -> bool {
+        matches!(self.movie, Some(MovieMode::Recording(_)))
+    }
+
+    /// Returns true while a movie is playing. Playback ends, and input goes back to
+    /// `set_input`, on the first frame after the movie's last one.
+    pub fn movie_playing(&self) -> bool {
+        matches!(self.movie, Some(MovieMode::Playing { .. }))
+    }
+
+    /// Movie frames recorded or played so far, or `None` without a movie.
+    fn movie_position(&self) -> Option<usize> {
+        match &self.movie {
+            None => None,
+            Some(MovieMode::Recording(movie)) => Some(movie.frames.len()),
+            Some(MovieMode::Playing { position, .. }) => Some(*position),
+        }
+    }
+
+    /// Checks that a state saved `saved` movie frames in can be loaded into the running
+    /// movie. Any state can be loaded without a movie.
+    fn check_movie_position(&self, saved: Option<usize>) -> io::Result<()> {
+        let length = match &self.movie {
+            None => return Ok(()),
+            Some(MovieMode::Recording(movie)) | Some(MovieMode::Playing { movie, .. }) => {
+                movie.frames.len()
+            }
+        };
+        match saved {
+            Some(position) if position <= length => Ok(()),
+            _ => Err(states::invalid("save state is not part of the running movie")),
+        }
+    }
+
+    /// After loading a state, drops the recorded frames that came after it or continues
+    /// playback from it.
+    fn seek_movie(&mut self, saved: Option<usize>) {
+        let Some(position) = saved else {
+            return;
+        };
+        match &mut self.movie {
+            None => {}
+            Some(MovieMode::Recording(movie)) => movie.frames.truncate(position),
+            Some(MovieMode::Playing {
+                position: current, ..
+            }) => *current = position,
+        }
+        self.pending_commands = 0;
+    }
+
+    /// Records or plays back the input for the frame about to run and performs its commands.
+    fn movie_frame(&mut self) {
+        let frame = match &mut self.movie {
+            None => return,
+            Some(MovieMode::Recording(movie)) => {
+                let frame = MovieFrame {
+                    commands: self.pending_commands,
+                    ports: self.inputs,
+                };
+                movie.frames.push(frame);
+                frame
+            }
+            Some(MovieMode::Playing { movie, position }) => match movie.frames.get(*position) {
+                Some(frame) => {
+                    *position += 1;
+                    *frame
+                }
+                None => {
+                    self.movie = None;
+                    return;
+                }
+            },
+        };
+        self.pending_commands = 0;
+
+        if frame.commands & COMMAND_POWER != 0 {
+            let rom = self.rom_path.clone();
+            self.insert_cartridge(&rom, false);
+        } else if frame.commands & COMMAND_RESET != 0 {
+            self.cpu.reset();
+        }
+        self.inputs = frame.ports;
+        for (controller, buttons) in self.controllers.iter().zip(frame.ports) {
+            controller.borrow_mut()._set_reg_value(buttons);
+        }
+    }
+
+    /// Returns the last rendered picture as `SCREEN_WIDTH * SCREEN_HEIGHT` 0xRRGGBB pixels.
+    pub fn framebuffer(&self) -> &[u32] {
+        self.cpu.bus().frame().get_buf()
+    }
+
+    /// Returns the framebuffer as a `Frame`.
+    pub fn frame(&self) -> &Frame {
+        self.cpu.bus().frame()
+    }
+
+    /// The 2KB of internal CPU RAM.
+    pub fn ram(&self) -> &[u8] {
+        self.cpu.bus().ram()
+    }
+
+    /// FNV-1a hash of the current framebuffer.
+    pub fn frame_hash(&self) -> u64 {
+        hash::hash_pixels(self.framebuffer())
+    }
+
+    /// FNV-1a hash of CPU RAM.
+    pub fn ram_hash(&self) -> u64 {
+        hash::hash_bytes(self.ram())
+    }
+
+    /// Hashes of the framebuffer, CPU RAM and PPU memory, meant to be logged after every
+    /// frame so two runs can be diffed.
+    pub fn frame_hashes(&self) -> FrameHashes {
+        let mut ppu = hash::Fnv1a::new();
+        self.ppu.borrow().hash_memory(&mut ppu);
+        FrameHashes {
+            frame: self.frame_hash(),
+            ram: self.ram_hash(),
+            ppu: ppu.finish(),
+        }
+    }
+
+    /// Drains the audio samples played since the last call, as interleaved left and right
+    /// pairs in stereo.
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        self.apu.borrow_mut().take_samples()
+    }
+
+    /// Queued audio relative to the latency target, or `None` without an audio device.
+    /// Pass it to [`FramePacer::wait`](crate::pacer::FramePacer::wait).
+    pub fn audio_fill(&self) -> Option<f32> {
+        self.apu.borrow().audio_fill()
+    }
+
+    /// Selects the filters modelling the console's analog audio output.
+    pub fn set_audio_filter(&mut self, preset: FilterPreset) {
+        self.apu.borrow_mut().set_filter(preset);
+    }
+
+    /// Starts recording the APU output to a WAV file, and with `stems` each channel to its
+    /// own file next to it (`song.wav`, `song.pulse1.wav`, ...). Recordings are identical
+    /// for identical input, whatever the audio backend does.
+    pub fn start_audio_recording<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        stems: bool,
+    ) -> io::Result<()> {
+        self.apu.borrow_mut().start_recording(path.as_ref(), stems)
+    }
+
+    /// Finishes the audio recording, if one is running.
+    pub fn stop_audio_recording(&mut self) -> io::Result<()> {
+        self.apu.borrow_mut().stop_recording()
+    }
+
+    pub fn is_recording_audio(&self) -> bool {
+        self.apu.borrow().is_recording()
+    }
+
+    /// Starts logging APU register writes to a VGM file, which is written by
+    /// [`Nes::stop_vgm_log`].
+    pub fn start_vgm_log<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.apu.borrow_mut().start_vgm_log(path.as_ref())
+    }
+
+    /// Marks the current point of the VGM log as the start of the loop players repeat.
+    pub fn mark_vgm_loop(&mut self) {
+        self.apu.borrow_mut().mark_vgm_loop();
+    }
+
+    /// Finishes the VGM log and writes the file, if logging.
+    pub fn stop_vgm_log(&mut self) -> io::Result<()> {
+        self.apu.borrow_mut().stop_vgm_log()
+    }
+
+    pub fn is_logging_vgm(&self) -> bool {
+        self.apu.borrow().is_logging_vgm()
+    }
+
+    /// Switches the noise channel to the PAL period table, for PAL games played on this
+    /// otherwise NTSC console. Part of the save state.
+    pub fn set_pal_noise(&mut self, pal: bool) {
+        self.apu.borrow_mut().set_pal(pal);
+    }
+
+    /// Mutes or unmutes the APU.
+    pub fn toggle_sound(&mut self) {
+        self.apu.borrow_mut().toggle_sound();
+    }
+
+    /// Sets a channel's volume, 1.0 being its level on the console, up to 2.0.
+    pub fn set_channel_volume(&mut self, channel: Channel, volume: f32) {
+        self.apu.borrow_mut().set_channel_volume(channel, volume);
+    }
+
+    pub fn channel_volume(&self, channel: Channel) -> f32 {
+        self.apu.borrow().channel_volume(channel)
+    }
+
+    pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+        self.apu.borrow_mut().set_channel_muted(channel, muted);
+    }
+
+    pub fn is_channel_muted(&self, channel: Channel) -> bool {
+        self.apu.borrow().is_channel_muted(channel)
+    }
+
+    /// While any channel is soloed only the soloed channels are heard, muted or not.
+    pub fn set_channel_solo(&mut self, channel: Channel, solo: bool) {
+        self.apu.borrow_mut().set_channel_solo(channel, solo);
+    }
+
+    pub fn is_channel_solo(&self, channel: Channel) -> bool {
+        self.apu.borrow().is_channel_solo(channel)
+    }
+
+    /// Puts every channel back at full volume, unmuted and not soloed. Pans are kept.
+    pub fn reset_channel_controls(&mut self) {
+        self.apu.borrow_mut().reset_channel_controls();
+    }
+
+    /// Sets a channel's position in stereo output, from -1.0 (left) through 0.0 (both
+    /// sides) to 1.0 (right). Only heard with [`AudioConfig::stereo`] set.
+    pub fn set_channel_pan(&mut self, channel: Channel, pan: f32) {
+        self.apu.borrow_mut().set_channel_pan(channel, pan);
+    }
+
+    pub fn channel_pan(&self, channel: Channel) -> f32 {
+        self.apu.borrow().channel_pan(channel)
+    }
+
+    /// Writes battery backed PRG-RAM to the ROM's `.sav` file now, if it changed.
+    /// Does nothing for carts without a battery.
+    pub fn flush_sram(&mut self) -> io::Result<()> {
+        self.cartridge.borrow_mut().flush_sram()
+    }
+
+    /// Serializes the whole machine: CPU, RAM, PPU, APU, mapper and controllers, plus the
+    /// framebuffer so the picture is back immediately after loading. While a movie runs
+    /// the number of movie frames so far is stored too.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.bytes(states::MAGIC);
+        w.u32(states::VERSION);
+        w.u64(self.cartridge.borrow().rom_hash());
+        let position = self.movie_position();
+        w.bool(position.is_some());
+        w.u32(position.unwrap_or(0) as u32);
+        self.cpu.save_state(&mut w);
+        self.cpu.bus().save_state(&mut w);
+        self.ppu.borrow().save_state(&mut w);
+        self.apu.borrow().save_state(&mut w);
+        self.cartridge.borrow().save_state(&mut w);
+        for controller in &self.controllers {
+            controller.borrow().save_state(&mut w);
+        }
+        self.cpu.bus().frame().save_state(&mut w);
+        w.finish()
+    }
+
+    /// Restores a state produced by [`Nes::save_state`]. The state must come from the same
+    /// ROM and format version. On error the machine is left exactly as it was.
+    ///
+    /// While a movie records, the frames recorded after the state was saved are dropped;
+    /// during playback, playback continues from the state's frame. A state saved outside
+    /// the movie, or later in it than the movie goes, is refused.
+    pub fn load_state(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut r = StateReader::new(data);
+        if r.bytes()? != states::MAGIC {
+            return Err(states::invalid("not a save state"));
+        }
+        if r.u32()? != states::VERSION {
+            return Err(states::invalid("unsupported save state version"));
+        }
+        if r.u64()? != self.cartridge.borrow().rom_hash() {
+            return Err(states::invalid("save state belongs to a different ROM"));
+        }
+        let has_position = r.bool()?;
+        let position = r.u32()? as usize;
+        let position = has_position.then_some(position);
+        self.check_movie_position(position)?;
+
+        let backup = self.save_state();
+        let result = self.load_components(&mut r);
+        if result.is_err() {
+            let mut r = StateReader::new(&backup);
+            r.bytes()?;
+            r.u32()?;
+            r.u64()?;
+            r.bool()?;
+            r.u32()?;
+            self.load_components(&mut r)?;
+            return result;
+        }
+        self.seek_movie(position);
+        result
+    }
+
+    fn load_components(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.cpu.load_state(r)?;
+        self.cpu.bus_mut().load_state(r)?;
+        self.ppu.borrow_mut().load_state(r)?;
+        self.apu.borrow_mut().load_state(r)?;
+        self.cartridge.borrow_mut().load_state(r)?;
+        for controller in &self.controllers {
+            controller.borrow_mut().load_state(r)?;
+        }
+        self.cpu.bus_mut().frame_mut().load_state(r)?;
+        r.finish()
+    }
+
+    /// Starts recording a snapshot every `interval` frames, keeping the last `capacity`
+    /// of them for [`Nes::rewind`].
+    pub fn enable_rewind(&mut self, interval: u32, capacity: usize) {
+        self.rewind = Some(RewindBuffer::new(interval, capacity));
+    }
+
+    /// Stops recording snapshots and frees the history.
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    /// Goes back to the most recent snapshot and removes it from the history, so calling
+    /// this once per frame plays the game backwards. Returns false once the history is used up.
+    pub fn rewind(&mut self) -> bool {
+        let state = match self.rewind.as_mut().and_then(RewindBuffer::pop) {
+            Some(state) => state,
+            None => return false,
+        };
+        if let Err(err) = self.load_state(&state) {
+            warn!("could not rewind: {}", err);
+            return false;
+        }
+        true
+    }
+
+    /// Writes [`Nes::save_state`] to `path`.
+    pub fn save_state_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.save_state())
+    }
+
+    /// Loads a state written by [`Nes::save_state_file`].
+    pub fn load_state_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let data = fs::read(path)?;
+        self.load_state(&data)
+    }
+
+    /// Draws both pattern tables to the right of the game image, for the debug view.
+    pub fn render_pattern_table(&mut self, frame: &mut Frame) {
+        self.ppu.borrow_mut().get_pattern_table(frame);
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+/// - Runs a tiny generated NROM program on the whole console
+pub(crate) mod nes_tests {
+    use super::{Buttons, Nes};
+    use crate::bus::IrqSource;
+    use crate::cpu::CpuBus;
+
+    /// Writes a 16KB NROM image to the temp dir and returns its path. The program enables
+    /// NMI, then forever counts in $10 and sums the A button of controller 1 into $12,
+    /// while the NMI handler counts frames in $11.
+    pub(crate) fn test_rom(name: &str) -> String {
+        let mut prg = vec![0u8; 0x4000];
+        prg[..0x1B].copy_from_slice(&[
+            0xA9, 0x80, 0x8D, 0x00, 0x20, // LDA #$80; STA $2000
+            0xE6, 0x10, // loop: INC $10
+            0xA9, 0x01, 0x8D, 0x16, 0x40, // LDA #1; STA $4016
+            0xA9, 0x00, 0x8D, 0x16, 0x40, // LDA #0; STA $4016
+            0xAD, 0x16, 0x40, // LDA $4016
+            0x65, 0x12, 0x85, 0x12, // ADC $12; STA $12
+            0x4C, 0x05, 0x80, // JMP loop
+        ]);
+        // $8020: INC $11; RTI
+        prg[0x20..0x23].copy_from_slice(&[0xE6, 0x11, 0x40]);
+        prg[0x3FFA..].copy_from_slice(&[0x20, 0x80, 0x00, 0x80, 0x00, 0x80]);
+
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.extend_from_slice(&prg);
+        rom.extend_from_slice(&[0; 0x2000]);
+        let path = std::env::temp_dir().join(format!("{}-{}.nes", name, std::process::id()));
+        std::fs::write(&path, rom).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    pub fn save_state_round_trip() {
+        let mut nes = Nes::new_headless(&test_rom("save_state_round_trip"));
+        for _ in 0..3 {
+            nes.step_frame();
+        }
+        // Take the state mid-frame so the PPU and CPU positions matter
+        for _ in 0..300 {
+            nes.step_instruction();
+        }
+        let state = nes.save_state();
+        for _ in 0..2 {
+            nes.step_frame();
+        }
+        let expected = (nes.ram_hash(), nes.frame_hash());
+
+        nes.load_state(&state).unwrap();
+        assert_eq!(nes.save_state(), state, "state after load, FAILED!");
+        for _ in 0..2 {
+            nes.step_frame();
+        }
+        assert_eq!((nes.ram_hash(), nes.frame_hash()), expected, "replay after load, FAILED!");
+    }
+
+    #[test]
+    pub fn rewind_restores_earlier_frames() {
+        let mut nes = Nes::new_headless(&test_rom("rewind_restores_earlier_frames"));
+        nes.enable_rewind(1, 10);
+        let mut hashes = Vec::new();
+        for _ in 0..5 {
+            hashes.push(nes.ram_hash());
+            nes.step_frame();
+        }
+        assert_ne!(nes.ram_hash(), hashes[4], "program changes ram, FAILED!");
+        // Each rewind lands on the frame before the one on screen
+        for expected in hashes.iter().rev() {
+            assert!(nes.rewind(), "snapshot available, FAILED!");
+            assert_eq!(nes.ram_hash(), *expected, "rewound ram, FAILED!");
+        }
+        assert!(!nes.rewind(), "history used up, FAILED!");
+    }
+
+    #[test]
+    pub fn movie_playback_matches_recording() {
+        let rom = test_rom("movie_playback_matches_recording");
+        let mut nes = Nes::new_headless(&rom);
+        nes.step_frame();
+        nes.record_movie(true);
+        for frame in 0..20 {
+            let buttons = if frame % 3 == 0 { Buttons::A } else { Buttons::empty() };
+            nes.set_input(0, buttons);
+            if frame == 10 {
+                nes.reset();
+            }
+            nes.step_frame();
+        }
+        let expected = nes.ram_hash();
+        let movie = nes.stop_movie().unwrap();
+        assert_eq!(movie.frames.len(), 20, "recorded frames, FAILED!");
+        assert_ne!(nes.ram()[0x12], 0, "program saw the input, FAILED!");
+
+        let mut nes = Nes::new_headless(&rom);
+        nes.play_movie(movie).unwrap();
+        for _ in 0..20 {
+            nes.step_frame();
+        }
+        assert_eq!(nes.ram_hash(), expected, "playback, FAILED!");
+    }
+
+    #[test]
+    pub fn rewinding_a_recording_keeps_it_in_sync() {
+        let rom = test_rom("rewinding_a_recording_keeps_it_in_sync");
+        let mut nes = Nes::new_headless(&rom);
+        nes.enable_rewind(1, 30);
+        nes.record_movie(true);
+        for frame in 0..10 {
+            let buttons = if frame % 3 == 0 { Buttons::A } else { Buttons::empty() };
+            nes.set_input(0, buttons);
+            nes.step_frame();
+        }
+        for _ in 0..4 {
+            assert!(nes.rewind(), "rewound while recording, FAILED!");
+        }
+        for frame in 0..10 {
+            let buttons = if frame % 2 == 0 { Buttons::B } else { Buttons::A };
+            nes.set_input(0, buttons);
+            nes.step_frame();
+        }
+        let expected = nes.ram_hash();
+        let movie = nes.stop_movie().unwrap();
+        assert_eq!(movie.frames.len(), 16, "rewound frames dropped, FAILED!");
+
+        let mut nes = Nes::new_headless(&rom);
+        nes.play_movie(movie.clone()).unwrap();
+        for _ in 0..16 {
+            nes.step_frame();
+        }
+        assert_eq!(nes.ram_hash(), expected, "playback, FAILED!");
+
+        // Rewinding during playback goes back in the movie too
+        let mut nes = Nes::new_headless(&rom);
+        nes.enable_rewind(1, 30);
+        nes.play_movie(movie).unwrap();
+        for _ in 0..10 {
+            nes.step_frame();
+        }
+        for _ in 0..3 {
+            assert!(nes.rewind(), "rewound while playing, FAILED!");
+        }
+        for _ in 0..9 {
+            nes.step_frame();
+        }
+        assert_eq!(nes.ram_hash(), expected, "playback after rewind, FAILED!");
+        assert!(nes.movie_playing(), "movie not over yet, FAILED!");
+    }
+
+    #[test]
+    pub fn deterministic_runs_match() {
+        let rom = test_rom("deterministic_runs_match");
+        let run = |seed| {
+            let mut nes = Nes::new_deterministic(&rom, seed);
+            (0..10)
+                .map(|_| {
+                    nes.step_frame();
+                    nes.frame_hashes()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(1), run(1), "same seed, FAILED!");
+        assert_ne!(run(1)[0].ram, run(2)[0].ram, "different seed, FAILED!");
+    }
+
+    #[test]
+    pub fn rejects_bad_state() {
+        let mut nes = Nes::new_headless(&test_rom("rejects_bad_state"));
+        nes.step_frame();
+        let before = nes.save_state();
+        let mut state = before.clone();
+        state.truncate(state.len() / 2);
+        assert!(nes.load_state(&state).is_err(), "truncated state, FAILED!");
+        assert_eq!(nes.save_state(), before, "machine untouched, FAILED!");
+    }
+
+    #[test]
+    pub fn oam_dma_copies_any_page_and_halts_the_cpu() {
+        let mut nes = Nes::new_headless(&test_rom("oam_dma"));
+        let bus = nes.cpu.bus_mut();
+        // Copy the first page of PRG-ROM
+        bus.write(0x4014, 0x80);
+        let before = bus.cycles();
+        bus.read(0x0000);
+        let halted = bus.cycles() - before - 1;
+        assert!(halted == 513 || halted == 514, "dma took {} cycles, FAILED!", halted);
+        for i in [0x00, 0x01, 0x1A, 0xFF] {
+            bus.write(0x2003, i);
+            assert_eq!(bus.peek(0x2004), bus.peek(0x8000 + i as u16), "oam byte {}, FAILED!", i);
+        }
+    }
+
+    #[test]
+    pub fn unmapped_reads_return_open_bus() {
+        let mut nes = Nes::new_headless(&test_rom("open_bus"));
+        let bus = nes.cpu.bus_mut();
+        bus.write(0x0010, 0xA7);
+        bus.read(0x0010);
+        assert_eq!(bus.read(0x4018), 0xA7, "unmapped io, FAILED!");
+        assert_eq!(bus.read(0x4000), 0xA7, "write only apu register, FAILED!");
+        assert_eq!(bus.read(0x4016) & 0xE0, 0xA0, "controller upper bits, FAILED!");
+
+        // The PPU keeps its own latch, which fades once nothing refreshes it
+        bus.write(0x2003, 0xA5);
+        bus.write(0x0010, 0x00);
+        assert_eq!(bus.read(0x2001), 0xA5, "ppu io latch, FAILED!");
+        for _ in 0..40 {
+            nes.step_frame();
+        }
+        assert_eq!(nes.cpu.bus_mut().read(0x2001), 0x00, "ppu latch decayed, FAILED!");
+    }
+
+    #[test]
+    pub fn frame_irq_shows_in_status_and_on_the_irq_line() {
+        let mut nes = Nes::new_headless(&test_rom("frame_irq"));
+        let bus = nes.cpu.bus_mut();
+        bus.write(0x4017, 0x00);
+        for _ in 0..29840 {
+            bus.read(0x0000);
+        }
+        assert_eq!(bus.irq_sources(), IrqSource::FRAME_COUNTER, "irq line, FAILED!");
+        assert_eq!(bus.read(0x4015) & 0x40, 0x40, "status bit 6, FAILED!");
+        assert_eq!(bus.read(0x4015) & 0x40, 0x00, "read acknowledges, FAILED!");
+        assert!(bus.irq_sources().is_empty(), "irq line released, FAILED!");
+    }
+
+    #[test]
+    pub fn dmc_fetch_steals_cpu_cycles() {
+        // The fetch is requested on the first cycle after the $4015 write and the CPU is
+        // halted on the next: halt, dummy and get on a get cycle, plus alignment on a put
+        for (halt_on_get, expected) in [(true, 3), (false, 4)] {
+            let mut nes = Nes::new_headless(&test_rom("dmc_fetch"));
+            let bus = nes.cpu.bus_mut();
+            // One byte sample at $C000 with the IRQ enabled
+            bus.write(0x4010, 0x8F);
+            bus.write(0x4012, 0x00);
+            bus.write(0x4013, 0x00);
+            if bus.cycles().is_multiple_of(2) == halt_on_get {
+                bus.read(0x0000);
+            }
+            bus.write(0x4015, 0x10);
+            let before = bus.cycles();
+            for _ in 0..10 {
+                bus.read(0x0000);
+            }
+            let stolen = bus.cycles() - before - 10;
+            assert_eq!(stolen, expected, "dma stolen cycles, FAILED!");
+            assert_eq!(bus.peek(0x4015) & 0x10, 0x00, "sample fetched, FAILED!");
+            assert_eq!(bus.irq_sources(), IrqSource::DMC, "dmc irq, FAILED!");
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! # Frame pacing
+//! Keeps a frontend running at the console's frame rate, 60.0988 Hz for NTSC.
+//!
+//! With an audio device the audio buffer drives the pace: every frame is scheduled one
+//! frame period after the last, shifted by how far the queued audio is from its target
+//! fill, so emulation slows down when audio piles up and hurries when it runs low. The
+//! APU's dynamic rate control evens out the remaining drift between the two clocks by
+//! stretching the resampling ratio slightly. Without an audio device the frames follow
+//! the wall clock alone.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Frames per second of an NTSC console: the PPU's 5.369318 MHz dot clock over 341 * 262
+/// dots per frame, less the dot skipped every other frame.
+pub const NTSC_FRAME_RATE: f64 = 60.0988;
+
+/// How many frames late the pacer may fall before it gives up catching up.
+const MAX_LAG_FRAMES: u32 = 3;
+
+pub struct FramePacer {
+    frame: Duration,
+    /// When the next frame is due.
+    next: Instant,
+}
+
+impl FramePacer {
+    pub fn new(frame_rate: f64) -> Self {
+        Self {
+            frame: Duration::from_secs_f64(1.0 / frame_rate),
+            next: Instant::now(),
+        }
+    }
+
+    /// Sleeps until the next frame is due. `audio_fill` is the queued audio relative to
+    /// its target (1.0 on target), or `None` to pace by the wall clock only. A fill off
+    /// target moves the deadline by up to half a frame.
+    pub fn wait(&mut self, audio_fill: Option<f32>) {
+        self.next += self.frame;
+        if let Some(fill) = audio_fill {
+            let skew = (fill - 1.0).clamp(-0.5, 0.5) as f64;
+            self.next = if skew >= 0.0 {
+                self.next + self.frame.mul_f64(skew)
+            } else {
+                self.next - self.frame.mul_f64(-skew)
+            };
+        }
+
+        let now = Instant::now();
+        if self.next > now {
+            thread::sleep(self.next - now);
+        } else if now - self.next > self.frame * MAX_LAG_FRAMES {
+            // After a stall, start over instead of rushing through the missed frames
+            self.next = now;
+        }
+    }
+}
+
+impl Default for FramePacer {
+    fn default() -> Self {
+        Self::new(NTSC_FRAME_RATE)
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod pacer_tests {
+    use super::*;
+
+    #[test]
+    pub fn frames_follow_the_frame_rate() {
+        let mut pacer = FramePacer::new(200.0);
+        let start = Instant::now();
+        for _ in 0..10 {
+            pacer.wait(None);
+        }
+        assert!(
+            start.elapsed() >= Duration::from_millis(49),
+            "waits a frame period per frame, FAILED!"
+        );
+
+        // Audio piling up past its target makes frames wait longer
+        let start = Instant::now();
+        for _ in 0..10 {
+            pacer.wait(Some(2.0));
+        }
+        assert!(
+            start.elapsed() >= Duration::from_millis(74),
+            "audio slows emulation down, FAILED!"
+        );
+    }
+}
@@ -3,6 +3,7 @@ mod oam;
 
 use core::panic;
 use std::cell::RefCell;
+use std::io;
 use std::rc::Rc;
 
 use crate::ppu::oam::Oam;
@@ -11,9 +12,15 @@ use log::info;
 use registers::{VtReg, PPUCTRL, PPUMASK, PPUSTATUS};
 
 use crate::cartridge::{Cartridge, MirrorMode};
+use crate::hash::Fnv1a;
+use crate::states::{Savestate, StateReader, StateWriter};
 
 pub mod frame;
 mod registers;
+
+/// Frames a bit of the I/O latch holds its value without being driven again (about 600 ms).
+const IO_LATCH_DECAY_FRAMES: u8 = 36;
+
 ///# PPU 
 /// ## Picture Processing Unit
 /// Handles rendering the 256 x 240 video on the NES
@@ -28,7 +35,7 @@ pub struct Ppu {
     x: u8, //fine x scroll
     vram: Vec<u8>,
     internal_buffer: u8,
-    nmi: bool,
+    frame_complete: bool,
     cart: Rc<RefCell<Cartridge>>,
     palette_memory: Vec<u8>,
     system_palette: Vec<(u8, u8, u8)>,
@@ -55,6 +62,10 @@ pub struct Ppu {
     sprite0xcoord: u16,
     sprite0ycoord: u16,
     sprite0poss: bool,
+    /// Value last driven on the PPU's data bus; reads of write-only registers return it.
+    io_latch: u8,
+    /// Frames left before each bit of `io_latch` decays to 0.
+    io_latch_decay: [u8; 8],
 }
 
 impl Ppu {
@@ -128,14 +139,20 @@ impl Ppu {
         toreturn
     }
 
-    /// # `get_nmi`
-    /// - checks if the picture processing unit invokes a non-maskable interrupt.
-    /// - utilizes test and set method
-    pub fn get_nmi(&mut self) -> bool {
-        let data = self.nmi;
-        if data {
-            self.nmi = false;
-        }
+    /// # `nmi_line`
+    /// - level of the PPU's /NMI output: asserted while the vblank flag and NMI enable are both set.
+    /// - the CPU reacts to the edge, so enabling NMIs in the middle of vblank fires one too.
+    pub fn nmi_line(&self) -> bool {
+        self.ppustatus.contains(PPUSTATUS::vblank_flag)
+            && self.ppuctrl.contains(PPUCTRL::vblank_enable)
+    }
+
+    /// # `frame_complete`
+    /// - checks if the PPU has finished drawing the visible part of a frame (start of vblank).
+    /// - utilizes test and set method, and fires even when NMIs are disabled.
+    pub fn frame_complete(&mut self) -> bool {
+        let data = self.frame_complete;
+        self.frame_complete = false;
         data
     }
     ///# `new(cartridge)`
@@ -154,7 +171,7 @@ impl Ppu {
             x: 0,
             vram: vram,
             internal_buffer: 0,
-            nmi: false,
+            frame_complete: false,
             cart: cartridge,
             palette_memory: pal,
             system_palette: Ppu::initialize_system_palette(),
@@ -177,6 +194,20 @@ impl Ppu {
             sprite0xcoord: 0,
             sprite0ycoord: 0,
             sprite0poss: false,
+            io_latch: 0,
+            io_latch_decay: [0; 8],
+        }
+    }
+
+    /// # `hash_memory(hasher)`
+    /// Feeds nametable RAM, palette RAM and OAM into `hasher`, for per-frame regression hashes.
+    pub fn hash_memory(&self, hasher: &mut Fnv1a) {
+        hasher.write(&self.vram);
+        hasher.write(&self.palette_memory);
+        for sprite in &self.oam_table {
+            for i in 0..4 {
+                hasher.write(&[sprite.get_byte(i)]);
+            }
         }
     }
 
@@ -191,7 +222,7 @@ impl Ppu {
     /// - Finds the location of sprite 0 hit flag.
     /// - Determines which point to enable the sprite_0_hit_flag register.
     pub fn find_sprite0_coord(&mut self) {
-        let mut sprite = self.oam_table[0].clone();
+        let sprite = self.oam_table[0].clone();
         let y_pos = sprite.get_byte(0) as u16;
         let tile_index = sprite.get_byte(1) as u16;
         let attributes = sprite.get_byte(2);
@@ -465,26 +496,35 @@ impl Ppu {
     /// 2: Read from the PPU Status Register
     /// 4: Read oam data
     /// 7: read value from ppu from ppu address set in the PPUADDR Register
+    ///
+    /// The write-only registers return the I/O latch, as do the bits a register does not drive.
     pub fn cpu_read(&mut self, address: u16, rdonly: bool) -> u8 {
         let mut _data = 0;
         let masked_address = address & 0x7;
         match masked_address {
             0 | 1 | 3 | 5 | 6 => {
-                _data = 0;
+                _data = self.io_latch;
             }
             2 => {
-                _data = self.ppustatus.bits();
+                _data = (self.ppustatus.bits() & 0xE0) | (self.io_latch & 0x1F);
+                self.refresh_latch(_data, 0xE0);
                 self.ppustatus.set(PPUSTATUS::vblank_flag, false);
                 self.w = 0;
             }
             4 => {
                 _data = self.oam_table[(self.oamaddr >> 2) as usize].get_byte(self.oamaddr);
+                self.refresh_latch(_data, 0xFF);
             }
             7 => {
+                let vram_address = self.v.get_data();
                 _data = self.internal_buffer;
-                self.internal_buffer = self.ppu_read(self.v.get_data());
-                if address >= 0x3F00 && address <= 0x3FFF {
-                    _data = self.internal_buffer;
+                self.internal_buffer = self.ppu_read(vram_address);
+                if vram_address >= 0x3F00 {
+                    // Palette reads skip the buffer and only drive the low six bits
+                    _data = (self.internal_buffer & 0x3F) | (self.io_latch & 0xC0);
+                    self.refresh_latch(_data, 0x3F);
+                } else {
+                    self.refresh_latch(_data, 0xFF);
                 }
                 /* We increment the v register by 32 or 1 depending on the PPUCTRL increment flag */
                 if !rdonly {
@@ -505,6 +545,46 @@ impl Ppu {
         _data
     }
 
+    ///# cpu_peek
+    /// Same as `cpu_read`, but without side effects: the vblank flag, write toggle,
+    /// read buffer and VRAM address are all left alone.
+    pub fn cpu_peek(&self, address: u16) -> u8 {
+        match address & 0x7 {
+            2 => (self.ppustatus.bits() & 0xE0) | (self.io_latch & 0x1F),
+            4 => self.oam_table[(self.oamaddr >> 2) as usize].get_byte(self.oamaddr),
+            7 => {
+                if self.v.get_data() >= 0x3F00 {
+                    (self.ppu_read(self.v.get_data()) & 0x3F) | (self.io_latch & 0xC0)
+                } else {
+                    self.internal_buffer
+                }
+            }
+            _ => self.io_latch,
+        }
+    }
+
+    /// Puts `value` on the I/O latch, refreshing the decay timer of the bits in `mask`.
+    fn refresh_latch(&mut self, value: u8, mask: u8) {
+        self.io_latch = (self.io_latch & !mask) | (value & mask);
+        for (bit, decay) in self.io_latch_decay.iter_mut().enumerate() {
+            if mask & (1 << bit) != 0 {
+                *decay = IO_LATCH_DECAY_FRAMES;
+            }
+        }
+    }
+
+    /// Lets the latch bits that were not driven for a while fade to 0, called once per frame.
+    fn decay_latch(&mut self) {
+        for (bit, decay) in self.io_latch_decay.iter_mut().enumerate() {
+            if *decay > 0 {
+                *decay -= 1;
+                if *decay == 0 {
+                    self.io_latch &= !(1 << bit);
+                }
+            }
+        }
+    }
+
     /// # cpu_write
     /// This function provides CPU access to the PPU, letting it define certain parameters and behaviors of the PPU.
     /// ## Address map
@@ -515,6 +595,7 @@ impl Ppu {
     /// - address $2006 initializes the PPU Addresses on writes
     /// - address $2007 will write data to the address in the PPU address space.
     pub fn cpu_write(&mut self, address: u16, data: u8) {
+        self.refresh_latch(data, 0xFF);
         let masked_address = address & 0x7;
         match masked_address {
             0 => {
@@ -988,9 +1069,8 @@ pub fn render_88_sprite(&mut self, index: usize, scanline: u16, nametable_frame:
             //     }
             // }
             self.ppustatus.set(PPUSTATUS::vblank_flag, true);
-            if self.ppuctrl.contains(PPUCTRL::vblank_enable) {
-                self.nmi = true;
-            }
+            self.frame_complete = true;
+            self.decay_latch();
         }
         if self.scanline_counter == -1 && self.cycle_counter == 1 {
             self.scanline_counter = 0;
@@ -1005,3 +1085,92 @@ pub fn render_88_sprite(&mut self, index: usize, scanline: u16, nametable_frame:
         self.total_cycles = self.total_cycles.wrapping_add(1);
     }
 }
+
+impl Savestate for Ppu {
+    /// Saves registers, internal memories and the rendering pipeline, so a state taken
+    /// mid-scanline resumes on the exact same dot.
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.ppuctrl.bits());
+        w.u8(self.ppumask.bits());
+        w.u8(self.ppustatus.bits());
+        w.u8(self.oamaddr);
+        w.u16(self.v.get_data());
+        w.u16(self.t.get_data());
+        w.u8(self.w);
+        w.u8(self.x);
+        w.bytes(&self.vram);
+        w.u8(self.internal_buffer);
+        w.bool(self.frame_complete);
+        w.bytes(&self.palette_memory);
+        w.u8(self.palette_num);
+        w.u16(self.cycle_counter);
+        w.i16(self.scanline_counter);
+        w.u64(self.total_cycles as u64);
+        for column in &self.frame_array {
+            w.bytes(column);
+        }
+        for sprite in &self.oam_table {
+            for i in 0..4 {
+                w.u8(sprite.get_byte(i));
+            }
+        }
+        w.u16(self.pattern_lo_shift_register);
+        w.u16(self.pattern_hi_shift_register);
+        w.u16(self.attribute_lo_shift_register);
+        w.u16(self.attribute_hi_shift_register);
+        w.u16(self.next_pattern_lo);
+        w.u16(self.next_pattern_hi);
+        w.u16(self.next_attribute_lo);
+        w.u16(self.next_attribute_hi);
+        w.u16(self.next_nametable_tile);
+        w.u16(self.next_attribute_tile);
+        w.u16(self.sprite0xcoord);
+        w.u16(self.sprite0ycoord);
+        w.bool(self.sprite0poss);
+        w.u8(self.io_latch);
+        w.bytes(&self.io_latch_decay);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.ppuctrl = PPUCTRL::from_bits_retain(r.u8()?);
+        self.ppumask = PPUMASK::from_bits_retain(r.u8()?);
+        self.ppustatus = PPUSTATUS::from_bits_retain(r.u8()?);
+        self.oamaddr = r.u8()?;
+        self.v.set_data(r.u16()?);
+        self.t.set_data(r.u16()?);
+        self.w = r.u8()?;
+        self.x = r.u8()?;
+        r.bytes_into(&mut self.vram)?;
+        self.internal_buffer = r.u8()?;
+        self.frame_complete = r.bool()?;
+        r.bytes_into(&mut self.palette_memory)?;
+        self.palette_num = r.u8()?;
+        self.cycle_counter = r.u16()?;
+        self.scanline_counter = r.i16()?;
+        self.total_cycles = r.u64()? as usize;
+        for column in self.frame_array.iter_mut() {
+            r.bytes_into(column)?;
+        }
+        for sprite in self.oam_table.iter_mut() {
+            for i in 0..4 {
+                sprite.set_byte(i, r.u8()?);
+            }
+        }
+        self.pattern_lo_shift_register = r.u16()?;
+        self.pattern_hi_shift_register = r.u16()?;
+        self.attribute_lo_shift_register = r.u16()?;
+        self.attribute_hi_shift_register = r.u16()?;
+        self.next_pattern_lo = r.u16()?;
+        self.next_pattern_hi = r.u16()?;
+        self.next_attribute_lo = r.u16()?;
+        self.next_attribute_hi = r.u16()?;
+        self.next_nametable_tile = r.u16()?;
+        self.next_attribute_tile = r.u16()?;
+        self.sprite0xcoord = r.u16()?;
+        self.sprite0ycoord = r.u16()?;
+        self.sprite0poss = r.bool()?;
+        self.io_latch = r.u8()?;
+        r.bytes_into(&mut self.io_latch_decay)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::states::{Savestate, StateReader, StateWriter};
+
+/// A simple RGB framebuffer abstraction that stores pixels as 32-bit integers (0xRRGGBB).
+/// Designed for use with NES PPU output or similar graphics rendering.
+pub struct Frame {
+    buffer: Vec<u32>, // Stores pixel data in row-major order (0xRRGGBB format).
+    width: u16,       // Width of the frame in pixels.
+}
+
+impl Frame {
+    /// Creates a new frame buffer with the specified width and height.
+    /// Initializes all pixels to black (0x000000).
+    pub fn new(width: u16, height: u16) -> Self {
+        Self {
+            buffer: vec![0; width as usize * height as usize],
+            width,
+        }
+    }
+
+    /// Draws a single pixel at coordinates (x, y) with the given RGB color.
+    /// The color tuple (r, g, b) is packed into a 32-bit integer as 0xRRGGBB.
+    pub fn drawpixel(&mut self, x: u16, y: u16, color: (u8, u8, u8)) {
+        if x < 255 && y < 240 {
+            let index = (y as usize * self.width as usize) + (x as usize);
+            self.buffer[index] =
+                ((color.0 as u32) << 16) | ((color.1 as u32) << 8) | (color.2 as u32);
+        }
+    }
+
+    /// Copies `other` into the top-left corner of this frame, clipping anything that does not fit.
+    /// Used to compose the game image with debug views in a wider window.
+    pub fn copy_from(&mut self, other: &Frame) {
+        let width = self.width.min(other.width) as usize;
+        let rows = self.buffer.len() / self.width as usize;
+        for (y, row) in other.buffer.chunks(other.width as usize).take(rows).enumerate() {
+            let start = y * self.width as usize;
+            self.buffer[start..start + width].copy_from_slice(&row[..width]);
+        }
+    }
+
+    /// Returns an immutable reference to the internal buffer.
+    /// Useful for passing to a renderer or GUI framework.
+    pub fn get_buf(&self) -> &Vec<u32> {
+        &self.buffer
+    }
+
+    /// Width of the frame in pixels.
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    /// Height of the frame in pixels.
+    pub fn height(&self) -> u16 {
+        (self.buffer.len() / self.width as usize) as u16
+    }
+
+    /// Writes the frame to `path` as a binary (P6) PPM image.
+    pub fn write_ppm<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height())?;
+        for pixel in &self.buffer {
+            out.write_all(&[(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8])?;
+        }
+        out.flush()
+    }
+}
+
+impl Savestate for Frame {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.buffer.len() as u32);
+        for pixel in &self.buffer {
+            w.u32(*pixel);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        if r.u32()? as usize != self.buffer.len() {
+            return Err(crate::states::invalid("frame buffer has the wrong size"));
+        }
+        for pixel in self.buffer.iter_mut() {
+            *pixel = r.u32()?;
+        }
+        Ok(())
+    }
+}
@@ -103,7 +103,7 @@ impl Oam {
     ///
     /// # Panics
     /// Panics if `address % 4` is out of range `[0, 3]`.
-    pub fn get_byte(&mut self, address: u8) -> u8 {
+    pub fn get_byte(&self, address: u8) -> u8 {
         match address % 4 {
             0 => self.y_position as u8,
             1 => self.index_number,
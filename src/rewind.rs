@@ -0,0 +1,203 @@
+//! # Rewind
+//! Keeps a bounded history of save states so the game can be stepped backwards.
+//! Only the newest snapshot is stored in full. Every older one is stored as the XOR
+//! against the snapshot taken after it, run-length encoded, which is small because
+//! most of the machine does not change between two frames.
+
+use std::collections::VecDeque;
+
+/// Ring buffer of delta compressed snapshots.
+pub struct RewindBuffer {
+    /// Frames between two snapshots.
+    interval: u32,
+    /// Maximum number of snapshots kept, including the newest one.
+    capacity: usize,
+    frames_until_snapshot: u32,
+    newest: Option<Vec<u8>>,
+    /// `deltas[i]` turns snapshot `i + 1` back into snapshot `i`; the back is the most recent.
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl RewindBuffer {
+    /// Creates a buffer taking a snapshot every `interval` frames and keeping at most
+    /// `capacity` of them, so it covers `interval * capacity` frames of history.
+    pub fn new(interval: u32, capacity: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            frames_until_snapshot: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Counts a frame and returns true when it is time to `push` a snapshot.
+    pub fn frame_done(&mut self) -> bool {
+        if self.frames_until_snapshot == 0 {
+            self.frames_until_snapshot = self.interval - 1;
+            true
+        } else {
+            self.frames_until_snapshot -= 1;
+            false
+        }
+    }
+
+    /// Stores `state` as the newest snapshot, dropping the oldest one when full.
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.newest.take() {
+            if previous.len() == state.len() {
+                self.deltas.push_back(encode_delta(&state, &previous));
+            } else {
+                // The layout changed (e.g. another ROM was loaded); the history is useless
+                self.deltas.clear();
+            }
+        }
+        self.newest = Some(state);
+        while self.deltas.len() + 1 > self.capacity {
+            self.deltas.pop_front();
+        }
+    }
+
+    /// Removes and returns the newest snapshot, making the one before it the newest.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let state = self.newest.take()?;
+        if let Some(delta) = self.deltas.pop_back() {
+            let mut older = state.clone();
+            apply_delta(&mut older, &delta);
+            self.newest = Some(older);
+        }
+        self.frames_until_snapshot = self.interval - 1;
+        Some(state)
+    }
+
+    /// Number of snapshots currently held.
+    pub fn len(&self) -> usize {
+        self.newest.as_ref().map_or(0, |_| self.deltas.len() + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    /// Forgets all snapshots.
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.frames_until_snapshot = 0;
+    }
+
+    /// Bytes used by the stored snapshots, for tuning `capacity`.
+    pub fn memory_usage(&self) -> usize {
+        self.newest.as_ref().map_or(0, Vec::len) + self.deltas.iter().map(Vec::len).sum::<usize>()
+    }
+}
+
+/// Encodes `from XOR to` as alternating runs: a LEB128 count of zero bytes to skip,
+/// then a LEB128 count of literal bytes followed by the bytes themselves.
+fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < from.len() {
+        let start = i;
+        while i < from.len() && from[i] == to[i] {
+            i += 1;
+        }
+        let zeros = i - start;
+
+        // A literal run ends at the first stretch of unchanged bytes long enough
+        // to be worth encoding as a skip
+        let literal_start = i;
+        let mut same = 0;
+        while i < from.len() && same < 4 {
+            if from[i] == to[i] {
+                same += 1;
+            } else {
+                same = 0;
+            }
+            i += 1;
+        }
+        if same == 4 {
+            i -= 4;
+        }
+        write_leb128(&mut out, zeros);
+        write_leb128(&mut out, i - literal_start);
+        out.extend((literal_start..i).map(|j| from[j] ^ to[j]));
+    }
+    out
+}
+
+/// XORs a delta produced by `encode_delta` into `state`.
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let mut pos = 0;
+    let mut i = 0;
+    while i < delta.len() {
+        pos += read_leb128(delta, &mut i);
+        let literals = read_leb128(delta, &mut i);
+        for byte in &delta[i..i + literals] {
+            state[pos] ^= byte;
+            pos += 1;
+        }
+        i += literals;
+    }
+}
+
+fn write_leb128(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_leb128(data: &[u8], i: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*i];
+        *i += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod rewind_tests {
+    use super::*;
+
+    #[test]
+    pub fn delta_round_trip() {
+        let old: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let mut new = old.clone();
+        new[0] ^= 0xFF;
+        new[500] = 7;
+        new[501] = 9;
+        new[999] = 1;
+        let delta = encode_delta(&new, &old);
+        assert!(delta.len() < 32, "delta is compressed, FAILED!");
+        let mut restored = new.clone();
+        apply_delta(&mut restored, &delta);
+        assert_eq!(restored, old, "delta restores the old state, FAILED!");
+    }
+
+    #[test]
+    pub fn pops_newest_first_and_drops_oldest() {
+        let mut buffer = RewindBuffer::new(1, 3);
+        for i in 0..5u8 {
+            assert!(buffer.frame_done(), "snapshot every frame, FAILED!");
+            buffer.push(vec![i; 64]);
+        }
+        assert_eq!(buffer.len(), 3, "capacity bound, FAILED!");
+        assert_eq!(buffer.pop(), Some(vec![4; 64]), "newest, FAILED!");
+        assert_eq!(buffer.pop(), Some(vec![3; 64]), "second newest, FAILED!");
+        assert_eq!(buffer.pop(), Some(vec![2; 64]), "oldest kept, FAILED!");
+        assert_eq!(buffer.pop(), None, "empty, FAILED!");
+    }
+}
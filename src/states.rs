@@ -1,3 +1,205 @@
-pub struct state{
-    
-}
\ No newline at end of file
+//! # States
+//! Save state plumbing. Every component writes its registers and buffers into a
+//! [`StateWriter`] and reads them back from a [`StateReader`] in the same order, so the
+//! format is simply the concatenation of each component's fields in little-endian.
+//! The whole machine state is prefixed with a magic number and a format version.
+
+use std::io::{self, Error, ErrorKind};
+
+/// Identifies a save state file.
+pub const MAGIC: &[u8; 4] = b"NESS";
+/// Bumped whenever the layout of any component's state changes.
+pub const VERSION: u32 = 15;
+
+/// Implemented by every component that is part of a save state.
+pub trait Savestate {
+    /// Appends the component's state to `w`.
+    fn save_state(&self, w: &mut StateWriter);
+
+    /// Restores the component from `r`, reading exactly what `save_state` wrote.
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()>;
+}
+
+/// Serializes values into a byte buffer.
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i16(&mut self, value: i16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes a length prefixed byte slice.
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads values back out of a buffer produced by [`StateWriter`].
+/// Running past the end or finding a mismatched length is reported as `InvalidData`.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return Err(invalid("save state is truncated"));
+        }
+        let slice = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut out = [0; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn i16(&mut self) -> io::Result<i16> {
+        Ok(i16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    /// Reads a length prefixed byte slice.
+    pub fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    /// Reads a length prefixed byte slice into `out`, which must have the same length.
+    pub fn bytes_into(&mut self, out: &mut [u8]) -> io::Result<()> {
+        let data = self.bytes()?;
+        if data.len() != out.len() {
+            return Err(invalid("save state buffer has the wrong size"));
+        }
+        out.copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Checks that the whole buffer was consumed.
+    pub fn finish(&self) -> io::Result<()> {
+        if self.pos != self.data.len() {
+            return Err(invalid("save state has trailing data"));
+        }
+        Ok(())
+    }
+}
+
+/// Builds the `InvalidData` error used for malformed save states.
+pub fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+///# Unit tests module
+mod states_tests {
+    use super::*;
+
+    #[test]
+    pub fn round_trip() {
+        let mut w = StateWriter::new();
+        w.u8(0xAB);
+        w.bool(true);
+        w.u16(0x1234);
+        w.i16(-5);
+        w.u64(u64::MAX - 1);
+        w.bytes(&[1, 2, 3]);
+        let data = w.finish();
+
+        let mut r = StateReader::new(&data);
+        assert_eq!(r.u8().unwrap(), 0xAB, "u8 round trip, FAILED!");
+        assert!(r.bool().unwrap(), "bool round trip, FAILED!");
+        assert_eq!(r.u16().unwrap(), 0x1234, "u16 round trip, FAILED!");
+        assert_eq!(r.i16().unwrap(), -5, "i16 round trip, FAILED!");
+        assert_eq!(r.u64().unwrap(), u64::MAX - 1, "u64 round trip, FAILED!");
+        let mut out = [0; 3];
+        r.bytes_into(&mut out).unwrap();
+        assert_eq!(out, [1, 2, 3], "bytes round trip, FAILED!");
+        assert!(r.finish().is_ok(), "reader consumed everything, FAILED!");
+    }
+
+    #[test]
+    pub fn truncated_is_an_error() {
+        let mut r = StateReader::new(&[1]);
+        let err = r.u16().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "truncated read, FAILED!");
+    }
+}